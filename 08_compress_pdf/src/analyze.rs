@@ -0,0 +1,340 @@
+use crate::compressor::PdfCompressor;
+use crate::crypt;
+use crate::error::Error;
+use crate::objstm::{self, Counting, type_name};
+use crate::pipeline::is_image_xobject;
+use crate::placement;
+use crate::report::ImageReport;
+use lopdf::{Document, Object, ObjectId};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// Explains where the bytes of a PDF go, and optionally what recompressing the
+/// images at a few settings would save. Nothing is written.
+#[derive(Debug, Clone, Default)]
+pub struct Analyzer {
+    password: Option<String>,
+    /// (quality, max_width) to estimate, in order
+    settings: Vec<(f32, u32)>,
+}
+
+/// Object sizes are measured as the objects serialize on their own, so
+/// objects packed into compressed object streams count uncompressed and the
+/// categories can add up to more than `file_size`.
+#[derive(Serialize, Debug, Default)]
+pub struct Analysis {
+    pub file_size: u64,
+    pub objects: usize,
+    pub images: Usage,
+    pub fonts: Usage,
+    /// Page content streams and Form XObjects
+    pub content: Usage,
+    /// XMP streams and the document information dictionary
+    pub metadata: Usage,
+    /// Objects nothing refers to; they go away on save
+    pub unreferenced: Usage,
+    /// Page tree, annotations, outlines, structure tree...
+    pub other: Usage,
+    /// Images (masks included) by filter chain and colour space, biggest first
+    pub image_groups: Vec<ImageGroup>,
+    pub pages: Vec<PageUsage>,
+    pub estimates: Vec<Estimate>,
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub objects: usize,
+    pub bytes: u64,
+}
+
+impl Usage {
+    fn add(&mut self, bytes: u64) {
+        self.objects += 1;
+        self.bytes += bytes;
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ImageGroup {
+    pub filter: String,
+    pub color_space: String,
+    pub images: usize,
+    pub bytes: u64,
+}
+
+/// An image drawn on several pages counts for each of them.
+#[derive(Serialize, Debug)]
+pub struct PageUsage {
+    pub page: u32,
+    pub content_bytes: u64,
+    pub images: usize,
+    pub image_bytes: u64,
+}
+
+/// The result of a dry run at one setting. Image bytes leave out soft masks,
+/// which are only rewritten along with their image.
+#[derive(Serialize, Debug)]
+pub struct Estimate {
+    pub quality: f32,
+    pub max_width: u32,
+    pub optimized: usize,
+    pub image_bytes_before: u64,
+    pub image_bytes_after: u64,
+    pub output_size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Category {
+    Image,
+    Font,
+    Content,
+    Metadata,
+    Unreferenced,
+    Other,
+}
+
+impl Analyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Password for encrypted PDFs, user or owner.
+    pub fn password(mut self, password: Option<String>) -> Self {
+        self.password = password;
+        self
+    }
+
+    /// Also estimate the size after recompressing at this JPEG quality and
+    /// maximum width. Can be given several times.
+    pub fn estimate(mut self, quality: f32, max_width: u32) -> Self {
+        self.settings.push((quality, max_width));
+        self
+    }
+
+    pub fn analyze_file(&self, input: &Path) -> Result<Analysis, Error> {
+        self.analyze_bytes(&std::fs::read(input)?)
+    }
+
+    pub fn analyze_bytes(&self, pdf: &[u8]) -> Result<Analysis, Error> {
+        let (doc, _) = crypt::load(pdf, self.password.as_deref(), false)?;
+        let mut analysis = breakdown(&doc);
+        analysis.file_size = pdf.len() as u64;
+
+        for &(quality, max_width) in &self.settings {
+            // Nothing gets written, so signatures don't matter
+            let compressor = PdfCompressor::new()
+                .quality(quality)
+                .max_width(max_width)
+                .password(self.password.clone())
+                .break_signatures(true);
+            let summary = compressor.compress_to(pdf, &mut std::io::sink())?;
+            let (before, after) = summary
+                .images
+                .iter()
+                .fold((0, 0), |(before, after), image| (before + image.bytes_before as u64, after + image.bytes_after as u64));
+            analysis.estimates.push(Estimate {
+                quality,
+                max_width,
+                optimized: summary.optimized,
+                image_bytes_before: before,
+                image_bytes_after: after,
+                output_size: summary.output_size,
+            });
+        }
+        Ok(analysis)
+    }
+}
+
+/// Sizes of everything in `doc` by category and page.
+fn breakdown(doc: &Document) -> Analysis {
+    let categories = categorize(doc);
+    let mut analysis = Analysis::default();
+    let mut sizes = BTreeMap::new();
+    let mut groups: BTreeMap<(String, String), (usize, u64)> = BTreeMap::new();
+
+    for (&id, object) in &doc.objects {
+        // Containers: what they hold is counted object by object
+        if matches!(type_name(object), Some(b"ObjStm" | b"XRef")) {
+            continue;
+        }
+        let bytes = serialized_size(id, object);
+        sizes.insert(id, bytes);
+        analysis.objects += 1;
+        let category = categories.get(&id).copied().unwrap_or(Category::Other);
+        match category {
+            Category::Image => analysis.images.add(bytes),
+            Category::Font => analysis.fonts.add(bytes),
+            Category::Content => analysis.content.add(bytes),
+            Category::Metadata => analysis.metadata.add(bytes),
+            Category::Unreferenced => analysis.unreferenced.add(bytes),
+            Category::Other => analysis.other.add(bytes),
+        }
+        if category == Category::Image {
+            let image = ImageReport::describe(doc, id);
+            let group = groups.entry((image.filter, image.color_space)).or_default();
+            group.0 += 1;
+            group.1 += bytes;
+        }
+    }
+
+    analysis.image_groups = groups
+        .into_iter()
+        .map(|((filter, color_space), (images, bytes))| ImageGroup { filter, color_space, images, bytes })
+        .collect();
+    analysis.image_groups.sort_by_key(|group| std::cmp::Reverse(group.bytes));
+
+    let placements = placement::collect_placements(doc);
+    for (page, page_id) in doc.get_pages() {
+        let content_bytes = doc.get_page_contents(page_id).iter().filter_map(|id| sizes.get(id)).sum();
+        let drawn: Vec<u64> = placements
+            .iter()
+            .filter(|(_, placement)| placement.pages.contains(&page))
+            .map(|(id, _)| sizes.get(id).copied().unwrap_or(0))
+            .collect();
+        analysis.pages.push(PageUsage { page, content_bytes, images: drawn.len(), image_bytes: drawn.iter().sum() });
+    }
+    analysis
+}
+
+/// Sorts every object into one category. Unreferenced wins over everything,
+/// then images, fonts (with everything they use), content and metadata.
+fn categorize(doc: &Document) -> BTreeMap<ObjectId, Category> {
+    let mut categories = BTreeMap::new();
+    let referenced = reachable(doc, references(&Object::Dictionary(doc.trailer.clone())), &[]);
+
+    for (&id, object) in &doc.objects {
+        if !referenced.contains(&id) {
+            categories.insert(id, Category::Unreferenced);
+        } else if is_image_xobject(object) {
+            categories.insert(id, Category::Image);
+        }
+    }
+
+    // Descriptors, font files, ToUnicode CMaps, Type3 glyph procedures...
+    let fonts: Vec<ObjectId> =
+        doc.objects.iter().filter(|(_, object)| type_name(object) == Some(b"Font")).map(|(&id, _)| id).collect();
+    for id in reachable(doc, fonts, &[b"Page", b"Pages"]) {
+        categories.entry(id).or_insert(Category::Font);
+    }
+
+    for page_id in doc.get_pages().into_values() {
+        for id in doc.get_page_contents(page_id) {
+            categories.entry(id).or_insert(Category::Content);
+        }
+    }
+    for (&id, object) in &doc.objects {
+        let subtype = object.as_stream().ok().and_then(|s| s.dict.get(b"Subtype").and_then(|t| t.as_name()).ok());
+        if subtype == Some(b"Form") {
+            categories.entry(id).or_insert(Category::Content);
+        } else if type_name(object) == Some(b"Metadata") {
+            categories.entry(id).or_insert(Category::Metadata);
+        }
+    }
+    if let Ok(info) = doc.trailer.get(b"Info").and_then(|o| o.as_reference()) {
+        categories.entry(info).or_insert(Category::Metadata);
+    }
+    categories
+}
+
+/// Every object reachable from `start`, not walking into objects of the `stop` types.
+fn reachable(doc: &Document, start: Vec<ObjectId>, stop: &[&[u8]]) -> HashSet<ObjectId> {
+    let mut seen = HashSet::new();
+    let mut todo = start;
+    while let Some(id) = todo.pop() {
+        let Ok(object) = doc.get_object(id) else { continue };
+        if type_name(object).is_some_and(|t| stop.contains(&t)) || !seen.insert(id) {
+            continue;
+        }
+        todo.extend(references(object));
+    }
+    seen
+}
+
+fn references(object: &Object) -> Vec<ObjectId> {
+    let mut found = Vec::new();
+    let mut todo = vec![object];
+    while let Some(object) = todo.pop() {
+        match object {
+            Object::Reference(id) => found.push(*id),
+            Object::Array(items) => todo.extend(items),
+            Object::Dictionary(dict) => todo.extend(dict.iter().map(|(_, v)| v)),
+            Object::Stream(stream) => todo.extend(stream.dict.iter().map(|(_, v)| v)),
+            _ => {}
+        }
+    }
+    found
+}
+
+fn serialized_size(id: ObjectId, object: &Object) -> u64 {
+    let mut out = Counting { inner: std::io::sink(), written: 0 };
+    let _ = objstm::write_indirect(&mut out, id, object);
+    out.written as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Stream, dictionary};
+
+    fn sample_pdf() -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        // Noisy enough that JPEG has something to save
+        let pixels: Vec<u8> = (0..300 * 300 * 3u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        let image = doc.add_object(Stream::new(
+            dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => 300, "Height" => 300, "ColorSpace" => "DeviceRGB", "BitsPerComponent" => 8 },
+            pixels,
+        ));
+        let font_file = doc.add_object(Stream::new(dictionary! {}, vec![0; 500]));
+        let descriptor = doc.add_object(dictionary! { "Type" => "FontDescriptor", "FontFile2" => font_file });
+        let font = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "TrueType", "FontDescriptor" => descriptor });
+        let content = doc.add_object(Stream::new(dictionary! {}, b"q 300 0 0 300 0 0 cm /Im0 Do Q BT /F1 12 Tf (Hi) Tj ET".to_vec()));
+        let metadata = doc.add_object(Stream::new(dictionary! { "Type" => "Metadata", "Subtype" => "XML" }, b"<x:xmpmeta/>".to_vec()));
+        doc.add_object(Stream::new(dictionary! {}, vec![1; 100]));
+
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content,
+            "Resources" => dictionary! { "XObject" => dictionary! { "Im0" => image }, "Font" => dictionary! { "F1" => font } },
+        });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id, "Metadata" => metadata });
+        doc.trailer.set("Root", catalog);
+
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+        pdf
+    }
+
+    #[test]
+    fn sorts_objects_into_categories_and_pages() {
+        let analysis = Analyzer::new().analyze_bytes(&sample_pdf()).unwrap();
+
+        assert_eq!(analysis.objects, 10);
+        assert_eq!(analysis.images.objects, 1);
+        assert!(analysis.images.bytes > 270_000);
+        assert_eq!(analysis.fonts.objects, 3);
+        assert_eq!((analysis.content.objects, analysis.metadata.objects, analysis.unreferenced.objects), (1, 1, 1));
+        assert_eq!(analysis.other.objects, 3);
+
+        let group = &analysis.image_groups[0];
+        assert_eq!((group.filter.as_str(), group.color_space.as_str(), group.images), ("None", "DeviceRGB", 1));
+        let page = &analysis.pages[0];
+        assert_eq!((page.page, page.images, page.image_bytes), (1, 1, analysis.images.bytes));
+        assert!(analysis.estimates.is_empty());
+    }
+
+    #[test]
+    fn estimates_without_writing() {
+        let pdf = sample_pdf();
+        let analysis = Analyzer::new().estimate(30.0, 100).estimate(90.0, 1200).analyze_bytes(&pdf).unwrap();
+
+        let [small, large] = &analysis.estimates[..] else { panic!("{:?}", analysis.estimates) };
+        assert_eq!((small.optimized, small.max_width), (1, 100));
+        assert!(small.image_bytes_after < large.image_bytes_after);
+        assert!(large.output_size < analysis.file_size);
+        assert_eq!(small.image_bytes_before, large.image_bytes_before);
+    }
+}
@@ -0,0 +1,409 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use compress_pdf::{Bilevel, CmykProfile, DocPasses, LosslessMode, Selection};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+/// Recompress the images inside PDF files with mozjpeg.
+#[derive(Parser, Debug)]
+#[command(
+    name = "compress_pdf",
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    after_help = "Exit codes:\n  0  every image was optimized (or skipped)\n  1  some images failed to decode or compress, were flagged by --verify, or PDF/A violations are left\n  3  a PDF could not be loaded or saved\n  4  a PDF was refused (signed, or encrypted and the password is wrong)"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// PDF files, directories or glob patterns (e.g. "scans/*.pdf")
+    #[arg(required = true, value_name = "INPUT")]
+    pub inputs: Vec<String>,
+
+    /// Output file for a single input, or output directory in batch mode
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// Images wider than this are downscaled, keeping the aspect ratio
+    #[arg(short = 'w', long, default_value_t = 1200, value_name = "PX")]
+    pub max_width: u32,
+
+    /// Downsample to this resolution at the size images are drawn on the page
+    /// (e.g. 150 for screen, 300 for print); max-width still applies
+    #[arg(short = 'd', long, value_name = "DPI", value_parser = parse_dpi)]
+    pub target_dpi: Option<f32>,
+
+    /// JPEG quality passed to mozjpeg (1-100)
+    #[arg(short, long, default_value_t = 60.0, value_parser = parse_quality)]
+    pub quality: f32,
+
+    /// Keep lowering quality/resolution until the output fits, e.g. 2MB, 500KB
+    #[arg(short = 't', long, value_name = "SIZE", value_parser = parse_size)]
+    pub target_size: Option<u64>,
+
+    /// Write CMYK images as CMYK JPEGs instead of converting them to RGB
+    #[arg(long)]
+    pub keep_cmyk: bool,
+
+    /// ICC profile for converting DeviceCMYK images to RGB (e.g. the press
+    /// profile the PDF was made for); images with their own profile keep it
+    #[arg(long, value_name = "ICC", value_parser = parse_cmyk_profile)]
+    pub cmyk_profile: Option<CmykProfile>,
+
+    /// When to re-encode images losslessly (Flate/palette) instead of as JPEG
+    #[arg(long, value_enum, default_value_t = Lossless::Auto)]
+    pub lossless: Lossless,
+
+    /// Store colour images that are gray anyway (e.g. colour scans of
+    /// black-and-white forms) as DeviceGray
+    #[arg(long)]
+    pub grayscale: bool,
+
+    /// Turn scanned text pages into 1-bit images with an adaptive threshold,
+    /// as CCITT G4, Flate or whichever is smaller (implies --grayscale)
+    #[arg(long, value_enum, value_name = "FILTER", num_args = 0..=1, require_equals = true, default_missing_value = "auto")]
+    pub bilevel: Option<BilevelFilter>,
+
+    /// Number of worker threads for image recompression (0 = one per CPU core)
+    #[arg(short, long, default_value_t = 0, value_name = "N")]
+    pub jobs: usize,
+
+    /// Keep byte-identical image copies instead of merging them into one object
+    #[arg(long)]
+    pub no_dedup: bool,
+
+    /// Document-level passes besides images, comma separated, e.g.
+    /// "compress-streams,object-streams" or "all"
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "PASS")]
+    pub passes: Vec<DocPass>,
+
+    /// Only touch images drawn on these pages, e.g. "1-3,5,8-" (an image used
+    /// on several pages is only touched if all of them are selected)
+    #[arg(long, value_delimiter = ',', value_name = "PAGES", value_parser = parse_page_range)]
+    pub pages: Vec<RangeInclusive<u32>>,
+
+    /// Leave images stored in fewer bytes than this alone, e.g. 20KB
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_bytes: Option<u64>,
+
+    /// Leave images smaller than this alone, e.g. 300x300 (or just 300)
+    #[arg(long, value_name = "WxH", value_parser = parse_dimensions)]
+    pub min_dimensions: Option<(u32, u32)>,
+
+    /// Object numbers of images to leave alone, comma separated
+    #[arg(long, value_delimiter = ',', value_name = "ID")]
+    pub exclude_objects: Vec<u32>,
+
+    /// Write a JSON report (every image, what happened to it, totals) to this file
+    #[arg(long, value_name = "FILE")]
+    pub report: Option<PathBuf>,
+
+    /// Password for encrypted PDFs, user or owner (the empty user password is
+    /// tried when none is given); the output is encrypted the same way
+    #[arg(long, value_name = "PW")]
+    pub password: Option<String>,
+
+    /// Save encrypted PDFs without encryption
+    #[arg(long)]
+    pub decrypt: bool,
+
+    /// Compress signed PDFs anyway, invalidating their signatures
+    #[arg(long)]
+    pub break_signatures: bool,
+
+    /// Append the changed objects to the original file as an incremental update
+    /// instead of rewriting it; the original revision and its signatures stay intact
+    #[arg(long, conflicts_with_all = ["target_size", "decrypt"])]
+    pub incremental: bool,
+
+    /// Decode every replaced image again and compare it with the original
+    /// (SSIM and PSNR); images below the thresholds are flagged
+    #[arg(long)]
+    pub verify: bool,
+
+    /// Lowest acceptable SSIM (0-1) for --verify
+    #[arg(long, default_value_t = 0.9, value_name = "SSIM", value_parser = parse_ssim)]
+    pub min_ssim: f64,
+
+    /// Lowest acceptable PSNR in dB for --verify
+    #[arg(long, default_value_t = 30.0, value_name = "DB")]
+    pub min_psnr: f64,
+
+    /// Put flagged images back as they were before saving (implies --verify)
+    #[arg(long)]
+    pub revert_flagged: bool,
+
+    /// Low-memory mode for very large PDFs: image data stays in the file until
+    /// needed and images are processed in batches that fit into this much memory
+    /// (e.g. 512MB)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "target_size")]
+    pub memory_limit: Option<u64>,
+
+    /// Make the output PDF/A-2b: sRGB output intent, XMP identification, no
+    /// encryption, JavaScript or other forbidden features; what can't be fixed
+    /// is reported
+    #[arg(long, conflicts_with_all = ["incremental", "keep_cmyk", "target_size"])]
+    pub pdfa: bool,
+
+    /// Run the whole pipeline but don't write any output file
+    #[arg(short = 'n', long)]
+    pub dry_run: bool,
+
+    /// Keep running and compress every PDF that lands in INPUT (one directory)
+    /// once it is fully written, into -o (default INPUT/compressed); stop with Ctrl-C
+    #[arg(long, conflicts_with_all = ["dry_run", "report"])]
+    pub watch: bool,
+
+    /// Where --watch moves originals after compressing them (default INPUT/archive)
+    #[arg(long, value_name = "DIR", requires = "watch")]
+    pub archive: Option<PathBuf>,
+
+    /// Where --watch moves PDFs that can't be compressed, with a .error file
+    /// saying why (default INPUT/quarantine)
+    #[arg(long, value_name = "DIR", requires = "watch")]
+    pub quarantine: Option<PathBuf>,
+
+    /// How often --watch tries a PDF that fails to load again before
+    /// quarantining it (it may still be being written)
+    #[arg(long, default_value_t = 3, value_name = "N", requires = "watch")]
+    pub retries: u32,
+
+    /// Seconds a new PDF has to stay unchanged before --watch picks it up
+    #[arg(long, default_value_t = 5.0, value_name = "SECS", value_parser = parse_seconds, requires = "watch")]
+    pub settle: f64,
+
+    /// Append one JSON line per file handled by --watch to this file
+    #[arg(long, value_name = "FILE", requires = "watch")]
+    pub watch_log: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Write every image to a directory instead of compressing: DCTDecode images
+    /// as the JPEGs they are, the others as PNG, plus a manifest.json
+    Extract(ExtractArgs),
+    /// Show where the bytes of each PDF go (by category, image type and page) and
+    /// estimate what recompressing would save, without writing anything
+    Analyze(AnalyzeArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ExtractArgs {
+    /// PDF files, directories or glob patterns
+    #[arg(required = true, value_name = "INPUT")]
+    pub inputs: Vec<String>,
+
+    /// Directory for the images (default: <name>_images next to the PDF);
+    /// in batch mode every PDF gets a subdirectory of it
+    #[arg(short, long, value_name = "DIR")]
+    pub output: Option<PathBuf>,
+
+    /// Password for encrypted PDFs, user or owner
+    #[arg(long, value_name = "PW")]
+    pub password: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct AnalyzeArgs {
+    /// PDF files, directories or glob patterns
+    #[arg(required = true, value_name = "INPUT")]
+    pub inputs: Vec<String>,
+
+    /// JPEG qualities to estimate, comma separated
+    #[arg(short, long, value_delimiter = ',', default_value = "60", value_parser = parse_quality)]
+    pub quality: Vec<f32>,
+
+    /// Maximum widths to estimate, comma separated; every quality is tried with every width
+    #[arg(short = 'w', long, value_delimiter = ',', default_value = "1200", value_name = "PX")]
+    pub max_width: Vec<u32>,
+
+    /// Only break the size down, skip the estimates (they run the whole pipeline)
+    #[arg(long)]
+    pub no_estimate: bool,
+
+    /// Password for encrypted PDFs, user or owner
+    #[arg(long, value_name = "PW")]
+    pub password: Option<String>,
+
+    /// Also write the analysis as JSON to this file
+    #[arg(long, value_name = "FILE")]
+    pub json: Option<PathBuf>,
+}
+
+fn parse_quality(s: &str) -> Result<f32, String> {
+    let q: f32 = s.parse().map_err(|_| format!("`{}` is not a number", s))?;
+    if (1.0..=100.0).contains(&q) {
+        Ok(q)
+    } else {
+        Err(format!("quality must be between 1 and 100, got {}", q))
+    }
+}
+
+fn parse_ssim(s: &str) -> Result<f64, String> {
+    let ssim: f64 = s.parse().map_err(|_| format!("`{}` is not a number", s))?;
+    if (0.0..=1.0).contains(&ssim) {
+        Ok(ssim)
+    } else {
+        Err(format!("SSIM must be between 0 and 1, got {}", ssim))
+    }
+}
+
+fn parse_cmyk_profile(s: &str) -> Result<CmykProfile, String> {
+    let icc = std::fs::read(s).map_err(|e| format!("cannot read {}: {}", s, e))?;
+    CmykProfile::from_icc(icc)
+}
+
+fn parse_seconds(s: &str) -> Result<f64, String> {
+    let secs: f64 = s.parse().map_err(|_| format!("`{}` is not a number", s))?;
+    if secs.is_finite() && secs >= 0.0 {
+        Ok(secs)
+    } else {
+        Err("seconds can't be negative".to_string())
+    }
+}
+
+fn parse_dpi(s: &str) -> Result<f32, String> {
+    let dpi: f32 = s.parse().map_err(|_| format!("`{}` is not a number", s))?;
+    if dpi >= 1.0 {
+        Ok(dpi)
+    } else {
+        Err("DPI must be at least 1".to_string())
+    }
+}
+
+/// One part of `--pages`: a page or a range, open at either end, counting from 1.
+fn parse_page_range(s: &str) -> Result<RangeInclusive<u32>, String> {
+    let page = |p: &str, default: u32| -> Result<u32, String> {
+        match p.trim() {
+            "" => Ok(default),
+            p => match p.parse() {
+                Ok(0) | Err(_) => Err(format!("`{}` is not a page number", p)),
+                Ok(n) => Ok(n),
+            },
+        }
+    };
+    let range = match s.split_once('-') {
+        Some((first, last)) => page(first, 1)?..=page(last, u32::MAX)?,
+        None if s.trim().is_empty() => return Err("empty page range".to_string()),
+        None => page(s, 1)?..=page(s, 1)?,
+    };
+    match range.is_empty() {
+        true => Err(format!("`{}` is backwards", s.trim())),
+        false => Ok(range),
+    }
+}
+
+/// `WIDTHxHEIGHT` in pixels, or one number for both.
+fn parse_dimensions(s: &str) -> Result<(u32, u32), String> {
+    let px = |p: &str| p.trim().parse::<u32>().map_err(|_| format!("`{}` is not a pixel size", p.trim()));
+    match s.to_ascii_lowercase().split_once('x') {
+        Some((width, height)) => Ok((px(width)?, px(height)?)),
+        None => px(s).map(|n| (n, n)),
+    }
+}
+
+/// Accepts plain bytes or a KB/MB/GB suffix (powers of 1024), e.g. `2MB`, `750kb`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let upper = s.trim().to_ascii_uppercase();
+    let (number, unit) = match upper.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => upper.split_at(i),
+        None => (upper.as_str(), ""),
+    };
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        other => return Err(format!("unknown size unit `{}`", other)),
+    };
+    let value: f64 = number.trim().parse().map_err(|_| format!("`{}` is not a size", s))?;
+    if value <= 0.0 {
+        return Err("size must be positive".to_string());
+    }
+    Ok((value * multiplier as f64) as u64)
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum DocPass {
+    /// Flate-compress streams that are stored without any filter
+    CompressStreams,
+    /// Pack objects into object streams with a cross-reference stream (PDF 1.5)
+    ObjectStreams,
+    /// Drop fonts from page/form resources that no text uses
+    UnusedFonts,
+    /// Remove page thumbnails (/Thumb)
+    Thumbnails,
+    /// Remove application data in /PieceInfo
+    PieceInfo,
+    /// Remove private application data (Illustrator's AIPrivateData...)
+    PrivateData,
+    /// Every pass above
+    All,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Lossless {
+    /// Line art and screenshots get the smaller of lossless and high-quality JPEG
+    Auto,
+    /// Never produce JPEG
+    Always,
+    /// Always JPEG
+    Never,
+}
+
+impl From<Lossless> for LosslessMode {
+    fn from(mode: Lossless) -> Self {
+        match mode {
+            Lossless::Auto => LosslessMode::Auto,
+            Lossless::Always => LosslessMode::Always,
+            Lossless::Never => LosslessMode::Never,
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum BilevelFilter {
+    /// Whichever of CCITT G4 and Flate is smaller
+    Auto,
+    /// CCITT Group 4, what fax machines and most scanners use
+    Ccitt,
+    /// Flate on packed 1-bit rows
+    Flate,
+}
+
+impl From<BilevelFilter> for Bilevel {
+    fn from(filter: BilevelFilter) -> Self {
+        match filter {
+            BilevelFilter::Auto => Bilevel::Auto,
+            BilevelFilter::Ccitt => Bilevel::Ccitt,
+            BilevelFilter::Flate => Bilevel::Flate,
+        }
+    }
+}
+
+/// The selection options as one `Selection`.
+pub fn selection(cli: &Cli) -> Selection {
+    let (min_width, min_height) = cli.min_dimensions.unwrap_or((0, 0));
+    Selection {
+        pages: cli.pages.clone(),
+        min_bytes: cli.min_bytes.unwrap_or(0),
+        min_width,
+        min_height,
+        exclude: cli.exclude_objects.iter().copied().collect(),
+    }
+}
+
+/// The `--passes` list as the library's switches.
+pub fn doc_passes(passes: &[DocPass]) -> DocPasses {
+    let all = passes.contains(&DocPass::All);
+    let on = |p: DocPass| all || passes.contains(&p);
+    DocPasses {
+        compress_streams: on(DocPass::CompressStreams),
+        object_streams: on(DocPass::ObjectStreams),
+        unused_fonts: on(DocPass::UnusedFonts),
+        thumbnails: on(DocPass::Thumbnails),
+        piece_info: on(DocPass::PieceInfo),
+        private_data: on(DocPass::PrivateData),
+    }
+}
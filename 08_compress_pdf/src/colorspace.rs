@@ -0,0 +1,155 @@
+use crate::icc::CmykProfile;
+use lopdf::{Document, Object};
+use std::fmt;
+
+/// Image colour space, resolved against the document (references, ICC streams,
+/// palettes) so decoding can happen without access to the `Document`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PdfColorSpace {
+    Gray,
+    Rgb,
+    /// With the profile to get to sRGB, if the image or the user brought one
+    Cmyk(Option<CmykProfile>),
+    /// `[/Indexed base hival lookup]`, `lookup` holds `(hival + 1) * base.components()` bytes
+    Indexed { base: Box<PdfColorSpace>, hival: u8, lookup: Vec<u8> },
+    /// Anything we can't turn into pixels (Lab, Separation, DeviceN, Pattern...)
+    Unsupported(String),
+}
+
+impl PdfColorSpace {
+    /// Resolves the `/ColorSpace` entry of an image dictionary.
+    pub fn resolve(doc: &Document, obj: &Object) -> PdfColorSpace {
+        let obj = match doc.dereference(obj) {
+            Ok((_, o)) => o,
+            Err(e) => return PdfColorSpace::Unsupported(format!("broken reference ({})", e)),
+        };
+
+        match obj {
+            Object::Name(n) => Self::from_name(n),
+            Object::Array(arr) => {
+                let family = arr.first().and_then(|x| x.as_name().ok()).unwrap_or(b"");
+                match family {
+                    b"ICCBased" => Self::resolve_icc(doc, arr.get(1)),
+                    b"Indexed" | b"I" => Self::resolve_indexed(doc, arr),
+                    b"CalRGB" | b"CalGray" | b"CalCMYK" => Self::from_name(family),
+                    // e.g. [/DeviceRGB] written as a one-element array
+                    _ if arr.len() == 1 => Self::from_name(family),
+                    _ => PdfColorSpace::Unsupported(String::from_utf8_lossy(family).into_owned()),
+                }
+            }
+            _ => PdfColorSpace::Unsupported("not a name or array".to_string()),
+        }
+    }
+
+    fn from_name(name: &[u8]) -> PdfColorSpace {
+        match name {
+            b"DeviceGray" | b"G" | b"CalGray" => PdfColorSpace::Gray,
+            b"DeviceRGB" | b"RGB" | b"CalRGB" => PdfColorSpace::Rgb,
+            b"DeviceCMYK" | b"CMYK" | b"CalCMYK" => PdfColorSpace::Cmyk(None),
+            other => PdfColorSpace::Unsupported(String::from_utf8_lossy(other).into_owned()),
+        }
+    }
+
+    /// `[/ICCBased stream]`: the component count `/N` tells us which device space
+    /// the samples are laid out in. Only CMYK profiles are kept, gray and RGB
+    /// ones are close enough to their device space.
+    fn resolve_icc(doc: &Document, profile: Option<&Object>) -> PdfColorSpace {
+        let stream = match profile.map(|p| doc.dereference(p)) {
+            Some(Ok((_, Object::Stream(s)))) => s,
+            _ => return PdfColorSpace::Unsupported("ICCBased without profile stream".to_string()),
+        };
+
+        match stream.dict.get(b"N").and_then(|n| n.as_i64()) {
+            Ok(1) => PdfColorSpace::Gray,
+            Ok(3) => PdfColorSpace::Rgb,
+            Ok(4) => PdfColorSpace::Cmyk(Some(CmykProfile::embedded(
+                stream.decompressed_content().unwrap_or_else(|_| stream.content.clone()),
+            ))),
+            _ => match stream.dict.get(b"Alternate") {
+                Ok(alt) => Self::resolve(doc, alt),
+                Err(_) => PdfColorSpace::Unsupported("ICCBased with unknown /N".to_string()),
+            },
+        }
+    }
+
+    fn resolve_indexed(doc: &Document, arr: &[Object]) -> PdfColorSpace {
+        if arr.len() < 4 {
+            return PdfColorSpace::Unsupported("Indexed with missing operands".to_string());
+        }
+
+        let base = Self::resolve(doc, &arr[1]);
+        if matches!(base, PdfColorSpace::Indexed { .. } | PdfColorSpace::Unsupported(_)) {
+            return PdfColorSpace::Unsupported(format!("Indexed over {}", base));
+        }
+
+        let hival = match doc.dereference(&arr[2]).map(|(_, o)| o.as_i64()) {
+            Ok(Ok(h)) if (0..=255).contains(&h) => h as u8,
+            _ => return PdfColorSpace::Unsupported("Indexed with bad hival".to_string()),
+        };
+
+        // The lookup table is either a (hex) string or a stream
+        let lookup = match doc.dereference(&arr[3]) {
+            Ok((_, Object::String(bytes, _))) => bytes.clone(),
+            Ok((_, Object::Stream(s))) => s.decompressed_content().unwrap_or_else(|_| s.content.clone()),
+            _ => return PdfColorSpace::Unsupported("Indexed without lookup table".to_string()),
+        };
+
+        let needed = (hival as usize + 1) * base.components() as usize;
+        if lookup.len() < needed {
+            return PdfColorSpace::Unsupported(format!("Indexed lookup too short. Need {}, got {}", needed, lookup.len()));
+        }
+
+        PdfColorSpace::Indexed { base: Box::new(base), hival, lookup }
+    }
+
+    /// CMYK itself, or a palette whose entries are CMYK.
+    pub fn is_cmyk(&self) -> bool {
+        match self {
+            PdfColorSpace::Cmyk(_) => true,
+            PdfColorSpace::Indexed { base, .. } => base.is_cmyk(),
+            _ => false,
+        }
+    }
+
+    /// The profile CMYK samples (also palette entries) are converted with.
+    pub fn cmyk_profile(&self) -> Option<&CmykProfile> {
+        match self {
+            PdfColorSpace::Cmyk(profile) => profile.as_ref(),
+            PdfColorSpace::Indexed { base, .. } => base.cmyk_profile(),
+            _ => None,
+        }
+    }
+
+    /// Gives CMYK without a profile of its own (also under a palette) `profile`.
+    pub fn with_cmyk_profile(self, profile: &CmykProfile) -> PdfColorSpace {
+        match self {
+            PdfColorSpace::Cmyk(None) => PdfColorSpace::Cmyk(Some(profile.clone())),
+            PdfColorSpace::Indexed { base, hival, lookup } => {
+                PdfColorSpace::Indexed { base: Box::new(base.with_cmyk_profile(profile)), hival, lookup }
+            }
+            other => other,
+        }
+    }
+
+    /// Number of samples per pixel in the image data.
+    pub fn components(&self) -> u32 {
+        match self {
+            PdfColorSpace::Gray | PdfColorSpace::Indexed { .. } | PdfColorSpace::Unsupported(_) => 1,
+            PdfColorSpace::Rgb => 3,
+            PdfColorSpace::Cmyk(_) => 4,
+        }
+    }
+}
+
+impl fmt::Display for PdfColorSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PdfColorSpace::Gray => write!(f, "DeviceGray"),
+            PdfColorSpace::Rgb => write!(f, "DeviceRGB"),
+            PdfColorSpace::Cmyk(None) => write!(f, "DeviceCMYK"),
+            PdfColorSpace::Cmyk(Some(_)) => write!(f, "ICCBased CMYK"),
+            PdfColorSpace::Indexed { base, hival, .. } => write!(f, "Indexed {} {}", base, hival),
+            PdfColorSpace::Unsupported(name) => write!(f, "{}", name),
+        }
+    }
+}
@@ -0,0 +1,896 @@
+use crate::crypt::{self, Security};
+use crate::dedup::{self, Dedup};
+use crate::error::{Error, ImageError};
+use crate::icc::CmykProfile;
+use crate::lazy::{self, LazySource};
+use crate::lossless::LosslessMode;
+use crate::mono::Bilevel;
+use crate::objstm::Counting;
+use crate::optimize::{self, DocPasses};
+use crate::pdfa::{self, PdfaReport};
+use crate::pipeline::{EncodeSettings, NotExtracted, Outcome, extract_image_job, is_image_xobject, process_all, write_back};
+use crate::report::{Action, ImageReport};
+use crate::select::Selection;
+use crate::verify::{self, Thresholds};
+use crate::{incremental, mask, placement, signature, target};
+use lopdf::{Document, Object, ObjectId};
+use memmap2::Mmap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Progress lines for the command line, silent unless `verbose` is set.
+macro_rules! say {
+    ($self:ident, $($arg:tt)*) => {
+        if $self.verbose {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Recompresses the images of a PDF. Built with chained setters, every option
+/// starts at the command line's default:
+///
+/// ```no_run
+/// # use compress_pdf::{PdfCompressor, LosslessMode};
+/// let compressor = PdfCompressor::new().max_width(1600).quality(70.0).lossless(LosslessMode::Never);
+/// let (pdf, summary) = compressor.compress_bytes(&std::fs::read("in.pdf")?)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct PdfCompressor {
+    max_width: u32,
+    quality: f32,
+    target_dpi: Option<f32>,
+    target_size: Option<u64>,
+    keep_cmyk: bool,
+    cmyk_profile: Option<CmykProfile>,
+    lossless: LosslessMode,
+    grayscale: bool,
+    bilevel: Option<Bilevel>,
+    dedup: bool,
+    passes: DocPasses,
+    password: Option<String>,
+    decrypt: bool,
+    break_signatures: bool,
+    incremental: bool,
+    verify: Option<Thresholds>,
+    revert_flagged: bool,
+    memory_limit: Option<u64>,
+    selection: Selection,
+    pdfa: bool,
+    verbose: bool,
+}
+
+impl Default for PdfCompressor {
+    fn default() -> Self {
+        PdfCompressor {
+            max_width: 1200,
+            quality: 60.0,
+            target_dpi: None,
+            target_size: None,
+            keep_cmyk: false,
+            cmyk_profile: None,
+            lossless: LosslessMode::Auto,
+            grayscale: false,
+            bilevel: None,
+            dedup: true,
+            passes: DocPasses::default(),
+            password: None,
+            decrypt: false,
+            break_signatures: false,
+            incremental: false,
+            verify: None,
+            revert_flagged: false,
+            memory_limit: None,
+            selection: Selection::default(),
+            pdfa: false,
+            verbose: false,
+        }
+    }
+}
+
+/// What happened to one document.
+#[derive(Debug, Default, Clone)]
+pub struct Summary {
+    /// Image XObjects in the document, soft masks not counted
+    pub found: usize,
+    pub optimized: usize,
+    pub failed: usize,
+    /// Optimized images below the verify thresholds, kept anyway
+    pub flagged: usize,
+    /// Images put back because of `revert_flagged`
+    pub reverted: usize,
+    /// The saved file is bigger than `target_size`, even at the lowest settings
+    /// or because flagged images were put back
+    pub target_missed: bool,
+    /// Identical images merged before recompression
+    pub dedup: Dedup,
+    /// File sizes, only known when the compressor loaded and saved the file itself
+    pub input_size: u64,
+    pub output_size: u64,
+    /// Every image and what happened to it, in object id order
+    pub images: Vec<ImageReport>,
+    /// What the PDF/A conversion fixed and what it couldn't, if it ran
+    pub pdfa: Option<PdfaReport>,
+}
+
+impl PdfCompressor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Images wider than this are downscaled, keeping the aspect ratio.
+    pub fn max_width(mut self, px: u32) -> Self {
+        self.max_width = px;
+        self
+    }
+
+    /// JPEG quality passed to mozjpeg, 1-100.
+    pub fn quality(mut self, quality: f32) -> Self {
+        self.quality = quality;
+        self
+    }
+
+    /// Downsample to this resolution at the size images are drawn on the page.
+    pub fn target_dpi(mut self, dpi: Option<f32>) -> Self {
+        self.target_dpi = dpi;
+        self
+    }
+
+    /// Keep lowering quality and resolution until the saved file fits into this many bytes.
+    pub fn target_size(mut self, bytes: Option<u64>) -> Self {
+        self.target_size = bytes;
+        self
+    }
+
+    /// Write CMYK images as CMYK JPEGs instead of converting them to RGB.
+    pub fn keep_cmyk(mut self, keep: bool) -> Self {
+        self.keep_cmyk = keep;
+        self
+    }
+
+    /// Converts DeviceCMYK images to RGB through this profile (e.g. the press
+    /// profile the document was made for) instead of the plain formula. Images
+    /// with an `/ICCBased` profile of their own always use that one.
+    pub fn cmyk_profile(mut self, profile: Option<CmykProfile>) -> Self {
+        self.cmyk_profile = profile;
+        self
+    }
+
+    pub fn lossless(mut self, mode: LosslessMode) -> Self {
+        self.lossless = mode;
+        self
+    }
+
+    /// Store colour images whose pixels are all (nearly) gray, typically colour
+    /// scans of black-and-white pages, as DeviceGray.
+    pub fn grayscale(mut self, grayscale: bool) -> Self {
+        self.grayscale = grayscale;
+        self
+    }
+
+    /// Turn gray images that look like scanned text into 1-bit images with an
+    /// adaptive threshold, compressed with CCITT G4 or Flate. Implies `grayscale`.
+    pub fn bilevel(mut self, filter: Option<Bilevel>) -> Self {
+        self.bilevel = filter;
+        self
+    }
+
+    /// Merge byte-identical image copies into one object (on by default).
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    pub fn passes(mut self, passes: DocPasses) -> Self {
+        self.passes = passes;
+        self
+    }
+
+    /// User or owner password for encrypted PDFs; the empty user password is
+    /// tried when there is none.
+    pub fn password(mut self, password: Option<String>) -> Self {
+        self.password = password;
+        self
+    }
+
+    /// Save encrypted PDFs without encryption. Incremental updates always keep
+    /// the original encryption.
+    pub fn decrypt(mut self, decrypt: bool) -> Self {
+        self.decrypt = decrypt;
+        self
+    }
+
+    /// Compress signed PDFs anyway instead of refusing with [`Error::Signed`].
+    pub fn break_signatures(mut self, break_signatures: bool) -> Self {
+        self.break_signatures = break_signatures;
+        self
+    }
+
+    /// Append the changed objects to the original bytes instead of rewriting the
+    /// file, which keeps signatures valid. Doesn't apply to
+    /// [`compress_document`](Self::compress_document), which doesn't save.
+    pub fn incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Compare every replaced image with its original and flag the ones below `thresholds`.
+    pub fn verify(mut self, thresholds: Option<Thresholds>) -> Self {
+        self.verify = thresholds;
+        self
+    }
+
+    /// Put flagged images back as they were. Verifies with the default
+    /// thresholds if [`verify`](Self::verify) wasn't set.
+    pub fn revert_flagged(mut self, revert: bool) -> Self {
+        self.revert_flagged = revert;
+        self
+    }
+
+    /// Low-memory mode for very large files: big image streams stay in the file
+    /// until they are processed or written, files are mapped instead of read,
+    /// and images are processed in batches whose decode/resize/encode buffers
+    /// fit into this many bytes. An image bigger than that is processed on its own.
+    /// The rest of the document (pages, fonts, small images) is not counted.
+    /// `target_size` is ignored, it needs every image in memory at once.
+    pub fn memory_limit(mut self, bytes: Option<u64>) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Only recompress the images picked by `selection`, the others are reported
+    /// as skipped and left byte for byte as they are.
+    pub fn selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Make the output PDF/A-2b as far as possible without rewriting page content.
+    /// Implies `decrypt`, converts CMYK images to RGB even with `keep_cmyk`, and
+    /// rewrites the whole file even with `incremental`. What is left to fix by
+    /// hand ends up in [`Summary::pdfa`].
+    pub fn pdfa(mut self, pdfa: bool) -> Self {
+        self.pdfa = pdfa;
+        self
+    }
+
+    /// Print progress to stdout, as the command line does.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Compresses the images of an already loaded, unencrypted document in place.
+    /// Saving is up to the caller, so `input_size` and `output_size` stay 0.
+    pub fn compress_document(&self, doc: &mut Document) -> Result<Summary, Error> {
+        if doc.trailer.has(b"Encrypt") {
+            return Err(Error::Encryption("still encrypted, load it with a password first".to_string()));
+        }
+        self.check_signatures(doc, false)?;
+        self.process(doc, None, None)
+    }
+
+    /// Loads a PDF from memory, compresses it and returns the saved result.
+    pub fn compress_bytes(&self, pdf: &[u8]) -> Result<(Vec<u8>, Summary), Error> {
+        let mut output = Vec::new();
+        let summary = self.compress_to(pdf, &mut output)?;
+        Ok((output, summary))
+    }
+
+    /// Compresses `input` into a new file next to `output` and renames it over
+    /// `output` once it is complete, so `output` may be the input itself and is
+    /// never left half written.
+    pub fn compress_file(&self, input: &Path, output: &Path) -> Result<Summary, Error> {
+        let partial = output.with_extension("pdf.partial");
+        let mut file = BufWriter::new(File::create(&partial)?);
+        let result = self.compress_file_to(input, &mut file).and_then(|summary| {
+            file.flush()?;
+            Ok(summary)
+        });
+        drop(file);
+        match result {
+            Ok(summary) => {
+                std::fs::rename(&partial, output)?;
+                Ok(summary)
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&partial);
+                Err(e)
+            }
+        }
+    }
+
+    /// Compresses `input` and writes the result to `target` as it is produced.
+    pub fn compress_file_to<W: Write>(&self, input: &Path, target: &mut W) -> Result<Summary, Error> {
+        if self.memory_limit.is_none() {
+            return self.compress_to(&std::fs::read(input)?, target);
+        }
+        let file = File::open(input)?;
+        // Mapped instead of read: the OS pages the file in when an image is read
+        // back and can drop those pages again under memory pressure.
+        // SAFETY: the map is only read, and we never write to `input` while it
+        // exists; `compress_file` writes to a separate file and renames it.
+        let pdf = unsafe { Mmap::map(&file)? };
+        self.compress_to(&pdf, target)
+    }
+
+    pub(crate) fn compress_to<W: Write>(&self, pdf: &[u8], target: &mut W) -> Result<Summary, Error> {
+        let lazy = self.memory_limit.is_some();
+        let (mut doc, security) = crypt::load(pdf, self.password.as_deref(), lazy)?;
+        // Left-out data is read back encrypted, so the source needs the key even with `decrypt`
+        let source = lazy.then(|| LazySource::new(pdf, &doc, security.as_ref()));
+        if let Some(source) = &source {
+            say!(self, "🪶 Low-memory mode: {} image stream(s) left in the file until needed", source.count());
+        }
+
+        // PDF/A forbids encryption, and wants the file rewritten with its own header
+        let incremental = self.incremental && !self.pdfa;
+        let decrypt = (self.decrypt || self.pdfa) && !incremental;
+        if let Some(security) = &security {
+            let password = if security.owner { "owner" } else { "user" };
+            match decrypt {
+                true => say!(self, "🔓 Decrypted with the {} password, saving without encryption", password),
+                false => say!(self, "🔐 Decrypted with the {} password, will be encrypted again on save", password),
+            }
+        }
+        let security = if decrypt { None } else { security };
+        // An incremental update is compared against the document as loaded
+        let original = incremental.then(|| doc.clone());
+        self.check_signatures(&doc, incremental)?;
+
+        let mut summary = self.process(&mut doc, security.as_ref(), source.as_ref())?;
+
+        let mut output = Counting { inner: target, written: 0 };
+        match &original {
+            Some(original) => {
+                if self.passes.compress_streams {
+                    optimize::compress_streams(&mut doc);
+                }
+                let update =
+                    incremental::save_incremental(original, pdf, &doc, security.as_ref(), source.as_ref(), &mut output)?;
+                say!(self, "🧷 Incremental update: {} object(s) appended, {} freed", update.written, update.freed);
+            }
+            None => optimize::save_to(&mut doc, &self.passes, security.as_ref(), source.as_ref(), &mut output)?,
+        }
+
+        summary.input_size = pdf.len() as u64;
+        summary.output_size = output.written as u64;
+        // What was measured while fitting doesn't know about reverted images or PDF/A
+        summary.target_missed = self.budget().is_some_and(|budget| summary.output_size > budget);
+        Ok(summary)
+    }
+
+    /// `target_size`, unless low-memory mode leaves it out.
+    fn budget(&self) -> Option<u64> {
+        self.target_size.filter(|_| self.memory_limit.is_none())
+    }
+
+    /// Rewriting the file invalidates every signature in it, appending to it doesn't.
+    fn check_signatures(&self, doc: &Document, incremental: bool) -> Result<(), Error> {
+        let signatures = signature::find_signatures(doc);
+        if signatures.is_empty() {
+            return Ok(());
+        }
+        if incremental {
+            say!(self, "✍️  Keeping {}: changes go into an incremental update", signatures.join(", "));
+        } else if self.break_signatures {
+            say!(self, "⚠️  Breaking {}: the output will no longer validate", signatures.join(", "));
+        } else {
+            return Err(Error::Signed(signatures));
+        }
+        Ok(())
+    }
+
+    fn process(&self, doc: &mut Document, security: Option<&Security>, source: Option<&LazySource>) -> Result<Summary, Error> {
+        let settings = EncodeSettings {
+            max_width: self.max_width,
+            quality: self.quality,
+            keep_cmyk: self.keep_cmyk && !self.pdfa,
+            lossless: self.lossless,
+            width_scale: 1.0,
+            grayscale: self.grayscale,
+            bilevel: self.bilevel,
+        };
+
+        // Thumbnails and private data go first, no point recompressing them
+        let stripped = optimize::strip(doc, &self.passes);
+        if stripped.thumbnails + stripped.piece_info + stripped.private_data + stripped.fonts > 0 {
+            say!(
+                self,
+                "🧹 Stripped {} thumbnail(s), {} /PieceInfo, {} private data entr(ies), {} unused font(s)",
+                stripped.thumbnails, stripped.piece_info, stripped.private_data, stripped.fonts
+            );
+            doc.prune_objects();
+        }
+        // Masks are rewritten together with the image that uses them, never on their own
+        let mut mask_parents = mask::collect_mask_parents(doc);
+        let mut image_ids = BTreeSet::new();
+        for (id, obj) in doc.objects.iter() {
+            if is_image_xobject(obj) && !mask_parents.contains_key(id) {
+                image_ids.insert(*id);
+            }
+        }
+        let found = image_ids.len();
+        say!(self, "🔍 Found {} images inside PDF", found);
+        // Described up front, duplicates are gone from the document after dedup
+        let mut images: BTreeMap<ObjectId, ImageReport> = image_ids.iter().map(|&id| (id, ImageReport::describe(doc, id))).collect();
+
+        // Unselected images are dropped before dedup, so they can't be merged into
+        // (and then rewritten with) a selected copy
+        let drawn = match self.selection.by_page() {
+            true => placement::collect_placements(doc),
+            false => HashMap::new(),
+        };
+        let rejected: Vec<(ObjectId, String)> =
+            image_ids.iter().filter_map(|&id| Some((id, self.selection.reject(doc, id, drawn.get(&id))?))).collect();
+        if !rejected.is_empty() {
+            say!(self, "🎯 {} image(s) not selected, left as they are", rejected.len());
+        }
+        for (id, reason) in rejected {
+            image_ids.remove(&id);
+            if let Some(image) = images.get_mut(&id) {
+                image.finish(Action::Skipped, Some(format!("not selected: {}", reason)));
+            }
+        }
+
+        // Copies of the same image are compressed once and stored once. Images left
+        // in the file would all have to be read back to compare them, so they aren't.
+        let dedup = match (self.dedup, source) {
+            (false, _) => Dedup::default(),
+            (true, None) => dedup::dedup_images(doc, &mut image_ids),
+            (true, Some(source)) => {
+                let mut in_memory: BTreeSet<ObjectId> =
+                    image_ids.iter().filter(|&&id| !doc.objects.get(&id).is_some_and(|o| source.is_left_out(id, o))).copied().collect();
+                let dedup = dedup::dedup_images(doc, &mut in_memory);
+                for (copy, _) in &dedup.merged {
+                    image_ids.remove(copy);
+                }
+                dedup
+            }
+        };
+        if dedup.removed > 0 {
+            say!(self, "🧬 Merged {} duplicate image(s), {}kb saved", dedup.removed, dedup.bytes_saved / 1024);
+            // The removed copies no longer count as users of their masks
+            mask_parents = mask::collect_mask_parents(doc);
+        }
+        for (copy, kept) in &dedup.merged {
+            if let Some(image) = images.get_mut(copy) {
+                image.bytes_after = 0;
+                image.finish(Action::Deduplicated, Some(format!("same as object {}", kept.0)));
+            }
+        }
+
+        let mut summary = Summary { found, dedup, ..Summary::default() };
+
+        // How big every image is actually drawn, only needed for target_dpi
+        let placements = match self.target_dpi {
+            Some(_) => placement::collect_placements(doc),
+            None => HashMap::new(),
+        };
+        let thresholds = match (self.verify, self.revert_flagged) {
+            (Some(thresholds), _) => Some(thresholds),
+            (None, true) => Some(Thresholds::default()),
+            (None, false) => None,
+        };
+        let target_size = self.budget();
+        if self.target_size.is_some() && target_size.is_none() {
+            say!(self, "⚠️  Target size ignored in low-memory mode");
+        }
+
+        for batch in self.batches(doc, image_ids) {
+            if let Some(limit) = self.memory_limit
+                && let [id] = batch[..]
+                && working_memory(doc, id) > limit
+            {
+                say!(self, "⚠️  Img {} needs about {}MB, more than the memory limit", id.0, working_memory(doc, id) >> 20);
+            }
+
+            // 1. Extraction: copy everything we need out of the Document (sequential, ordered by id)
+            let mut jobs = Vec::with_capacity(batch.len());
+            for object_id in batch {
+                let image = images.get_mut(&object_id).expect("described above");
+                let extracted = match source {
+                    Some(source) => source
+                        .with_data(doc, object_id, |doc| extract_image_job(doc, object_id))
+                        .unwrap_or_else(|e| Err(NotExtracted::Failed(ImageError::Stream(e.to_string())))),
+                    None => extract_image_job(doc, object_id),
+                };
+                match extracted {
+                    Ok(mut job) => {
+                        if let Some(profile) = &self.cmyk_profile {
+                            job.colorspace = job.colorspace.with_cmyk_profile(profile);
+                        }
+                        // Images that are never drawn have no DPI, max_width still applies
+                        if let (Some(dpi), Some(placement)) = (self.target_dpi, placements.get(&object_id)) {
+                            job.dpi_width = Some(placement.width_for_dpi(dpi, job.width, job.height));
+                        }
+                        jobs.push(job);
+                    }
+                    Err(NotExtracted::Ignored) => {
+                        image.finish(Action::Skipped, Some("stencil mask or empty image".to_string()));
+                    }
+                    Err(NotExtracted::Skipped(reason)) => {
+                        say!(self, "   SKIP Img {}: {}", object_id.0, reason);
+                        image.finish(Action::Skipped, Some(reason));
+                    }
+                    Err(NotExtracted::Failed(e)) => {
+                        let reason = e.to_string();
+                        if !reason.contains("Unsupported") {
+                            say!(self, "   ❌ Failed extraction Img {}: {}", object_id.0, reason);
+                        }
+                        summary.failed += 1;
+                        image.finish(Action::Failed, Some(reason));
+                        image.error = Some(e);
+                    }
+                }
+            }
+
+            // Originals to put back if revert_flagged doesn't like the result.
+            // Left-out images are snapshotted without their data, which stays in the file.
+            let snapshot: HashMap<ObjectId, Object> = match self.revert_flagged {
+                true => jobs
+                    .iter()
+                    .flat_map(|job| std::iter::once(job.id).chain(job.smask.as_ref().map(|m| m.id)))
+                    .filter_map(|id| Some((id, doc.objects.get(&id)?.clone())))
+                    .collect(),
+                false => HashMap::new(),
+            };
+
+            let outcomes = match target_size {
+                Some(budget) => {
+                    let fit = target::fit_to_size(doc, &jobs, &settings, &mask_parents, &self.passes, security, budget)?;
+                    if self.verbose {
+                        target::print_report(&fit, &settings, budget);
+                    }
+                    summary.target_missed = fit.size > budget;
+                    *doc = fit.doc;
+                    fit.outcomes
+                }
+                None => {
+                    let results = process_all(&jobs, &settings);
+                    write_back(doc, &jobs, results, &mask_parents)
+                }
+            };
+
+            let checks = match thresholds {
+                Some(_) => verify::check_all(doc, &jobs, &outcomes),
+                None => jobs.iter().map(|_| None).collect(),
+            };
+
+            for ((job, outcome), check) in jobs.iter().zip(outcomes).zip(checks) {
+                say!(self, "➡️ Processing Img {} ({})", job.id.0, job.filter_name);
+                let image = images.get_mut(&job.id).expect("described above");
+                match outcome {
+                    Outcome::Optimized { before, after, label } => {
+                        say!(self, "   ✨ Optimized: {}kb -> {}kb ({})", before / 1024, after / 1024, label);
+                        let problem = match (check, &thresholds) {
+                            (Some(Ok(quality)), Some(thresholds)) => {
+                                say!(self, "   🔬 SSIM {:.4}, PSNR {:.1} dB", quality.ssim, quality.psnr);
+                                image.ssim = Some(quality.ssim);
+                                image.psnr = Some(quality.psnr);
+                                quality.problem(thresholds)
+                            }
+                            // Can't prove it's fine, so it isn't
+                            (Some(Err(e)), _) => Some(format!("can't verify: {}", e)),
+                            _ => None,
+                        };
+
+                        if let Some(problem) = problem {
+                            if self.revert_flagged {
+                                say!(self, "   ↩️  Reverted: {}", problem);
+                                for id in std::iter::once(job.id).chain(job.smask.as_ref().map(|m| m.id)) {
+                                    if let Some(object) = snapshot.get(&id) {
+                                        doc.objects.insert(id, object.clone());
+                                    }
+                                }
+                                summary.reverted += 1;
+                                image.finish(Action::Skipped, Some(format!("reverted, {}", problem)));
+                                continue;
+                            }
+                            say!(self, "   ⚠️  Flagged: {}", problem);
+                            summary.flagged += 1;
+                            image.reason = Some(problem);
+                        }
+
+                        summary.optimized += 1;
+                        image.bytes_after = after;
+                        image.encoding = Some(label);
+                        image.action = Action::Optimized;
+                    }
+                    Outcome::Skipped(reason) => {
+                        say!(self, "   SKIP: {}", reason);
+                        image.finish(Action::Skipped, Some(reason));
+                    }
+                    Outcome::Failed(e) => {
+                        say!(self, "   ❌ {}", e);
+                        summary.failed += 1;
+                        image.finish(Action::Failed, Some(e.to_string()));
+                        image.error = Some(e);
+                    }
+                }
+            }
+        }
+
+        if self.pdfa {
+            let report = pdfa::convert(doc, source);
+            for fixed in &report.fixed {
+                say!(self, "🗄️  PDF/A: {}", fixed);
+            }
+            for violation in &report.violations {
+                say!(self, "⚠️  PDF/A violation left: {}", violation);
+            }
+            summary.pdfa = Some(report);
+        }
+
+        doc.prune_objects();
+        summary.images = images.into_values().collect();
+        Ok(summary)
+    }
+
+    /// All images at once, or with a memory limit, runs of images (in id order)
+    /// whose working memory together fits into it.
+    fn batches(&self, doc: &Document, ids: BTreeSet<ObjectId>) -> Vec<Vec<ObjectId>> {
+        let Some(limit) = self.memory_limit else { return vec![ids.into_iter().collect()] };
+        let mut batches: Vec<Vec<ObjectId>> = Vec::new();
+        let mut used = 0;
+        for id in ids {
+            let need = working_memory(doc, id);
+            match batches.last_mut() {
+                Some(batch) if used + need <= limit => {
+                    batch.push(id);
+                    used += need;
+                }
+                _ => {
+                    batches.push(vec![id]);
+                    used = need;
+                }
+            }
+        }
+        batches
+    }
+}
+
+/// Rough peak memory of one image on the worker pool: the stored data, then
+/// the decoded samples, the picture and its resized copy at up to 4 bytes per
+/// pixel each, plus a byte per pixel for a soft mask.
+fn working_memory(doc: &Document, id: ObjectId) -> u64 {
+    let Ok(stream) = doc.get_object(id).and_then(|o| o.as_stream()) else { return 0 };
+    let int = |key: &[u8]| stream.dict.get(key).and_then(|v| v.as_i64()).unwrap_or(0).max(0) as u64;
+    let pixels = int(b"Width") * int(b"Height");
+    lazy::stored_len(doc, stream) as u64 + 13 * pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filters::{self, Decoded};
+    use lopdf::{Stream, dictionary};
+
+    /// Square RGB image with enough texture that JPEG beats Flate.
+    fn textured_image(size: u32) -> Stream {
+        let pixels: Vec<u8> = (0..size * size)
+            .flat_map(|i| {
+                let (x, y) = (i % size, i / size);
+                let noise = (x.wrapping_mul(7919) ^ y.wrapping_mul(104729)) % 23;
+                [(x + noise) as u8, (y + noise) as u8, ((x + y) / 2 + noise) as u8]
+            })
+            .collect();
+        let mut image = Stream::new(
+            dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => size, "Height" => size, "ColorSpace" => "DeviceRGB", "BitsPerComponent" => 8 },
+            pixels,
+        );
+        image.compress().unwrap();
+        image
+    }
+
+    /// One page showing `images` as /Im0, /Im1, ...
+    fn pdf_with_images(images: Vec<Stream>) -> Vec<u8> {
+        let mut doc = Document::with_version("1.4");
+        let pages_id = doc.new_object_id();
+        let mut xobjects = lopdf::Dictionary::new();
+        let mut content = Vec::new();
+        for (i, image) in images.into_iter().enumerate() {
+            xobjects.set(format!("Im{}", i), doc.add_object(image));
+            content.extend_from_slice(format!("q 200 0 0 200 0 0 cm /Im{} Do Q ", i).as_bytes());
+        }
+        let content = doc.add_object(Stream::new(dictionary! {}, content));
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "MediaBox" => vec![0.into(), 0.into(), 200.into(), 200.into()],
+            "Contents" => content,
+            "Resources" => dictionary! { "XObject" => xobjects },
+        });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+        pdf
+    }
+
+    fn sample_pdf() -> Vec<u8> {
+        pdf_with_images(vec![textured_image(200)])
+    }
+
+    #[test]
+    fn compresses_bytes_and_reports_every_image() {
+        let pdf = sample_pdf();
+        let compressor = PdfCompressor::new().max_width(100).verify(Some(Thresholds::default()));
+        let (output, summary) = compressor.compress_bytes(&pdf).unwrap();
+
+        assert_eq!((summary.found, summary.optimized, summary.failed), (1, 1, 0));
+        assert!(output.len() < pdf.len());
+        let image = &summary.images[0];
+        assert_eq!((image.action, image.encoding), (Action::Optimized, Some("jpeg")));
+        assert!(image.ssim.is_some() && image.error.is_none());
+
+        let reloaded = Document::load_mem(&output).unwrap();
+        let stream = reloaded.get_object((image.id, image.generation)).unwrap().as_stream().unwrap();
+        assert_eq!(stream.dict.get(b"Width").unwrap().as_i64().unwrap(), 100);
+    }
+
+    #[test]
+    fn inverted_cmyk_jpegs_keep_their_colours() {
+        // Adobe CMYK JPEG as Photoshop writes it (255 = no ink), undone by /Decode
+        let cyan = [0u8, 255, 255, 255].repeat(64 * 64);
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_CMYK);
+        comp.set_size(64, 64);
+        comp.set_quality(100.0);
+        let mut jpeg = Vec::new();
+        let mut started = comp.start_compress(&mut jpeg).unwrap();
+        started.write_scanlines(&cyan).unwrap();
+        started.finish().unwrap();
+        let image = Stream::new(
+            dictionary! {
+                "Type" => "XObject", "Subtype" => "Image", "Width" => 64, "Height" => 64, "BitsPerComponent" => 8,
+                "ColorSpace" => "DeviceCMYK", "Filter" => "DCTDecode",
+                "Decode" => vec![1.into(), 0.into(), 1.into(), 0.into(), 1.into(), 0.into(), 1.into(), 0.into()],
+            },
+            jpeg,
+        );
+
+        let compressor = PdfCompressor::new().lossless(LosslessMode::Never);
+        let (output, summary) = compressor.compress_bytes(&pdf_with_images(vec![image])).unwrap();
+        assert_eq!(summary.optimized, 1);
+        let doc = Document::load_mem(&output).unwrap();
+        let stream = doc.get_object((summary.images[0].id, 0)).unwrap().as_stream().unwrap();
+        assert!(!stream.dict.has(b"Decode"));
+        let rgb = image::load_from_memory(&stream.content).unwrap().to_rgb8();
+        let pixel = rgb.get_pixel(10, 10).0;
+        assert!(pixel[0] < 10 && pixel[1] > 245 && pixel[2] > 245, "{:?}", pixel);
+    }
+
+    #[test]
+    fn target_size_encrypts_an_encrypted_input_only_once() {
+        let mut doc = Document::load_mem(&sample_pdf()).unwrap();
+        crypt::encrypt_for_tests(&mut doc);
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+
+        let (output, summary) = PdfCompressor::new().target_size(Some(pdf.len() as u64)).compress_bytes(&pdf).unwrap();
+        assert!(!summary.target_missed);
+        let (reloaded, security) = crypt::load(&output, None, false).unwrap();
+        assert!(security.is_some());
+        let image = &summary.images[0];
+        let stream = reloaded.get_object((image.id, image.generation)).unwrap().as_stream().unwrap();
+        assert!(stream.content.starts_with(&[0xFF, 0xD8]), "{:02x?}", &stream.content[..4]);
+        let page = *reloaded.get_pages().values().next().unwrap();
+        assert!(reloaded.get_and_decode_page_content(page).is_ok_and(|c| !c.operations.is_empty()));
+    }
+
+    #[test]
+    fn reverted_images_count_against_the_target_size() {
+        let pdf = sample_pdf();
+        let budget = Some(pdf.len() as u64 / 2);
+        let (_, summary) = PdfCompressor::new().target_size(budget).compress_bytes(&pdf).unwrap();
+        assert!(!summary.target_missed);
+
+        // Nothing passes these, so every image goes back to its original
+        let strict = Thresholds { min_ssim: 1.1, min_psnr: 1000.0 };
+        let compressor = PdfCompressor::new().target_size(budget).verify(Some(strict)).revert_flagged(true);
+        let (output, summary) = compressor.compress_bytes(&pdf).unwrap();
+        assert_eq!(summary.reverted, 1);
+        assert!(summary.target_missed, "{} bytes for a budget of {:?}", output.len(), budget);
+    }
+
+    #[test]
+    fn refuses_documents_that_are_still_encrypted() {
+        let mut doc = Document::load_mem(&sample_pdf()).unwrap();
+        doc.trailer.set("Encrypt", dictionary! { "Filter" => "Standard" });
+        assert!(matches!(PdfCompressor::new().compress_document(&mut doc), Err(Error::Encryption(_))));
+    }
+
+    #[test]
+    fn low_memory_mode_reads_left_out_images_back_on_save() {
+        // No JPX decoder, so this one is skipped and has to be copied from the input
+        let jpx: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        let passthrough = Stream::new(
+            dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => 100, "Height" => 100, "Filter" => "JPXDecode" },
+            jpx.clone(),
+        );
+        let pdf = pdf_with_images(vec![textured_image(400), passthrough]);
+
+        // A one-byte ceiling puts every image into a batch of its own
+        let (output, summary) = PdfCompressor::new().memory_limit(Some(1)).compress_bytes(&pdf).unwrap();
+        assert_eq!((summary.found, summary.optimized, summary.failed), (2, 1, 0));
+        assert!(summary.images.iter().all(|i| i.bytes_before > lazy::LEAVE_OUT_MIN));
+
+        let reloaded = Document::load_mem(&output).unwrap();
+        let skipped = &summary.images[1];
+        assert_eq!(skipped.action, Action::Skipped);
+        assert_eq!(reloaded.get_object((skipped.id, skipped.generation)).unwrap().as_stream().unwrap().content, jpx);
+    }
+
+    #[test]
+    fn unselected_images_are_neither_touched_nor_merged() {
+        let pdf = pdf_with_images(vec![textured_image(400), textured_image(400)]);
+        let (_, all) = PdfCompressor::new().compress_bytes(&pdf).unwrap();
+        assert_eq!(all.dedup.removed, 1);
+
+        let excluded = all.images[0].id;
+        let selection = Selection { exclude: BTreeSet::from([excluded]), ..Selection::default() };
+        let (output, summary) = PdfCompressor::new().selection(selection).compress_bytes(&pdf).unwrap();
+        assert_eq!((summary.optimized, summary.dedup.removed), (1, 0));
+        assert_eq!(summary.images[0].action, Action::Skipped);
+
+        let original = Document::load_mem(&pdf).unwrap();
+        let reloaded = Document::load_mem(&output).unwrap();
+        let content = |doc: &Document| doc.get_object((excluded, 0)).unwrap().as_stream().unwrap().content.clone();
+        assert_eq!(content(&reloaded), content(&original));
+    }
+
+    #[test]
+    fn colour_scans_of_text_become_gray_or_bilevel() {
+        // Slightly warm paper with scanner noise and dark strokes every 10 px
+        let size = 200u32;
+        let pixels: Vec<u8> = (0..size * size)
+            .flat_map(|i| {
+                let (x, y) = (i % size, i / size);
+                let noise = (i.wrapping_mul(2_654_435_761) >> 28) as u8;
+                let v = if x % 10 < 2 && y % 16 < 12 { 30 + noise } else { 235 + noise };
+                [v + 4, v, v.saturating_sub(6)]
+            })
+            .collect();
+        let scan = Stream::new(
+            dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => size, "Height" => size, "ColorSpace" => "DeviceRGB", "BitsPerComponent" => 8 },
+            pixels,
+        );
+        let pdf = pdf_with_images(vec![scan]);
+        let image = |output: &[u8], summary: &Summary| {
+            let doc = Document::load_mem(output).unwrap();
+            let id = (summary.images[0].id, summary.images[0].generation);
+            let stream = doc.get_object(id).unwrap().as_stream().unwrap().clone();
+            (doc, stream)
+        };
+
+        let (output, summary) = PdfCompressor::new().grayscale(true).compress_bytes(&pdf).unwrap();
+        let (_, stream) = image(&output, &summary);
+        assert_eq!(stream.dict.get(b"ColorSpace").unwrap().as_name().unwrap(), b"DeviceGray");
+
+        let (output, summary) = PdfCompressor::new().bilevel(Some(Bilevel::Ccitt)).compress_bytes(&pdf).unwrap();
+        assert_eq!(summary.images[0].encoding, Some("ccitt"));
+        let (doc, stream) = image(&output, &summary);
+        assert_eq!(stream.dict.get(b"BitsPerComponent").unwrap().as_i64().unwrap(), 1);
+        let Ok(Decoded::Raw(rows)) = filters::decode_stream(&doc, &stream) else { panic!("CCITT data didn't decode") };
+        // First row: ink (0 bits) in columns 0-1 and 10-11
+        assert_eq!(&rows[..2], &[0b0011_1111, 0b1100_1111]);
+    }
+
+    #[test]
+    fn pdfa_output_has_an_output_intent_and_a_binary_header() {
+        let (output, summary) = PdfCompressor::new().pdfa(true).compress_bytes(&sample_pdf()).unwrap();
+        let report = summary.pdfa.unwrap();
+        assert!(report.violations.is_empty(), "{:?}", report.violations);
+        // Written by our own writer, lopdf's has no binary comment
+        assert!(output[..16].starts_with(b"%PDF-1.4\n%\xe2\xe3\xcf\xd3"));
+
+        let reloaded = Document::load_mem(&output).unwrap();
+        assert!(reloaded.catalog().unwrap().has(b"OutputIntents"));
+        assert!(reloaded.trailer.has(b"ID"));
+    }
+}
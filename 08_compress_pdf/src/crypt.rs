@@ -0,0 +1,625 @@
+use crate::error::Error;
+use crate::lazy;
+use aes::{Aes128, Aes256};
+use cbc::cipher::block_padding::{NoPadding, Pkcs7};
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use lopdf::xref::XrefEntry;
+use lopdf::{Dictionary, Document, Object, ObjectId, Reader};
+use md5::{Digest, Md5};
+use sha2::{Sha256, Sha384, Sha512};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::sync::Mutex;
+
+/// Padding string from the PDF spec, used to stretch passwords to 32 bytes.
+const PAD: [u8; 32] = [
+    0x28, 0xBF, 0x4E, 0x5E, 0x4E, 0x75, 0x8A, 0x41, 0x64, 0x00, 0x4E, 0x56, 0xFF, 0xFA, 0x01, 0x08,
+    0x2E, 0x2E, 0x00, 0xB6, 0xD0, 0x68, 0x3E, 0x80, 0x2F, 0x0C, 0xA9, 0xFE, 0x64, 0x53, 0x69, 0x7A,
+];
+
+/// How strings or streams are encrypted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Method {
+    Identity,
+    Rc4,
+    Aes128,
+    Aes256,
+}
+
+/// The Standard security handler of one document, unlocked with a password.
+/// Keeps the original `/Encrypt` dictionary and file key, so the document can be
+/// encrypted again on save and opens with the same passwords as before.
+#[derive(Debug, Clone)]
+pub struct Security {
+    dict: Dictionary,
+    /// Where the dictionary lives in the original file, unless it's direct in the trailer
+    id: Option<ObjectId>,
+    key: Vec<u8>,
+    strings: Method,
+    streams: Method,
+    encrypt_metadata: bool,
+    /// Whether the owner (not just the user) password was given
+    pub owner: bool,
+}
+
+/// lopdf 0.34 parses object streams while loading, before we get a chance to
+/// decrypt them, and silently drops them when they don't inflate. Its load filter
+/// is a plain `fn`, so the key it needs is parked here for the duration of the load,
+/// together with whether big images are left out (see [`lazy`]), and the first
+/// object stream that didn't decrypt. The filter runs on rayon's threads, so this
+/// can't be a thread-local; [`RELOADS`] keeps other loads out until it's cleared.
+static LOADING: Mutex<Option<Loading>> = Mutex::new(None);
+
+/// Held for a whole reload, so concurrent loads don't swap keys under each other.
+static RELOADS: Mutex<()> = Mutex::new(());
+
+struct Loading {
+    security: Security,
+    lazy: bool,
+    failed: Option<Error>,
+}
+
+/// Loads a PDF and, if it is encrypted, decrypts it in memory with `password`
+/// (user or owner; none means the empty user password most "protected" files use).
+/// The `/Encrypt` entry is taken out of the document and returned as [`Security`].
+///
+/// With `lazy`, the data of big image streams stays in `pdf`, see [`lazy::LazySource`].
+pub fn load(pdf: &[u8], password: Option<&str>, lazy: bool) -> Result<(Document, Option<Security>), Error> {
+    let filter = lazy.then_some(lazy::leave_out_images as _);
+    let doc = Reader { buffer: pdf, document: Document::new() }.read(filter)?;
+    if !doc.trailer.has(b"Encrypt") {
+        return Ok((doc, None));
+    }
+
+    let security = Security::open(&doc, password.unwrap_or("").as_bytes())?;
+
+    // Reload so the object streams get decrypted before lopdf unpacks them
+    let reload = RELOADS.lock().unwrap_or_else(|e| e.into_inner());
+    *LOADING.lock().unwrap_or_else(|e| e.into_inner()) = Some(Loading { security: security.clone(), lazy, failed: None });
+    let reloaded = Reader { buffer: pdf, document: Document::new() }.read(Some(decrypt_object_stream));
+    let loading = LOADING.lock().unwrap_or_else(|e| e.into_inner()).take();
+    drop(reload);
+    if let Some(Loading { failed: Some(e), .. }) = loading {
+        return Err(e);
+    }
+    let mut doc = reloaded?;
+
+    security.decrypt_document(&mut doc)?;
+    Ok((doc, Some(security)))
+}
+
+fn decrypt_object_stream(id: ObjectId, object: &mut Object) -> Option<(ObjectId, Object)> {
+    let lazy = match LOADING.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        Some(loading) => {
+            if let Object::Stream(stream) = object
+                && stream.dict.type_is(b"ObjStm")
+            {
+                let security = &loading.security;
+                match security.decrypt_bytes(id, &stream.content, security.streams) {
+                    Ok(plain) => stream.set_content(plain),
+                    Err(e) => {
+                        loading.failed.get_or_insert(e);
+                    }
+                }
+            }
+            loading.lazy
+        }
+        None => false,
+    };
+    if lazy {
+        lazy::leave_out(object);
+    }
+    // Top-level objects are kept as edited in place and the returned copy is
+    // dropped; objects unpacked from a stream are taken from the return value.
+    // Those are never streams, so only small objects get cloned.
+    match object {
+        Object::Stream(_) => Some((id, Object::Null)),
+        _ => Some((id, object.clone())),
+    }
+}
+
+impl Security {
+    /// Reads the `/Encrypt` dictionary and checks `password` against it, first as
+    /// the user password, then as the owner password.
+    pub fn open(doc: &Document, password: &[u8]) -> Result<Security, Error> {
+        let id = doc.trailer.get(b"Encrypt").and_then(|e| e.as_reference()).ok();
+        let dict = doc
+            .trailer
+            .get(b"Encrypt")
+            .and_then(|e| doc.dereference(e))
+            .and_then(|(_, e)| e.as_dict())
+            .map_err(|e| Error::Encryption(format!("broken /Encrypt: {}", e)))?
+            .clone();
+
+        let filter = dict.get(b"Filter").and_then(|f| f.as_name_str()).unwrap_or("");
+        if filter != "Standard" {
+            return Err(Error::Encryption(format!("unsupported security handler /{}", filter)));
+        }
+
+        let int = |key: &[u8], default: i64| dict.get(key).and_then(|v| v.as_i64()).unwrap_or(default);
+        let (v, r) = (int(b"V", 0), int(b"R", 0));
+        let (strings, streams, key_len) = match v {
+            1 => (Method::Rc4, Method::Rc4, 5),
+            2 => (Method::Rc4, Method::Rc4, (int(b"Length", 40) / 8).clamp(5, 16) as usize),
+            4 => (crypt_filter(&dict, b"StrF"), crypt_filter(&dict, b"StmF"), 16),
+            5 => (Method::Aes256, Method::Aes256, 32),
+            _ => return Err(Error::Encryption(format!("unsupported encryption /V {}", v))),
+        };
+
+        let id0 = doc
+            .trailer
+            .get(b"ID")
+            .and_then(|id| id.as_array())
+            .ok()
+            .and_then(|ids| ids.first())
+            .and_then(|id| id.as_str().ok())
+            .unwrap_or(&[])
+            .to_vec();
+        let bytes = |key: &[u8]| dict.get(key).and_then(|v| v.as_str()).map(|s| s.to_vec()).unwrap_or_default();
+        let handler = Handler {
+            r,
+            key_len,
+            o: bytes(b"O"),
+            u: bytes(b"U"),
+            oe: bytes(b"OE"),
+            ue: bytes(b"UE"),
+            p: int(b"P", 0) as i32,
+            id0,
+            encrypt_metadata: dict.get(b"EncryptMetadata").and_then(|m| m.as_bool()).unwrap_or(true),
+        };
+
+        let (key, owner) = match r {
+            2..=4 => match handler.user_key(password) {
+                Some(key) => (key, false),
+                None => (handler.owner_key(password).ok_or(Error::WrongPassword)?, true),
+            },
+            5 | 6 => match handler.user_key_aes256(password) {
+                Some(key) => (key, false),
+                None => (handler.owner_key_aes256(password).ok_or(Error::WrongPassword)?, true),
+            },
+            _ => return Err(Error::Encryption(format!("unsupported encryption revision /R {}", r))),
+        };
+
+        Ok(Security { dict, id, key, strings, streams, encrypt_metadata: handler.encrypt_metadata, owner })
+    }
+
+    /// Decrypts every string and stream in place, except objects that came out of
+    /// an object stream (already decrypted as part of it) and the xref streams.
+    /// Removes `/Encrypt` from the trailer.
+    fn decrypt_document(&self, doc: &mut Document) -> Result<(), Error> {
+        let encrypt_id = doc.trailer.get(b"Encrypt").and_then(|e| e.as_reference()).ok();
+        let packed: HashSet<u32> = doc
+            .reference_table
+            .entries
+            .iter()
+            .filter(|(_, entry)| matches!(entry, XrefEntry::Compressed { .. }))
+            .map(|(num, _)| *num)
+            .collect();
+
+        for (&id, object) in doc.objects.iter_mut() {
+            if Some(id) == encrypt_id || packed.contains(&id.0) || self.is_exempt(object) {
+                continue;
+            }
+            self.decrypt_in_place(id, object)?;
+        }
+
+        doc.trailer.remove(b"Encrypt");
+        if let Some(id) = encrypt_id {
+            doc.objects.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Encrypts every string and stream in place and puts `/Encrypt` back.
+    pub fn encrypt_document(&self, doc: &mut Document) {
+        for (&id, object) in doc.objects.iter_mut() {
+            if !self.is_exempt(object) {
+                self.encrypt_in_place(id, object);
+            }
+        }
+        self.attach(doc);
+    }
+
+    /// Adds the `/Encrypt` dictionary as a new object and references it from the trailer.
+    pub fn attach(&self, doc: &mut Document) -> ObjectId {
+        let id = doc.add_object(self.dict.clone());
+        doc.trailer.set("Encrypt", id);
+        id
+    }
+
+    /// The trailer `/Encrypt` value of the original file, for updates appended to it.
+    pub fn original_entry(&self) -> Object {
+        match self.id {
+            Some(id) => Object::Reference(id),
+            None => Object::Dictionary(self.dict.clone()),
+        }
+    }
+
+    /// Encrypts one object the way it is about to be written under `id`.
+    pub fn encrypt_object(&self, id: ObjectId, object: &mut Object) {
+        if !self.is_exempt(object) {
+            self.encrypt_in_place(id, object);
+        }
+    }
+
+    /// Decrypts one object read straight from the original file.
+    pub fn decrypt_object(&self, id: ObjectId, object: &mut Object) -> Result<(), Error> {
+        if !self.is_exempt(object) {
+            self.decrypt_in_place(id, object)?;
+        }
+        Ok(())
+    }
+
+    /// Encrypts raw stream data, e.g. a freshly built object stream.
+    pub fn encrypt_stream_data(&self, id: ObjectId, data: &[u8]) -> Vec<u8> {
+        self.encrypt_bytes(id, data, self.streams)
+    }
+
+    /// XRef streams are never encrypted, metadata only if the document says so,
+    /// and streams with their own `/Crypt` filter are left to it. Object streams
+    /// were decrypted while loading and are never written back as they are.
+    fn is_exempt(&self, object: &Object) -> bool {
+        let Object::Stream(stream) = object else { return false };
+        let type_name = stream.dict.get(b"Type").and_then(|t| t.as_name()).unwrap_or(b"");
+        let crypt_filter = match stream.dict.get(b"Filter") {
+            Ok(Object::Name(n)) => n == b"Crypt",
+            Ok(Object::Array(a)) => a.iter().any(|f| f.as_name().is_ok_and(|n| n == b"Crypt")),
+            _ => false,
+        };
+        matches!(type_name, b"XRef" | b"ObjStm") || (type_name == b"Metadata" && !self.encrypt_metadata) || crypt_filter
+    }
+
+    fn encrypt_in_place(&self, id: ObjectId, object: &mut Object) {
+        let Ok(()) = self.apply(object, true, &|data, method| Ok::<_, Infallible>(self.encrypt_bytes(id, data, method)));
+    }
+
+    fn decrypt_in_place(&self, id: ObjectId, object: &mut Object) -> Result<(), Error> {
+        self.apply(object, false, &|data, method| self.decrypt_bytes(id, data, method))
+    }
+
+    /// Runs `crypt` over every string and stream in `object`.
+    fn apply<E>(
+        &self,
+        object: &mut Object,
+        encrypt: bool,
+        crypt: &impl Fn(&[u8], Method) -> Result<Vec<u8>, E>,
+    ) -> Result<(), E> {
+        match object {
+            Object::String(text, _) => *text = crypt(text, self.strings)?,
+            Object::Array(items) => items.iter_mut().try_for_each(|o| self.apply(o, encrypt, crypt))?,
+            Object::Dictionary(dict) => dict.iter_mut().try_for_each(|(_, o)| self.apply(o, encrypt, crypt))?,
+            Object::Stream(stream) => {
+                stream.dict.iter_mut().try_for_each(|(_, o)| self.apply(o, encrypt, crypt))?;
+                // Nothing to decrypt, and `set_content` would overwrite the /Length
+                // of image data left in the file (low-memory mode)
+                if !encrypt && stream.content.is_empty() {
+                    return Ok(());
+                }
+                let content = crypt(&stream.content, self.streams)?;
+                stream.set_content(content);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn object_key(&self, id: ObjectId, method: Method) -> Vec<u8> {
+        if method == Method::Aes256 {
+            return self.key.clone();
+        }
+        let mut hasher = Md5::new();
+        hasher.update(&self.key);
+        hasher.update(&id.0.to_le_bytes()[..3]);
+        hasher.update(&id.1.to_le_bytes()[..2]);
+        if method == Method::Aes128 {
+            hasher.update(b"sAlT");
+        }
+        hasher.finalize()[..(self.key.len() + 5).min(16)].to_vec()
+    }
+
+    fn decrypt_bytes(&self, id: ObjectId, data: &[u8], method: Method) -> Result<Vec<u8>, Error> {
+        let key = self.object_key(id, method);
+        match method {
+            Method::Identity => Ok(data.to_vec()),
+            Method::Rc4 => Ok(rc4(&key, data)),
+            Method::Aes128 | Method::Aes256 => aes_decrypt(&key, data)
+                .ok_or_else(|| Error::Encryption(format!("object {} {} R doesn't decrypt", id.0, id.1))),
+        }
+    }
+
+    fn encrypt_bytes(&self, id: ObjectId, data: &[u8], method: Method) -> Vec<u8> {
+        let key = self.object_key(id, method);
+        match method {
+            Method::Identity => data.to_vec(),
+            Method::Rc4 => rc4(&key, data),
+            Method::Aes128 | Method::Aes256 => {
+                let mut iv = [0u8; 16];
+                getrandom::fill(&mut iv).expect("system random number generator");
+                aes_encrypt(&key, &iv, data)
+            }
+        }
+    }
+}
+
+fn crypt_filter(dict: &Dictionary, which: &[u8]) -> Method {
+    let name = dict.get(which).and_then(|n| n.as_name()).unwrap_or(b"Identity");
+    let cfm = dict
+        .get(b"CF")
+        .and_then(|cf| cf.as_dict())
+        .and_then(|cf| cf.get(name))
+        .and_then(|f| f.as_dict())
+        .and_then(|f| f.get(b"CFM"))
+        .and_then(|m| m.as_name())
+        .unwrap_or(b"None");
+    match cfm {
+        b"V2" => Method::Rc4,
+        b"AESV2" => Method::Aes128,
+        b"AESV3" => Method::Aes256,
+        _ => Method::Identity,
+    }
+}
+
+/// Everything from the `/Encrypt` dictionary and trailer that goes into the key.
+struct Handler {
+    r: i64,
+    key_len: usize,
+    o: Vec<u8>,
+    u: Vec<u8>,
+    oe: Vec<u8>,
+    ue: Vec<u8>,
+    p: i32,
+    id0: Vec<u8>,
+    encrypt_metadata: bool,
+}
+
+impl Handler {
+    /// Algorithm 2: file key from a user password (RC4 and AES-128, /R 2-4).
+    fn file_key(&self, password: &[u8]) -> Vec<u8> {
+        let mut hasher = Md5::new();
+        hasher.update(padded(password));
+        hasher.update(&self.o);
+        hasher.update(self.p.to_le_bytes());
+        hasher.update(&self.id0);
+        if self.r >= 4 && !self.encrypt_metadata {
+            hasher.update([0xff; 4]);
+        }
+        let mut key = hasher.finalize()[..self.key_len].to_vec();
+        if self.r >= 3 {
+            for _ in 0..50 {
+                key = Md5::digest(&key)[..self.key_len].to_vec();
+            }
+        }
+        key
+    }
+
+    /// Algorithms 4/5 + 6: the key if `password` is the user password.
+    fn user_key(&self, password: &[u8]) -> Option<Vec<u8>> {
+        let key = self.file_key(password);
+        let (expected, check) = if self.r == 2 {
+            (rc4(&key, &PAD), 32)
+        } else {
+            let mut hasher = Md5::new();
+            hasher.update(PAD);
+            hasher.update(&self.id0);
+            let mut hash = rc4(&key, &hasher.finalize());
+            for i in 1..=19u8 {
+                let round_key: Vec<u8> = key.iter().map(|b| b ^ i).collect();
+                hash = rc4(&round_key, &hash);
+            }
+            (hash, 16)
+        };
+        (self.u.len() >= check && expected[..check] == self.u[..check]).then_some(key)
+    }
+
+    /// Algorithm 7: the owner password decrypts /O into the user password.
+    fn owner_key(&self, password: &[u8]) -> Option<Vec<u8>> {
+        let mut hash = Md5::digest(padded(password)).to_vec();
+        if self.r >= 3 {
+            for _ in 0..50 {
+                hash = Md5::digest(&hash).to_vec();
+            }
+        }
+        let rc4_key = &hash[..self.key_len];
+        let user_password = if self.r == 2 {
+            rc4(rc4_key, &self.o)
+        } else {
+            let mut data = self.o.clone();
+            for i in (0..=19u8).rev() {
+                let round_key: Vec<u8> = rc4_key.iter().map(|b| b ^ i).collect();
+                data = rc4(&round_key, &data);
+            }
+            data
+        };
+        self.user_key(&user_password)
+    }
+
+    /// /R 5 and 6 (AES-256): hash(password + validation salt) must match /U.
+    fn user_key_aes256(&self, password: &[u8]) -> Option<Vec<u8>> {
+        let password = &password[..password.len().min(127)];
+        if self.u.len() < 48 || self.ue.len() < 32 {
+            return None;
+        }
+        if self.hash(password, &self.u[32..40], &[]) != self.u[..32] {
+            return None;
+        }
+        let intermediate = self.hash(password, &self.u[40..48], &[]);
+        aes256_unwrap(&intermediate, &self.ue[..32])
+    }
+
+    fn owner_key_aes256(&self, password: &[u8]) -> Option<Vec<u8>> {
+        let password = &password[..password.len().min(127)];
+        if self.o.len() < 48 || self.oe.len() < 32 || self.u.len() < 48 {
+            return None;
+        }
+        if self.hash(password, &self.o[32..40], &self.u[..48]) != self.o[..32] {
+            return None;
+        }
+        let intermediate = self.hash(password, &self.o[40..48], &self.u[..48]);
+        aes256_unwrap(&intermediate, &self.oe[..32])
+    }
+
+    /// SHA-256 for /R 5, Algorithm 2.B for /R 6.
+    fn hash(&self, password: &[u8], salt: &[u8], udata: &[u8]) -> Vec<u8> {
+        let mut k = Sha256::new().chain_update(password).chain_update(salt).chain_update(udata).finalize().to_vec();
+        if self.r == 5 {
+            return k;
+        }
+
+        let mut round = 0u32;
+        loop {
+            let block: Vec<u8> = [password, &k, udata].concat();
+            let k1 = block.repeat(64);
+            let e = cbc::Encryptor::<Aes128>::new_from_slices(&k[..16], &k[16..32])
+                .expect("16 byte key and iv")
+                .encrypt_padded_vec_mut::<NoPadding>(&k1);
+            let selector: u32 = e[..16].iter().map(|&b| b as u32).sum::<u32>() % 3;
+            k = match selector {
+                0 => Sha256::digest(&e).to_vec(),
+                1 => Sha384::digest(&e).to_vec(),
+                _ => Sha512::digest(&e).to_vec(),
+            };
+            round += 1;
+            if round >= 64 && (*e.last().unwrap_or(&0) as u32) <= round - 32 {
+                break;
+            }
+        }
+        k.truncate(32);
+        k
+    }
+}
+
+fn padded(password: &[u8]) -> [u8; 32] {
+    let mut out = PAD;
+    let n = password.len().min(32);
+    out[..n].copy_from_slice(&password[..n]);
+    out[n..].copy_from_slice(&PAD[..32 - n]);
+    out
+}
+
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut s: [u8; 256] = std::array::from_fn(|i| i as u8);
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+        s.swap(i, j as usize);
+    }
+
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|&byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(s[i as usize]);
+            s.swap(i as usize, j as usize);
+            byte ^ s[s[i as usize].wrapping_add(s[j as usize]) as usize]
+        })
+        .collect()
+}
+
+/// Encrypts `doc` with 40-bit RC4 (/V 1 /R 2) and an empty user password,
+/// for tests that need an encrypted input.
+#[cfg(test)]
+pub(crate) fn encrypt_for_tests(doc: &mut Document) -> Security {
+    use lopdf::dictionary;
+    let id0 = b"compress_pdf-test".to_vec();
+    let handler = Handler { r: 2, key_len: 5, o: vec![0; 32], u: Vec::new(), oe: Vec::new(), ue: Vec::new(), p: -4, id0, encrypt_metadata: true };
+    let key = handler.file_key(b"");
+    let dict = dictionary! {
+        "Filter" => "Standard",
+        "V" => 1,
+        "R" => 2,
+        "O" => Object::string_literal(handler.o.clone()),
+        "U" => Object::string_literal(rc4(&key, &PAD)),
+        "P" => -4,
+    };
+    let ids = vec![Object::string_literal(handler.id0.clone()), Object::string_literal(handler.id0)];
+    doc.trailer.set("ID", ids);
+    let security = Security { dict, id: None, key, strings: Method::Rc4, streams: Method::Rc4, encrypt_metadata: true, owner: false };
+    security.encrypt_document(doc);
+    security
+}
+
+/// AES-CBC with the IV in the first 16 bytes and PKCS#7 padding, as PDF stores it.
+fn aes_decrypt(key: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 32 || !data.len().is_multiple_of(16) {
+        // Nothing at all or only an IV (empty string), otherwise garbage
+        return (data.is_empty() || data.len() == 16).then(Vec::new);
+    }
+    let (iv, body) = data.split_at(16);
+    match key.len() {
+        16 => cbc::Decryptor::<Aes128>::new_from_slices(key, iv).ok()?.decrypt_padded_vec_mut::<Pkcs7>(body).ok(),
+        _ => cbc::Decryptor::<Aes256>::new_from_slices(key, iv).ok()?.decrypt_padded_vec_mut::<Pkcs7>(body).ok(),
+    }
+}
+
+fn aes_encrypt(key: &[u8], iv: &[u8; 16], data: &[u8]) -> Vec<u8> {
+    let body = match key.len() {
+        16 => cbc::Encryptor::<Aes128>::new_from_slices(key, iv).expect("aes-128 key").encrypt_padded_vec_mut::<Pkcs7>(data),
+        _ => cbc::Encryptor::<Aes256>::new_from_slices(key, iv).expect("aes-256 key").encrypt_padded_vec_mut::<Pkcs7>(data),
+    };
+    [&iv[..], &body].concat()
+}
+
+/// /UE and /OE hold the file key, AES-256 encrypted with a zero IV and no padding.
+fn aes256_unwrap(key: &[u8], wrapped: &[u8]) -> Option<Vec<u8>> {
+    cbc::Decryptor::<Aes256>::new_from_slices(key, &[0u8; 16])
+        .ok()?
+        .decrypt_padded_vec_mut::<NoPadding>(wrapped)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Stream, dictionary};
+
+    #[test]
+    fn rc4_known_answer() {
+        // RFC 6229 style check: "Key" / "Plaintext"
+        assert_eq!(rc4(b"Key", b"Plaintext"), [0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]);
+    }
+
+    #[test]
+    fn aes_round_trip() {
+        let key = [7u8; 16];
+        let encrypted = aes_encrypt(&key, &[1; 16], b"hello pdf");
+        assert_eq!(encrypted.len(), 32);
+        assert_eq!(aes_decrypt(&key, &encrypted).unwrap(), b"hello pdf");
+    }
+
+    #[test]
+    fn encrypt_then_decrypt_restores_strings_and_streams() {
+        let security = Security {
+            dict: dictionary! {},
+            id: None,
+            key: vec![3; 16],
+            strings: Method::Aes128,
+            streams: Method::Rc4,
+            encrypt_metadata: true,
+            owner: false,
+        };
+        let original = Object::Stream(Stream::new(dictionary! { "Title" => Object::string_literal("nested") }, b"BT ET".to_vec()));
+        let mut object = original.clone();
+        security.encrypt_object((4, 0), &mut object);
+        assert_ne!(object.as_stream().unwrap().content, b"BT ET");
+        security.decrypt_in_place((4, 0), &mut object).unwrap();
+        assert_eq!(object.as_stream().unwrap().content, b"BT ET");
+        assert_eq!(object.as_stream().unwrap().dict.get(b"Title").unwrap().as_str().unwrap(), b"nested");
+    }
+
+    #[test]
+    fn aes_data_that_doesnt_decrypt_is_an_error() {
+        let security = Security {
+            dict: dictionary! {},
+            id: None,
+            key: vec![3; 16],
+            strings: Method::Aes128,
+            streams: Method::Aes128,
+            encrypt_metadata: true,
+            owner: false,
+        };
+        let mut object = Object::string_literal("not encrypted, 33 bytes long....");
+        assert!(matches!(security.decrypt_in_place((4, 0), &mut object), Err(Error::Encryption(_))));
+        let mut empty = Object::string_literal("");
+        security.decrypt_in_place((4, 0), &mut empty).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+use crate::colorspace::PdfColorSpace;
+use crate::filters::{Decoded, ImageCodec, decode_stream};
+use crate::{icc, jpeg};
+use image::{DynamicImage, ImageBuffer, RgbaImage};
+use lopdf::{Document, Stream};
+
+/// Stream data ready for [`decode_pdf_image`]: DCTDecode stays a JPEG file,
+/// everything else gets its filters removed. JPX and JBIG2 come back as an
+/// error naming the codec, we can only pass those through untouched.
+pub fn stream_data(doc: &Document, stream: &Stream) -> Result<Decoded, String> {
+    match decode_stream(doc, stream)? {
+        Decoded::Image(codec @ (ImageCodec::Jpx | ImageCodec::Jbig2), _) => Err(format!("{} passthrough", codec)),
+        decoded => Ok(decoded),
+    }
+}
+
+/// `data` as pixels: gray and RGB as they are, CMYK converted to RGB.
+/// `decode` is the image's `/Decode` array, see [`decode_array`]. A JPEG's own
+/// size and component count win over the dictionary's, like in viewers.
+pub fn decode_pdf_image(data: &Decoded, width: u32, height: u32, cs: &PdfColorSpace, bpc: u32, decode: &[f32]) -> Result<DynamicImage, String> {
+    let (samples, device_cs, width, height) = device_samples(data, width, height, cs, bpc, decode)?;
+    samples_to_image(samples, width, height, &device_cs)
+}
+
+/// Decodes a CMYK (or Indexed-over-CMYK) image without converting it to RGB.
+/// `DynamicImage` has no CMYK variant, so the four channels travel as R=C, G=M, B=Y, A=K.
+pub fn decode_cmyk(data: &Decoded, width: u32, height: u32, cs: &PdfColorSpace, bpc: u32, decode: &[f32]) -> Result<RgbaImage, String> {
+    let (samples, device_cs, width, height) = device_samples(data, width, height, cs, bpc, decode)?;
+    if !matches!(device_cs, PdfColorSpace::Cmyk(_)) {
+        return Err(format!("{} is not CMYK", cs));
+    }
+    ImageBuffer::from_raw(width, height, samples).ok_or_else(|| "Failed to create CMYK buffer".to_string())
+}
+
+/// The `/Decode` array of an image as numbers, empty when it is missing or the
+/// default (`[0 1 0 1 ...]`, `[0 2^bpc-1]` for palettes).
+pub fn decode_array(stream: &Stream, cs: &PdfColorSpace, bpc: u32) -> Result<Vec<f32>, String> {
+    let Ok(values) = stream.dict.get(b"Decode").and_then(|d| d.as_array()) else { return Ok(Vec::new()) };
+    let decode = values
+        .iter()
+        .map(|v| v.as_float().or_else(|_| v.as_i64().map(|n| n as f32)))
+        .collect::<Result<Vec<f32>, _>>()
+        .map_err(|_| "/Decode with a value that isn't a number".to_string())?;
+
+    let pairs = match cs {
+        PdfColorSpace::Indexed { .. } => 1,
+        _ => cs.components() as usize,
+    };
+    if decode.len() != pairs * 2 {
+        return Err(format!("/Decode has {} values for {} components", decode.len(), pairs));
+    }
+
+    let is_default = match cs {
+        PdfColorSpace::Indexed { .. } => decode == [0.0, ((1u32 << bpc.min(8)) - 1) as f32],
+        _ => decode.iter().enumerate().all(|(i, &v)| v == (i % 2) as f32),
+    };
+    Ok(if is_default { Vec::new() } else { decode })
+}
+
+/// Unpacks the samples to 8 bits, applies `/Decode` and expands palettes,
+/// returning the device colour space and size of the samples.
+fn device_samples(data: &Decoded, width: u32, height: u32, cs: &PdfColorSpace, bpc: u32, decode: &[f32]) -> Result<(Vec<u8>, PdfColorSpace, u32, u32), String> {
+    if let PdfColorSpace::Unsupported(_) = cs {
+        return Err(format!("Unsupported Colorspace: {}", cs));
+    }
+
+    let data = match data {
+        Decoded::Raw(data) => data,
+        Decoded::Image(ImageCodec::Dct, jpeg) => return jpeg_samples(jpeg, cs, decode),
+        Decoded::Image(codec, _) => return Err(format!("{} images can't be decoded", codec)),
+    };
+
+    if let PdfColorSpace::Indexed { base, hival, lookup } = cs {
+        let mut indices = unpack(data, width, height, 1, bpc, false).map_err(|e| format!("{} for Indexed", e))?;
+        // /Decode maps the index range 0..2^bpc-1 onto [min max]
+        if let [min, max] = decode {
+            let top = ((1u32 << bpc.min(8)) - 1) as f32;
+            for index in &mut indices {
+                *index = (min + *index as f32 * (max - min) / top).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        let nc = base.components() as usize;
+        let mut samples = Vec::with_capacity(indices.len() * nc);
+        for index in indices {
+            // Out-of-range indices are clamped to hival, like viewers do
+            let at = index.min(*hival) as usize * nc;
+            samples.extend_from_slice(&lookup[at..at + nc]);
+        }
+        return Ok((samples, (**base).clone(), width, height));
+    }
+
+    let mut samples = unpack_samples(data, width, height, cs.components(), bpc).map_err(|e| format!("{} for {}", e, cs))?;
+    apply_decode(&mut samples, decode, cs.components() as usize);
+    Ok((samples, cs.clone(), width, height))
+}
+
+/// The samples of DCTDecode data; `/Decode` only applies if the JPEG has the
+/// declared number of components.
+fn jpeg_samples(data: &[u8], cs: &PdfColorSpace, decode: &[f32]) -> Result<(Vec<u8>, PdfColorSpace, u32, u32), String> {
+    let jpeg = jpeg::decode(data)?;
+    let device_cs = match (cs, jpeg.components) {
+        (PdfColorSpace::Indexed { .. }, _) => return Err(format!("JPEG data for {}", cs)),
+        (cs, n) if cs.components() == n => cs.clone(),
+        (_, 1) => PdfColorSpace::Gray,
+        (_, 3) => PdfColorSpace::Rgb,
+        (_, _) => PdfColorSpace::Cmyk(cs.cmyk_profile().cloned()),
+    };
+    let mut samples = jpeg.samples;
+    // A /Decode written for the declared colour space doesn't fit other data
+    if device_cs == *cs {
+        apply_decode(&mut samples, decode, jpeg.components as usize);
+    }
+    Ok((samples, device_cs, jpeg.width, jpeg.height))
+}
+
+/// Maps every sample through its component's `/Decode` range: 0 becomes `min`
+/// and 255 becomes `max`, so `[1 0]` inverts. Empty `decode` leaves them alone.
+fn apply_decode(samples: &mut [u8], decode: &[f32], components: usize) {
+    if decode.len() != components * 2 {
+        return;
+    }
+    let tables: Vec<[u8; 256]> = decode
+        .chunks_exact(2)
+        .map(|range| {
+            std::array::from_fn(|v| ((range[0] + v as f32 / 255.0 * (range[1] - range[0])).clamp(0.0, 1.0) * 255.0).round() as u8)
+        })
+        .collect();
+    for (i, sample) in samples.iter_mut().enumerate() {
+        *sample = tables[i % components][*sample as usize];
+    }
+}
+
+/// Builds an image from 8-bit samples laid out in a device colour space.
+fn samples_to_image(samples: Vec<u8>, width: u32, height: u32, cs: &PdfColorSpace) -> Result<DynamicImage, String> {
+    match cs {
+        PdfColorSpace::Rgb => {
+            let buf = ImageBuffer::from_raw(width, height, samples).ok_or("Failed to create RGB buffer")?;
+            Ok(DynamicImage::ImageRgb8(buf))
+        }
+        PdfColorSpace::Gray => {
+            let buf = ImageBuffer::from_raw(width, height, samples).ok_or("Failed to create Gray buffer")?;
+            Ok(DynamicImage::ImageLuma8(buf))
+        }
+        PdfColorSpace::Cmyk(profile) => {
+            let rgb_data = icc::cmyk_to_rgb(&samples, profile.as_ref());
+            let buf = ImageBuffer::from_raw(width, height, rgb_data).ok_or("Failed to create RGB buffer from CMYK")?;
+            Ok(DynamicImage::ImageRgb8(buf))
+        }
+        PdfColorSpace::Indexed { .. } | PdfColorSpace::Unsupported(_) => Err(format!("Unsupported Colorspace: {}", cs)),
+    }
+}
+
+/// Expands packed PDF samples into one byte per sample.
+///
+/// Every row starts on a byte boundary, so 1/2/4-bit rows carry padding bits at
+/// the end. Sub-byte values are scaled to the full 0-255 range, 16-bit samples
+/// keep their (big-endian) high byte.
+pub fn unpack_samples(data: &[u8], width: u32, height: u32, components: u32, bpc: u32) -> Result<Vec<u8>, String> {
+    unpack(data, width, height, components, bpc, true)
+}
+
+/// Same as [`unpack_samples`], but `scale = false` keeps the raw sample values
+/// (palette indices must not be stretched to 0-255).
+fn unpack(data: &[u8], width: u32, height: u32, components: u32, bpc: u32, scale: bool) -> Result<Vec<u8>, String> {
+    let samples_per_row = width as usize * components as usize;
+    let row_bytes = match bpc {
+        1 | 2 | 4 | 8 | 16 => (samples_per_row * bpc as usize).div_ceil(8),
+        _ => return Err(format!("Unsupported BitsPerComponent {}", bpc)),
+    };
+    let needed = row_bytes * height as usize;
+    if data.len() < needed {
+        return Err(format!("Data length mismatch ({} bpc). Need {}, got {}", bpc, needed, data.len()));
+    }
+
+    let mut out = Vec::with_capacity(samples_per_row * height as usize);
+    for row in data[..needed].chunks_exact(row_bytes) {
+        match bpc {
+            8 => out.extend_from_slice(row),
+            16 if scale => out.extend(row.chunks_exact(2).map(|pair| pair[0])),
+            16 => return Err("16-bit palette indices are not allowed".to_string()),
+            _ => {
+                let max = (1u16 << bpc) - 1;
+                let per_byte = 8 / bpc as usize;
+                for i in 0..samples_per_row {
+                    let byte = row[i / per_byte];
+                    let shift = 8 - bpc as usize * (i % per_byte + 1);
+                    let value = (byte >> shift) as u16 & max;
+                    out.push(if scale { (value * 255 / max) as u8 } else { value as u8 });
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_bit_gray_with_row_padding() {
+        // 10 px wide -> 2 bytes per row, last 6 bits are padding
+        let data = Decoded::Raw(vec![0b1010_1010, 0b1100_0000, 0b0101_0101, 0b0011_1111]);
+        let img = decode_pdf_image(&data, 10, 2, &PdfColorSpace::Gray, 1, &[]).unwrap().to_luma8();
+        let row0: Vec<u8> = (0..10).map(|x| img.get_pixel(x, 0)[0]).collect();
+        let row1: Vec<u8> = (0..10).map(|x| img.get_pixel(x, 1)[0]).collect();
+        assert_eq!(row0, [255, 0, 255, 0, 255, 0, 255, 0, 255, 255]);
+        assert_eq!(row1, [0, 255, 0, 255, 0, 255, 0, 255, 0, 0]);
+    }
+
+    #[test]
+    fn two_bit_gray() {
+        // 3 px wide -> 6 bits used per row
+        let data = Decoded::Raw(vec![0b00_01_10_00, 0b11_10_01_00]);
+        let img = decode_pdf_image(&data, 3, 2, &PdfColorSpace::Gray, 2, &[]).unwrap().to_luma8();
+        assert_eq!(img.as_raw(), &[0, 85, 170, 255, 170, 85]);
+    }
+
+    #[test]
+    fn four_bit_rgb() {
+        // 1 px RGB = 12 bits -> 2 bytes per row with 4 bits padding
+        let data = Decoded::Raw(vec![0xF0, 0x80, 0x0F, 0x10]);
+        let img = decode_pdf_image(&data, 1, 2, &PdfColorSpace::Rgb, 4, &[]).unwrap().to_rgb8();
+        assert_eq!(img.as_raw(), &[255, 0, 136, 0, 255, 17]);
+    }
+
+    #[test]
+    fn sixteen_bit_rgb_keeps_high_byte() {
+        let data = Decoded::Raw(vec![0xFF, 0xFF, 0x80, 0x01, 0x00, 0xFF]);
+        let img = decode_pdf_image(&data, 1, 1, &PdfColorSpace::Rgb, 16, &[]).unwrap().to_rgb8();
+        assert_eq!(img.as_raw(), &[255, 128, 0]);
+    }
+
+    #[test]
+    fn sixteen_bit_gray() {
+        let data = Decoded::Raw(vec![0x12, 0x34, 0xAB, 0xCD]);
+        let img = decode_pdf_image(&data, 2, 1, &PdfColorSpace::Gray, 16, &[]).unwrap().to_luma8();
+        assert_eq!(img.as_raw(), &[0x12, 0xAB]);
+    }
+
+    #[test]
+    fn one_bit_cmyk() {
+        // C=1 M=0 Y=0 K=0 -> cyan, then all zero -> white
+        let data = Decoded::Raw(vec![0b1000_0000, 0b0000_0000]);
+        let img = decode_pdf_image(&data, 2, 1, &PdfColorSpace::Cmyk(None), 1, &[]).unwrap().to_rgb8();
+        assert_eq!(img.as_raw(), &[0, 255, 255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn short_data_is_an_error() {
+        let err = decode_pdf_image(&Decoded::Raw(vec![0xFF]), 10, 2, &PdfColorSpace::Gray, 1, &[]).unwrap_err();
+        assert!(err.contains("Need 4, got 1"), "{}", err);
+    }
+
+    fn indexed(hival: u8, lookup: &[u8]) -> PdfColorSpace {
+        PdfColorSpace::Indexed { base: Box::new(PdfColorSpace::Rgb), hival, lookup: lookup.to_vec() }
+    }
+
+    #[test]
+    fn eight_bit_indexed_expands_palette() {
+        let cs = indexed(1, &[255, 0, 0, 0, 0, 255]);
+        let img = decode_pdf_image(&Decoded::Raw(vec![0, 1, 1, 0]), 2, 2, &cs, 8, &[]).unwrap().to_rgb8();
+        assert_eq!(img.as_raw(), &[255, 0, 0, 0, 0, 255, 0, 0, 255, 255, 0, 0]);
+    }
+
+    #[test]
+    fn two_bit_indexed_is_not_scaled_and_clamps() {
+        // indices 0, 1, 3 (3 > hival clamps to 1)
+        let cs = indexed(1, &[10, 20, 30, 40, 50, 60]);
+        let img = decode_pdf_image(&Decoded::Raw(vec![0b00_01_11_00]), 3, 1, &cs, 2, &[]).unwrap().to_rgb8();
+        assert_eq!(img.as_raw(), &[10, 20, 30, 40, 50, 60, 40, 50, 60]);
+    }
+
+    #[test]
+    fn indexed_over_gray() {
+        let cs = PdfColorSpace::Indexed { base: Box::new(PdfColorSpace::Gray), hival: 1, lookup: vec![7, 200] };
+        let img = decode_pdf_image(&Decoded::Raw(vec![0b0100_0000]), 2, 1, &cs, 1, &[]).unwrap().to_luma8();
+        assert_eq!(img.as_raw(), &[7, 200]);
+    }
+
+    #[test]
+    fn cmyk_stays_cmyk() {
+        let data = Decoded::Raw(vec![0b1000_0001, 0b0000_0000]);
+        let img = decode_cmyk(&data, 2, 1, &PdfColorSpace::Cmyk(None), 1, &[]).unwrap();
+        assert_eq!(img.as_raw(), &[255, 0, 0, 0, 0, 0, 0, 255]);
+        assert!(decode_cmyk(&Decoded::Raw(vec![0; 6]), 2, 1, &PdfColorSpace::Rgb, 8, &[]).is_err());
+    }
+
+    #[test]
+    fn raw_samples_that_look_like_a_jpeg_stay_samples() {
+        let data = Decoded::Raw(vec![0xFF, 0xD8, 0xFF, 0x00]);
+        let img = decode_pdf_image(&data, 4, 1, &PdfColorSpace::Gray, 8, &[]).unwrap().to_luma8();
+        assert_eq!(img.as_raw(), &[0xFF, 0xD8, 0xFF, 0x00]);
+    }
+
+    #[test]
+    fn unsupported_depth_is_an_error() {
+        assert!(unpack_samples(&[0; 16], 2, 2, 1, 3).is_err());
+    }
+
+    #[test]
+    fn decode_array_inverts() {
+        let img = decode_pdf_image(&Decoded::Raw(vec![0, 64, 255]), 3, 1, &PdfColorSpace::Gray, 8, &[1.0, 0.0]).unwrap().to_luma8();
+        assert_eq!(img.as_raw(), &[255, 191, 0]);
+    }
+
+    #[test]
+    fn decode_array_on_palette_indices() {
+        // [1 0] on 1-bit indices swaps the two palette entries
+        let cs = PdfColorSpace::Indexed { base: Box::new(PdfColorSpace::Gray), hival: 1, lookup: vec![7, 200] };
+        let img = decode_pdf_image(&Decoded::Raw(vec![0b0100_0000]), 2, 1, &cs, 1, &[1.0, 0.0]).unwrap().to_luma8();
+        assert_eq!(img.as_raw(), &[200, 7]);
+    }
+
+    #[test]
+    fn default_decode_arrays_are_dropped() {
+        let image = |decode: Vec<lopdf::Object>| Stream::new(lopdf::dictionary! { "Decode" => decode }, Vec::new());
+        let cmyk = PdfColorSpace::Cmyk(None);
+        assert!(decode_array(&image(vec![0.into(), 1.into(), 0.into(), 1.into(), 0.into(), 1.into(), 0.into(), 1.into()]), &cmyk, 8).unwrap().is_empty());
+        assert_eq!(decode_array(&image(vec![1.into(), 0.into(), 1.into(), 0.into(), 1.into(), 0.into(), 1.into(), 0.into()]), &cmyk, 8).unwrap(), [1.0, 0.0].repeat(4));
+        assert!(decode_array(&image(vec![1.into(), 0.into()]), &cmyk, 8).is_err());
+        assert!(decode_array(&image(vec![0.into(), 15.into()]), &indexed(15, &[0; 48]), 4).unwrap().is_empty());
+    }
+
+    #[test]
+    fn adobe_cmyk_jpeg_is_inverted_back_by_its_decode_array() {
+        // Photoshop-style: inverted samples, 255 = no ink. Pure cyan plus a white pixel.
+        let pixels = [[0u8, 255, 255, 255], [255, 255, 255, 255]].concat().repeat(32);
+        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_CMYK);
+        comp.set_size(8, 8);
+        comp.set_quality(100.0);
+        let mut data = Vec::new();
+        let mut started = comp.start_compress(&mut data).unwrap();
+        started.write_scanlines(&pixels).unwrap();
+        started.finish().unwrap();
+
+        let decode = [1.0, 0.0].repeat(4);
+        let img = decode_pdf_image(&Decoded::Image(ImageCodec::Dct, data), 8, 8, &PdfColorSpace::Cmyk(None), 8, &decode).unwrap().to_rgb8();
+        let near = |pixel: &image::Rgb<u8>, rgb: [u8; 3]| pixel.0.iter().zip(rgb).all(|(&a, b)| a.abs_diff(b) <= 4);
+        assert!(near(img.get_pixel(0, 0), [0, 255, 255]), "{:?}", img.get_pixel(0, 0));
+        assert!(near(img.get_pixel(1, 0), [255, 255, 255]), "{:?}", img.get_pixel(1, 0));
+    }
+}
@@ -0,0 +1,145 @@
+use lopdf::{Document, Object, ObjectId, Stream};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// What merging identical images saved.
+#[derive(Debug, Default, Clone)]
+pub struct Dedup {
+    /// Image streams dropped because an identical one already exists
+    pub removed: usize,
+    /// Stored bytes of those streams (and their soft masks)
+    pub bytes_saved: u64,
+    /// (removed copy, image it was merged into)
+    pub merged: Vec<(ObjectId, ObjectId)>,
+}
+
+/// Finds image XObjects with byte-identical streams (same dictionary, same data,
+/// same soft mask), re-points every reference at the lowest object id of each group
+/// and removes the copies from `image_ids` and the document.
+///
+/// Comparing the stored bytes instead of decoded pixels keeps this cheap and
+/// never merges two images that would render differently.
+pub fn dedup_images(doc: &mut Document, image_ids: &mut BTreeSet<ObjectId>) -> Dedup {
+    let mut groups: HashMap<u64, Vec<ObjectId>> = HashMap::new();
+    let mut redirect: HashMap<ObjectId, ObjectId> = HashMap::new();
+    let mut result = Dedup::default();
+
+    // BTreeSet order: the lowest id of every group is the one we keep
+    for &id in image_ids.iter() {
+        let Some(stream) = image_stream(doc, id) else { continue };
+        let candidates = groups.entry(fingerprint(doc, stream)).or_default();
+
+        match candidates.iter().find(|&&keep| same_image(doc, keep, id)) {
+            Some(&keep) => {
+                redirect.insert(id, keep);
+                result.merged.push((id, keep));
+                result.removed += 1;
+                result.bytes_saved += stored_size(doc, stream);
+            }
+            None => candidates.push(id),
+        }
+    }
+
+    if redirect.is_empty() {
+        return result;
+    }
+
+    for object in doc.objects.values_mut() {
+        repoint(object, &redirect);
+    }
+    for id in redirect.keys() {
+        image_ids.remove(id);
+        // Its soft mask becomes unreferenced and goes away with prune_objects
+        doc.objects.remove(id);
+    }
+
+    result
+}
+
+fn image_stream(doc: &Document, id: ObjectId) -> Option<&Stream> {
+    doc.get_object(id).and_then(|o| o.as_stream()).ok()
+}
+
+/// The image dictionary without entries that legitimately differ between copies.
+fn comparable_dict(stream: &Stream) -> String {
+    let mut dict = stream.dict.clone();
+    dict.remove(b"Length");
+    dict.remove(b"SMask");
+    format!("{:?}", dict)
+}
+
+fn smask<'a>(doc: &'a Document, stream: &Stream) -> Option<&'a Stream> {
+    let id = stream.dict.get(b"SMask").and_then(|o| o.as_reference()).ok()?;
+    image_stream(doc, id)
+}
+
+fn fingerprint(doc: &Document, stream: &Stream) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    comparable_dict(stream).hash(&mut hasher);
+    stream.content.hash(&mut hasher);
+    if let Some(mask) = smask(doc, stream) {
+        comparable_dict(mask).hash(&mut hasher);
+        mask.content.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Full comparison behind the hash, so a collision can never merge two images.
+fn same_image(doc: &Document, a: ObjectId, b: ObjectId) -> bool {
+    let (Some(a), Some(b)) = (image_stream(doc, a), image_stream(doc, b)) else { return false };
+    let same_stream = |x: &Stream, y: &Stream| x.content == y.content && comparable_dict(x) == comparable_dict(y);
+
+    same_stream(a, b)
+        && match (smask(doc, a), smask(doc, b)) {
+            (Some(x), Some(y)) => same_stream(x, y),
+            (None, None) => !a.dict.has(b"SMask") && !b.dict.has(b"SMask"),
+            _ => false,
+        }
+}
+
+fn stored_size(doc: &Document, stream: &Stream) -> u64 {
+    let mask_size = smask(doc, stream).map_or(0, |m| m.content.len());
+    (stream.content.len() + mask_size) as u64
+}
+
+fn repoint(object: &mut Object, redirect: &HashMap<ObjectId, ObjectId>) {
+    match object {
+        Object::Reference(id) => {
+            if let Some(keep) = redirect.get(id) {
+                *id = *keep;
+            }
+        }
+        Object::Array(arr) => arr.iter_mut().for_each(|o| repoint(o, redirect)),
+        Object::Dictionary(dict) => dict.iter_mut().for_each(|(_, o)| repoint(o, redirect)),
+        Object::Stream(stream) => stream.dict.iter_mut().for_each(|(_, o)| repoint(o, redirect)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    #[test]
+    fn merges_copies_and_repoints_references() {
+        let mut doc = Document::with_version("1.5");
+        let image = || Stream::new(dictionary! { "Subtype" => "Image", "Width" => 2, "Height" => 1 }, vec![1, 2, 3, 4, 5, 6]);
+        let a = doc.add_object(image());
+        let b = doc.add_object(image());
+        let c = doc.add_object(Stream::new(dictionary! { "Subtype" => "Image", "Width" => 2, "Height" => 1 }, vec![9; 6]));
+        let xobjects = doc.add_object(dictionary! { "A" => a, "B" => b, "C" => c });
+
+        let mut ids: BTreeSet<ObjectId> = [a, b, c].into_iter().collect();
+        let dedup = dedup_images(&mut doc, &mut ids);
+
+        assert_eq!(dedup.removed, 1);
+        assert_eq!(dedup.bytes_saved, 6);
+        assert_eq!(dedup.merged, vec![(b, a)]);
+        assert_eq!(ids.into_iter().collect::<Vec<_>>(), vec![a, c]);
+        let dict = doc.get_dictionary(xobjects).unwrap();
+        assert_eq!(dict.get(b"B").unwrap().as_reference().unwrap(), a);
+        assert!(doc.get_object(b).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+
+/// Why a whole document couldn't be compressed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading the input or writing the output failed
+    Io(std::io::Error),
+    /// The PDF couldn't be parsed
+    Pdf(lopdf::Error),
+    /// Encrypted in a way we can't open (other security handler, unknown revision,
+    /// broken `/Encrypt`), or still encrypted when handed over as a `Document`
+    Encryption(String),
+    /// Encrypted, and the password is neither the user nor the owner password
+    WrongPassword,
+    /// Digitally signed; rewriting the file would invalidate these signatures
+    Signed(Vec<String>),
+}
+
+impl Error {
+    /// The document was left alone on purpose rather than because something broke.
+    pub fn is_refusal(&self) -> bool {
+        matches!(self, Error::Encryption(_) | Error::WrongPassword | Error::Signed(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Pdf(e) => write!(f, "{}", e),
+            Error::Encryption(reason) => write!(f, "encrypted PDF: {}", reason),
+            Error::WrongPassword => f.write_str("encrypted PDF: wrong password"),
+            Error::Signed(signatures) => write!(f, "signed PDF ({}), left untouched", signatures.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Pdf(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<lopdf::Error> for Error {
+    fn from(e: lopdf::Error) -> Self {
+        Error::Pdf(e)
+    }
+}
+
+/// Why one image couldn't be recompressed. The document is still saved, the
+/// image just stays as it was.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum ImageError {
+    /// The stream filters couldn't be undone (unknown filter, corrupt data)
+    Stream(String),
+    /// The samples don't make a picture (length mismatch, unsupported colour space)
+    Decode(String),
+    /// The JPEG or lossless encoder failed
+    Encode(String),
+    /// The soft mask couldn't be resized along with the image
+    Mask(String),
+    /// The new stream couldn't be put into the document
+    WriteBack(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Stream(e) => write!(f, "{}", e),
+            ImageError::Decode(e) => write!(f, "Decode Pixel Error: {}", e),
+            ImageError::Encode(e) => write!(f, "Compression Error: {}", e),
+            ImageError::Mask(e) => write!(f, "SMask Error: {}", e),
+            ImageError::WriteBack(e) => write!(f, "Write-back Error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
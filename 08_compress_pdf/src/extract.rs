@@ -0,0 +1,286 @@
+use crate::colorspace::PdfColorSpace;
+use crate::crypt;
+use crate::decode::{decode_array, decode_pdf_image};
+use crate::error::Error;
+use crate::filters::{self, Decoded, ImageCodec};
+use crate::pipeline::is_image_xobject;
+use crate::placement;
+use crate::report::ImageReport;
+use image::ImageFormat;
+use lopdf::{Document, ObjectId, Stream};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Cursor;
+use std::path::Path;
+
+/// Name of the manifest written next to the extracted images.
+pub const MANIFEST: &str = "manifest.json";
+
+/// Writes every image of a PDF to a directory: DCTDecode images as the JPEG
+/// files they already are, JPXDecode ones as `.jp2`, everything else decoded
+/// to PNG. `manifest.json` lists what was found and where it went.
+#[derive(Debug, Clone, Default)]
+pub struct ImageExtractor {
+    password: Option<String>,
+    verbose: bool,
+}
+
+/// Everything written for one document, in object id order.
+#[derive(Serialize, Debug, Default)]
+pub struct Manifest {
+    pub images: Vec<ExtractedImage>,
+}
+
+impl Manifest {
+    pub fn written(&self) -> usize {
+        self.images.iter().filter(|i| i.file.is_some()).count()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.images.len() - self.written()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ExtractedImage {
+    pub id: u32,
+    pub generation: u16,
+    /// 1-based pages the image is drawn on; for masks, the pages of their images
+    pub pages: Vec<u32>,
+    /// File name inside the output directory, `None` if it couldn't be extracted
+    pub file: Option<String>,
+    /// "jpeg", "jpeg2000" or "png"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<&'static str>,
+    pub filter: String,
+    pub color_space: String,
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_component: u32,
+    /// Size of the stream in the PDF
+    pub stored_bytes: usize,
+    /// The soft mask or mask image of this image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mask: Option<u32>,
+    /// Set for masks: the image(s) they belong to
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mask_of: Vec<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What ends up in the file, before it is written.
+enum Payload {
+    /// Stored data that already is an image file
+    Passthrough(Vec<u8>, &'static str, &'static str),
+    /// Filters removed, still to be decoded and encoded as PNG
+    Samples { data: Vec<u8>, colorspace: PdfColorSpace, bpc: u32, decode: Vec<f32> },
+}
+
+impl ImageExtractor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Password for encrypted PDFs, user or owner. Without one the empty user
+    /// password is tried.
+    pub fn password(mut self, password: Option<String>) -> Self {
+        self.password = password;
+        self
+    }
+
+    /// Print a line per image that couldn't be extracted.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Loads `input` and extracts its images into `dir`, creating it if needed.
+    pub fn extract_file(&self, input: &Path, dir: &Path) -> Result<Manifest, Error> {
+        let (doc, _) = crypt::load(&std::fs::read(input)?, self.password.as_deref(), false)?;
+        self.extract_document(&doc, dir)
+    }
+
+    /// Extracts the images of an already loaded, unencrypted document into `dir`.
+    pub fn extract_document(&self, doc: &Document, dir: &Path) -> Result<Manifest, Error> {
+        if doc.trailer.has(b"Encrypt") {
+            return Err(Error::Encryption("still encrypted, load it with a password first".to_string()));
+        }
+        std::fs::create_dir_all(dir)?;
+
+        // Masks aren't drawn on their own, they show up wherever their image does
+        let placements = placement::collect_placements(doc);
+        let mut pages: BTreeMap<ObjectId, BTreeSet<u32>> =
+            placements.into_iter().map(|(id, placement)| (id, placement.pages)).collect();
+        let mut mask_of: BTreeMap<ObjectId, Vec<u32>> = BTreeMap::new();
+        let mut masks: BTreeMap<ObjectId, ObjectId> = BTreeMap::new();
+        for (&id, object) in &doc.objects {
+            let Ok(stream) = object.as_stream() else { continue };
+            if !is_image_xobject(object) {
+                continue;
+            }
+            for key in [&b"SMask"[..], &b"Mask"[..]] {
+                if let Ok(mask) = stream.dict.get(key).and_then(|o| o.as_reference()) {
+                    masks.insert(id, mask);
+                    mask_of.entry(mask).or_default().push(id.0);
+                    let parent_pages = pages.get(&id).cloned().unwrap_or_default();
+                    pages.entry(mask).or_default().extend(parent_pages);
+                }
+            }
+        }
+
+        // Reading the streams is sequential, decoding and writing runs on the rayon pool
+        let jobs: Vec<(ExtractedImage, Result<Payload, String>)> = doc
+            .objects
+            .iter()
+            .filter(|(_, object)| is_image_xobject(object))
+            .map(|(&id, object)| {
+                let described = ImageReport::describe(doc, id);
+                let image = ExtractedImage {
+                    id: id.0,
+                    generation: id.1,
+                    pages: pages.get(&id).map(|p| p.iter().copied().collect()).unwrap_or_default(),
+                    file: None,
+                    format: None,
+                    filter: described.filter,
+                    color_space: described.color_space,
+                    width: described.width,
+                    height: described.height,
+                    bits_per_component: described.bits_per_component,
+                    stored_bytes: described.bytes_before,
+                    mask: masks.get(&id).map(|m| m.0),
+                    mask_of: mask_of.remove(&id).unwrap_or_default(),
+                    error: None,
+                };
+                let payload = object.as_stream().map_err(|e| e.to_string()).and_then(|s| payload(doc, s));
+                (image, payload)
+            })
+            .collect();
+
+        let images: Vec<ExtractedImage> = jobs
+            .into_par_iter()
+            .map(|(mut image, payload)| {
+                match payload.and_then(|p| write_image(&image, p, dir)) {
+                    Ok((file, format)) => {
+                        image.file = Some(file);
+                        image.format = Some(format);
+                    }
+                    Err(e) => {
+                        if self.verbose {
+                            println!("   ❌ Img {}: {}", image.id, e);
+                        }
+                        image.error = Some(e);
+                    }
+                }
+                image
+            })
+            .collect();
+
+        let manifest = Manifest { images };
+        let file = std::fs::File::create(dir.join(MANIFEST))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), &manifest).map_err(std::io::Error::from)?;
+        Ok(manifest)
+    }
+}
+
+fn payload(doc: &Document, stream: &Stream) -> Result<Payload, String> {
+    let int = |key: &[u8], default: i64| stream.dict.get(key).and_then(|v| v.as_i64()).unwrap_or(default);
+    let stencil = stream.dict.get(b"ImageMask").and_then(|o| o.as_bool()).unwrap_or(false);
+
+    match filters::decode_stream(doc, stream)? {
+        Decoded::Image(ImageCodec::Dct, data) => Ok(Payload::Passthrough(data, "jpg", "jpeg")),
+        Decoded::Image(ImageCodec::Jpx, data) => Ok(Payload::Passthrough(data, "jp2", "jpeg2000")),
+        Decoded::Image(codec, _) => Err(format!("{} images can't be decoded", codec)),
+        Decoded::Raw(data) => {
+            // Stencil masks come out as black shapes on white
+            let colorspace = match stream.dict.get(b"ColorSpace") {
+                _ if stencil => PdfColorSpace::Gray,
+                Ok(cs) => PdfColorSpace::resolve(doc, cs),
+                Err(_) => PdfColorSpace::Rgb,
+            };
+            let bpc = if stencil { 1 } else { int(b"BitsPerComponent", 8) as u32 };
+            let decode = decode_array(stream, &colorspace, bpc)?;
+            Ok(Payload::Samples { data, colorspace, bpc, decode })
+        }
+    }
+}
+
+/// Writes one image, returning its file name and format.
+fn write_image(image: &ExtractedImage, payload: Payload, dir: &Path) -> Result<(String, &'static str), String> {
+    let name = |extension: &str| {
+        let page = image.pages.first().map_or_else(|| "unplaced".to_string(), |p| format!("page{:03}", p));
+        match image.generation {
+            0 => format!("{}_obj{}.{}", page, image.id, extension),
+            generation => format!("{}_obj{}_{}.{}", page, image.id, generation, extension),
+        }
+    };
+
+    let (file, data, format) = match payload {
+        Payload::Passthrough(data, extension, format) => (name(extension), data, format),
+        Payload::Samples { data, colorspace, bpc, decode } => {
+            let img = decode_pdf_image(&Decoded::Raw(data), image.width, image.height, &colorspace, bpc, &decode)?;
+            let mut png = Vec::new();
+            img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).map_err(|e| e.to_string())?;
+            (name("png"), png, "png")
+        }
+    };
+    std::fs::write(dir.join(&file), data).map_err(|e| format!("cannot write {}: {}", file, e))?;
+    Ok((file, format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Object, dictionary};
+
+    fn image(dict: lopdf::Dictionary, data: Vec<u8>) -> Stream {
+        let mut base = dictionary! { "Type" => "XObject", "Subtype" => "Image", "Width" => 2, "Height" => 2, "BitsPerComponent" => 8 };
+        for (key, value) in dict {
+            base.set(key, value);
+        }
+        Stream::new(base, data)
+    }
+
+    #[test]
+    fn writes_files_and_manifest() {
+        let mut doc = Document::with_version("1.5");
+        let smask = doc.add_object(image(dictionary! { "ColorSpace" => "DeviceGray" }, vec![0, 85, 170, 255]));
+        let rgb = doc.add_object(image(dictionary! { "ColorSpace" => "DeviceRGB", "SMask" => smask }, vec![200; 12]));
+        let jpeg = doc.add_object(image(dictionary! { "ColorSpace" => "DeviceRGB", "Filter" => "DCTDecode" }, b"\xFF\xD8\xFF not decoded".to_vec()));
+        let jbig2 = doc.add_object(image(dictionary! { "ColorSpace" => "DeviceGray", "Filter" => "JBIG2Decode" }, vec![1, 2, 3]));
+
+        let content = doc.add_object(Stream::new(dictionary! {}, b"q 10 0 0 10 0 0 cm /A Do Q".to_vec()));
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content,
+            "Resources" => dictionary! { "XObject" => dictionary! { "A" => rgb } },
+        });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+
+        let dir = std::env::temp_dir().join(format!("compress_pdf_extract_{}", std::process::id()));
+        let manifest = ImageExtractor::new().extract_document(&doc, &dir).unwrap();
+        let by_id = |id: ObjectId| manifest.images.iter().find(|i| i.id == id.0).unwrap();
+        assert_eq!((manifest.written(), manifest.failed()), (3, 1));
+
+        let drawn = by_id(rgb);
+        assert_eq!((drawn.file.as_deref(), drawn.mask), (Some(&*format!("page001_obj{}.png", rgb.0)), Some(smask.0)));
+        assert_eq!(image::open(dir.join(drawn.file.as_ref().unwrap())).unwrap().to_rgb8().get_pixel(1, 1).0, [200, 200, 200]);
+
+        let mask = by_id(smask);
+        assert_eq!((mask.pages.as_slice(), mask.mask_of.as_slice()), (&[1][..], &[rgb.0][..]));
+
+        let passthrough = by_id(jpeg);
+        assert_eq!(passthrough.file.as_deref(), Some(&*format!("unplaced_obj{}.jpg", jpeg.0)));
+        assert_eq!(std::fs::read(dir.join(passthrough.file.as_ref().unwrap())).unwrap(), b"\xFF\xD8\xFF not decoded");
+
+        assert!(by_id(jbig2).error.as_ref().unwrap().contains("JBIG2Decode"));
+        let written: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join(MANIFEST)).unwrap()).unwrap();
+        assert_eq!(written["images"].as_array().unwrap().len(), 4);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
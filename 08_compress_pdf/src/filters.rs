@@ -0,0 +1,405 @@
+use flate2::read::ZlibDecoder;
+use lopdf::{Dictionary, Document, Object, Stream};
+use std::fmt;
+use std::io::Read;
+
+/// Whole-image codecs: once one of these is reached the bytes are an image
+/// file of their own and the filter chain stops.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageCodec {
+    Dct,
+    Jpx,
+    Jbig2,
+}
+
+impl fmt::Display for ImageCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageCodec::Dct => write!(f, "DCTDecode"),
+            ImageCodec::Jpx => write!(f, "JPXDecode"),
+            ImageCodec::Jbig2 => write!(f, "JBIG2Decode"),
+        }
+    }
+}
+
+/// Stream content after running the filter chain.
+pub enum Decoded {
+    /// Every filter removed, plain samples
+    Raw(Vec<u8>),
+    /// Still encoded with a whole-image codec
+    Image(ImageCodec, Vec<u8>),
+}
+
+/// One `/Filter` entry with its `/DecodeParms`.
+struct Step<'a> {
+    name: &'static str,
+    parms: Option<&'a Dictionary>,
+}
+
+/// Long names of the filters in the order they have to be applied, e.g.
+/// `"ASCII85Decode+FlateDecode"`. Abbreviations (`/Fl`, `/AHx`...) are expanded.
+pub fn chain_name(dict: &Dictionary) -> String {
+    let names: Vec<&str> = filter_names(dict).iter().map(|n| normalize(n)).collect();
+    if names.is_empty() { "None".to_string() } else { names.join("+") }
+}
+
+fn filter_names(dict: &Dictionary) -> Vec<&[u8]> {
+    match dict.get(b"Filter") {
+        Ok(Object::Name(n)) => vec![n.as_slice()],
+        Ok(Object::Array(arr)) => arr.iter().filter_map(|o| o.as_name().ok()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn normalize(name: &[u8]) -> &'static str {
+    match name {
+        b"FlateDecode" | b"Fl" => "FlateDecode",
+        b"LZWDecode" | b"LZW" => "LZWDecode",
+        b"RunLengthDecode" | b"RL" => "RunLengthDecode",
+        b"ASCIIHexDecode" | b"AHx" => "ASCIIHexDecode",
+        b"ASCII85Decode" | b"A85" => "ASCII85Decode",
+        b"CCITTFaxDecode" | b"CCF" => "CCITTFaxDecode",
+        b"DCTDecode" | b"DCT" => "DCTDecode",
+        b"JPXDecode" => "JPXDecode",
+        b"JBIG2Decode" => "JBIG2Decode",
+        b"Crypt" => "Crypt",
+        _ => "Unknown",
+    }
+}
+
+fn steps<'a>(doc: &'a Document, dict: &'a Dictionary) -> Vec<Step<'a>> {
+    let parms_of = |o: &'a Object| doc.dereference(o).ok().and_then(|(_, o)| o.as_dict().ok());
+    let parms: Vec<Option<&Dictionary>> = match dict.get(b"DecodeParms").or_else(|_| dict.get(b"DP")) {
+        Ok(Object::Array(arr)) => arr.iter().map(parms_of).collect(),
+        Ok(single) => vec![parms_of(single)],
+        Err(_) => Vec::new(),
+    };
+
+    filter_names(dict)
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| Step { name: normalize(name), parms: parms.get(i).copied().flatten() })
+        .collect()
+}
+
+/// Runs the stream content through its `/Filter` chain.
+pub fn decode_stream(doc: &Document, stream: &Stream) -> Result<Decoded, String> {
+    let mut data = stream.content.clone();
+
+    for step in steps(doc, &stream.dict) {
+        data = match step.name {
+            "FlateDecode" => predict(inflate(&data)?, step.parms)?,
+            "LZWDecode" => predict(lzw(&data, step.parms)?, step.parms)?,
+            "RunLengthDecode" => run_length(&data),
+            "ASCIIHexDecode" => ascii_hex(&data)?,
+            "ASCII85Decode" => ascii85(&data)?,
+            "CCITTFaxDecode" => ccitt_fax(&data, step.parms, &stream.dict)?,
+            "DCTDecode" => return Ok(Decoded::Image(ImageCodec::Dct, data)),
+            "JPXDecode" => return Ok(Decoded::Image(ImageCodec::Jpx, data)),
+            "JBIG2Decode" => return Ok(Decoded::Image(ImageCodec::Jbig2, data)),
+            // Crypt with the Identity filter; real decryption happens at load time
+            "Crypt" => data,
+            other => return Err(format!("Unsupported Filter {}", other)),
+        };
+    }
+
+    Ok(Decoded::Raw(data))
+}
+
+/// Inflates as much as possible; slightly broken streams keep what decoded fine.
+fn inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoder = ZlibDecoder::new(data);
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        match decoder.read(&mut chunk) {
+            Ok(0) => return Ok(out),
+            Ok(n) => out.extend_from_slice(&chunk[..n]),
+            Err(e) if out.is_empty() => return Err(format!("Zlib Failed: {}", e)),
+            Err(_) => return Ok(out),
+        }
+    }
+}
+
+fn lzw(data: &[u8], parms: Option<&Dictionary>) -> Result<Vec<u8>, String> {
+    use weezl::{BitOrder, decode::Decoder};
+
+    let early_change = int_param(parms, b"EarlyChange", 1) != 0;
+    let mut decoder = if early_change {
+        Decoder::with_tiff_size_switch(BitOrder::Msb, 8)
+    } else {
+        Decoder::new(BitOrder::Msb, 8)
+    };
+
+    let mut out = Vec::new();
+    let result = decoder.into_stream(&mut out).decode_all(data);
+    match result.status {
+        // A missing EOD code is common and harmless
+        Err(e) if out.is_empty() => Err(format!("LZW Failed: {}", e)),
+        _ => Ok(out),
+    }
+}
+
+fn int_param(parms: Option<&Dictionary>, key: &[u8], default: i64) -> i64 {
+    parms.and_then(|p| p.get(key).ok()).and_then(|v| v.as_i64().ok()).unwrap_or(default)
+}
+
+fn bool_param(parms: Option<&Dictionary>, key: &[u8]) -> bool {
+    parms.and_then(|p| p.get(key).ok()).and_then(|v| v.as_bool().ok()).unwrap_or(false)
+}
+
+/// Undoes `/Predictor` 2 (TIFF) and 10-15 (PNG) after Flate/LZW.
+fn predict(data: Vec<u8>, parms: Option<&Dictionary>) -> Result<Vec<u8>, String> {
+    let predictor = int_param(parms, b"Predictor", 1);
+    if predictor < 2 {
+        return Ok(data);
+    }
+
+    let colors = int_param(parms, b"Colors", 1).max(1) as usize;
+    let bpc = int_param(parms, b"BitsPerComponent", 8).max(1) as usize;
+    let columns = int_param(parms, b"Columns", 1).max(1) as usize;
+    let row_bytes = (colors * bpc * columns).div_ceil(8);
+    let bpp = (colors * bpc).div_ceil(8);
+
+    if predictor == 2 {
+        if bpc != 8 {
+            return Err(format!("TIFF predictor with {} bpc is not supported", bpc));
+        }
+        let mut out = data;
+        for row in out.chunks_mut(row_bytes) {
+            for i in bpp..row.len() {
+                row[i] = row[i].wrapping_add(row[i - bpp]);
+            }
+        }
+        return Ok(out);
+    }
+
+    // PNG: every row is prefixed with its own filter type byte
+    let mut out = Vec::with_capacity(data.len());
+    let mut prev = vec![0u8; row_bytes];
+    for chunk in data.chunks(row_bytes + 1) {
+        if chunk.len() < 2 {
+            break;
+        }
+        let filter_type = chunk[0];
+        let mut row = chunk[1..].to_vec();
+        row.resize(row_bytes, 0);
+        for i in 0..row_bytes {
+            let a = if i >= bpp { row[i - bpp] } else { 0 };
+            let b = prev[i];
+            let c = if i >= bpp { prev[i - bpp] } else { 0 };
+            let predicted = match filter_type {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth(a, b, c),
+                t => return Err(format!("Bad PNG predictor row type {}", t)),
+            };
+            row[i] = row[i].wrapping_add(predicted);
+        }
+        out.extend_from_slice(&row);
+        prev = row;
+    }
+    Ok(out)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+fn run_length(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() * 2);
+    let mut i = 0;
+    while i < data.len() {
+        let len = data[i] as usize;
+        i += 1;
+        match len {
+            128 => break,
+            0..=127 => {
+                let end = (i + len + 1).min(data.len());
+                out.extend_from_slice(&data[i..end]);
+                i = end;
+            }
+            _ => {
+                if let Some(&b) = data.get(i) {
+                    out.extend(std::iter::repeat_n(b, 257 - len));
+                }
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+fn ascii_hex(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+    let mut high: Option<u8> = None;
+    for &c in data {
+        let nibble = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            b'>' => break,
+            c if c.is_ascii_whitespace() => continue,
+            c => return Err(format!("Bad character {:?} in ASCIIHexDecode", c as char)),
+        };
+        match high.take() {
+            Some(h) => out.push(h << 4 | nibble),
+            None => high = Some(nibble),
+        }
+    }
+    // An odd final digit behaves as if followed by 0
+    if let Some(h) = high {
+        out.push(h << 4);
+    }
+    Ok(out)
+}
+
+fn ascii85(data: &[u8]) -> Result<Vec<u8>, String> {
+    let data = data.strip_prefix(b"<~").unwrap_or(data);
+    let mut out = Vec::with_capacity(data.len() * 4 / 5);
+    let mut group = [0u8; 5];
+    let mut count = 0;
+
+    for &c in data {
+        match c {
+            b'~' => break,
+            b'z' if count == 0 => out.extend_from_slice(&[0, 0, 0, 0]),
+            b'!'..=b'u' => {
+                group[count] = c - b'!';
+                count += 1;
+                if count == 5 {
+                    out.extend_from_slice(&base85_value(&group).to_be_bytes());
+                    count = 0;
+                }
+            }
+            c if c.is_ascii_whitespace() => {}
+            c => return Err(format!("Bad character {:?} in ASCII85Decode", c as char)),
+        }
+    }
+
+    // A partial group of n characters encodes n-1 bytes, padded with 'u'
+    if count > 1 {
+        group[count..].fill(b'u' - b'!');
+        out.extend_from_slice(&base85_value(&group).to_be_bytes()[..count - 1]);
+    }
+    Ok(out)
+}
+
+fn base85_value(group: &[u8; 5]) -> u32 {
+    group.iter().fold(0u32, |acc, &d| acc.wrapping_mul(85).wrapping_add(d as u32))
+}
+
+/// CCITT Group 3 (1-D) and Group 4 fax data, unpacked to 1-bit rows where
+/// 0 is black (or 1 with `/BlackIs1 true`), ready for a DeviceGray 1 bpc image.
+fn ccitt_fax(data: &[u8], parms: Option<&Dictionary>, image: &Dictionary) -> Result<Vec<u8>, String> {
+    use fax::{Color, decoder};
+
+    let k = int_param(parms, b"K", 0);
+    let columns = int_param(parms, b"Columns", 1728);
+    let image_height = image.get(b"Height").and_then(|h| h.as_i64()).unwrap_or(0);
+    let rows = int_param(parms, b"Rows", image_height);
+    let black_is_1 = bool_param(parms, b"BlackIs1");
+
+    if !(1..=u16::MAX as i64).contains(&columns) || rows > u16::MAX as i64 {
+        return Err(format!("CCITT image too large ({}x{})", columns, rows));
+    }
+    let width = columns as u16;
+    let row_bytes = (width as usize).div_ceil(8);
+    let mut out = Vec::with_capacity(row_bytes * rows.max(0) as usize);
+
+    let mut push_line = |transitions: &[u16]| {
+        let mut row = vec![0u8; row_bytes];
+        for (i, color) in decoder::pels(transitions, width).enumerate() {
+            // White is 1 unless BlackIs1 flips it around
+            if (color == Color::White) != black_is_1 {
+                row[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+        out.extend_from_slice(&row);
+    };
+
+    let ok = if k < 0 {
+        let height = if rows > 0 { Some(rows as u16) } else { None };
+        decoder::decode_g4(data.iter().copied(), width, height, &mut push_line)
+    } else if k == 0 {
+        decoder::decode_g3(data.iter().copied(), &mut push_line)
+    } else {
+        return Err("CCITT Group 3 2-D (K > 0) is not supported".to_string());
+    };
+
+    if ok.is_none() && out.is_empty() {
+        return Err("CCITT decoding failed".to_string());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_length_literal_and_repeat() {
+        assert_eq!(run_length(&[2, b'a', b'b', b'c', 254, b'x', 128, 0, b'z']), b"abcxxx");
+    }
+
+    #[test]
+    fn ascii_hex_with_whitespace_and_odd_digit() {
+        assert_eq!(ascii_hex(b"48 65\n6c6C6f 7>").unwrap(), b"Hello\x70");
+    }
+
+    #[test]
+    fn ascii85_round_trip() {
+        // "Man " and "sure" from the classic example, plus a short tail
+        assert_eq!(ascii85(b"<~9jqo^F*2M7/c~>").unwrap(), b"Man sure.");
+        assert_eq!(ascii85(b"z/c~>").unwrap(), b"\0\0\0\0.");
+    }
+
+    #[test]
+    fn lzw_round_trip() {
+        let input = b"-----A---B--------A---B---".repeat(20);
+        let encoded = weezl::encode::Encoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8).encode(&input).unwrap();
+        assert_eq!(lzw(&encoded, None).unwrap(), input);
+    }
+
+    #[test]
+    fn png_up_predictor() {
+        let mut parms = Dictionary::new();
+        parms.set("Predictor", 12);
+        parms.set("Columns", 3);
+        let data = vec![0, 1, 2, 3, 2, 1, 1, 1];
+        assert_eq!(predict(data, Some(&parms)).unwrap(), vec![1, 2, 3, 2, 3, 4]);
+    }
+
+    #[test]
+    fn ccitt_g4_round_trip() {
+        use fax::{Color, VecWriter, encoder::Encoder};
+
+        let width = 16u16;
+        let lines: Vec<Vec<Color>> = (0..4)
+            .map(|y| (0..width).map(|x| if (x / 4 + y) % 2 == 0 { Color::Black } else { Color::White }).collect())
+            .collect();
+        let mut encoder = Encoder::new(VecWriter::new());
+        for line in &lines {
+            encoder.encode_line(line.iter().copied(), width).unwrap();
+        }
+        let encoded = encoder.finish().unwrap().finish();
+
+        let mut parms = Dictionary::new();
+        parms.set("K", -1);
+        parms.set("Columns", width as i64);
+        parms.set("Rows", 4);
+        let out = ccitt_fax(&encoded, Some(&parms), &Dictionary::new()).unwrap();
+        // black = 0 bits: row 0 is BBBB WWWW BBBB WWWW
+        assert_eq!(out, vec![0x0F, 0x0F, 0xF0, 0xF0, 0x0F, 0x0F, 0xF0, 0xF0]);
+    }
+}
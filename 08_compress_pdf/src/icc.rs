@@ -0,0 +1,123 @@
+use moxcms::{ColorProfile, DataColorSpace, Layout, Transform8BitExecutor, TransformOptions};
+use std::fmt;
+use std::sync::Arc;
+
+/// An ICC profile for CMYK samples, from an `/ICCBased` colour space or given by
+/// the user for DeviceCMYK. Cheap to clone, images share the bytes.
+#[derive(Clone, PartialEq)]
+pub struct CmykProfile {
+    icc: Arc<[u8]>,
+}
+
+impl CmykProfile {
+    /// Checks that `icc` is a CMYK profile we can convert to sRGB with.
+    pub fn from_icc(icc: Vec<u8>) -> Result<CmykProfile, String> {
+        let profile = CmykProfile { icc: icc.into() };
+        profile.transform()?;
+        Ok(profile)
+    }
+
+    /// Embedded profiles aren't checked until they are used, a broken one then
+    /// falls back to the plain formula.
+    pub(crate) fn embedded(icc: Vec<u8>) -> CmykProfile {
+        CmykProfile { icc: icc.into() }
+    }
+
+    fn transform(&self) -> Result<Arc<Transform8BitExecutor>, String> {
+        let profile = ColorProfile::new_from_slice(&self.icc).map_err(|e| format!("bad ICC profile: {}", e))?;
+        if profile.color_space != DataColorSpace::Cmyk {
+            return Err(format!("ICC profile is for {:?}, not CMYK", profile.color_space));
+        }
+        // 8-bit CMYK uses the RGBA layout
+        profile
+            .create_transform_8bit(Layout::Rgba, &ColorProfile::new_srgb(), Layout::Rgb, TransformOptions::default())
+            .map_err(|e| format!("ICC profile can't be converted to sRGB: {}", e))
+    }
+}
+
+impl fmt::Debug for CmykProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CmykProfile({} bytes)", self.icc.len())
+    }
+}
+
+/// Converts C, M, Y, K samples (0 = no ink) to R, G, B: through `profile` if
+/// there is one that works, otherwise with the uncalibrated `(1-c)(1-k)` formula.
+pub fn cmyk_to_rgb(samples: &[u8], profile: Option<&CmykProfile>) -> Vec<u8> {
+    if let Some(transform) = profile.and_then(|p| p.transform().ok()) {
+        let mut rgb = vec![0; samples.len() / 4 * 3];
+        if transform.transform(&samples[..samples.len() / 4 * 4], &mut rgb).is_ok() {
+            return rgb;
+        }
+    }
+
+    let mut rgb = Vec::with_capacity(samples.len() / 4 * 3);
+    for chunk in samples.chunks_exact(4) {
+        let c = chunk[0] as f32 / 255.0;
+        let m = chunk[1] as f32 / 255.0;
+        let y = chunk[2] as f32 / 255.0;
+        let k = chunk[3] as f32 / 255.0;
+        rgb.push((255.0 * (1.0 - c) * (1.0 - k)) as u8);
+        rgb.push((255.0 * (1.0 - m) * (1.0 - k)) as u8);
+        rgb.push((255.0 * (1.0 - y) * (1.0 - k)) as u8);
+    }
+    rgb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use moxcms::{LutDataType, LutStore, LutType, LutWarehouse, Matrix3d, ProfileClass};
+
+    /// A CMYK printer profile (lut16, 2x2x2x2 grid) that ignores C, M and Y and
+    /// only lets K darken the paper, so it's easy to tell apart from the formula.
+    fn k_only_profile() -> Vec<u8> {
+        let identity: Vec<u16> = vec![0, 65535];
+        let mut clut = Vec::new();
+        for i in 0..16 {
+            // Grid order is C, M, Y, K with K varying fastest; Lab in lut16 encoding
+            let lightness = if i % 2 == 1 { 0 } else { 0xFF00 };
+            clut.extend([lightness, 0x8000, 0x8000]);
+        }
+        let lut = LutDataType {
+            num_input_channels: 4,
+            num_output_channels: 3,
+            num_clut_grid_points: 2,
+            matrix: Matrix3d::IDENTITY,
+            num_input_table_entries: 2,
+            num_output_table_entries: 2,
+            input_table: LutStore::Store16(identity.repeat(4)),
+            clut_table: LutStore::Store16(clut),
+            output_table: LutStore::Store16(identity.repeat(3)),
+            lut_type: LutType::Lut16,
+        };
+        let mut profile = ColorProfile::default();
+        profile.profile_class = ProfileClass::OutputDevice;
+        profile.color_space = DataColorSpace::Cmyk;
+        profile.pcs = DataColorSpace::Lab;
+        profile.lut_a_to_b_perceptual = Some(LutWarehouse::Lut(lut.clone()));
+        profile.lut_a_to_b_colorimetric = Some(LutWarehouse::Lut(lut));
+        profile.encode().unwrap()
+    }
+
+    #[test]
+    fn profile_is_used_instead_of_the_formula() {
+        let profile = CmykProfile::from_icc(k_only_profile()).unwrap();
+        // Full cyan, then full black
+        let samples = [255, 0, 0, 0, 0, 0, 0, 255];
+
+        let plain = cmyk_to_rgb(&samples, None);
+        assert_eq!(plain, [0, 255, 255, 0, 0, 0]);
+
+        let managed = cmyk_to_rgb(&samples, Some(&profile));
+        assert!(managed[..3].iter().all(|&v| v > 240), "{:?}", managed);
+        assert!(managed[3..].iter().all(|&v| v < 15), "{:?}", managed);
+    }
+
+    #[test]
+    fn broken_profiles_fall_back_to_the_formula() {
+        assert!(CmykProfile::from_icc(b"not a profile".to_vec()).is_err());
+        let broken = CmykProfile::embedded(b"not a profile".to_vec());
+        assert_eq!(cmyk_to_rgb(&[255, 0, 0, 0], Some(&broken)), [0, 255, 255]);
+    }
+}
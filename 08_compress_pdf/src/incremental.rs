@@ -0,0 +1,163 @@
+use crate::crypt::Security;
+use crate::lazy::LazySource;
+use crate::objstm::{self, Entry};
+use lopdf::xref::XrefType;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+/// What an incremental update added on top of the original file.
+#[derive(Debug, Default)]
+pub struct Update {
+    /// Objects written again (replaced images, edited dictionaries) or new
+    pub written: usize,
+    /// Objects no longer used, marked free in the new xref section
+    pub freed: usize,
+}
+
+/// Writes `source` (the original file) unchanged and appends one update section
+/// with every object of `doc` that differs from `original`, plus free entries for
+/// the ones that were removed. The original revision, and with it any signature's
+/// byte range, stays intact and recoverable.
+///
+/// The new xref section is a table or a stream, whichever the original uses.
+/// Objects are encrypted with the original key if the file was encrypted.
+/// Changed streams whose data was left out (low-memory mode) get it from `lazy`.
+pub fn save_incremental<W: Write>(
+    original: &Document,
+    source: &[u8],
+    doc: &Document,
+    security: Option<&Security>,
+    lazy: Option<&LazySource>,
+    target: &mut W,
+) -> io::Result<Update> {
+    let mut update = Update::default();
+    let mut body = Vec::new();
+    let base = if source.ends_with(b"\n") { source.len() } else { source.len() + 1 };
+    let mut entries: Vec<(u32, Entry)> = Vec::new();
+
+    for (&id, object) in &doc.objects {
+        if original.objects.get(&id) == Some(object) || is_structural(object) {
+            continue;
+        }
+        entries.push((id.0, Entry::Offset(base + body.len(), id.1)));
+        let object = match lazy {
+            Some(lazy) => lazy.complete(doc, id, object).map_err(io::Error::other)?,
+            None => Cow::Borrowed(object),
+        };
+        match security {
+            Some(security) => {
+                let mut object = object.into_owned();
+                security.encrypt_object(id, &mut object);
+                objstm::write_indirect(&mut body, id, &object)?;
+            }
+            None => objstm::write_indirect(&mut body, id, &object)?,
+        }
+        update.written += 1;
+    }
+
+    // Object streams and xref streams of the original revision are still in use
+    // even though nothing in the document references them
+    let mut freed: Vec<ObjectId> = original
+        .objects
+        .iter()
+        .filter(|(id, object)| !doc.objects.contains_key(id) && !is_structural(object))
+        .map(|(id, _)| *id)
+        .collect();
+    freed.sort();
+    update.freed = freed.len();
+    if !freed.is_empty() {
+        // Free list: 0 -> first freed -> ... -> last freed -> 0
+        entries.push((0, Entry::Free(freed[0].0, u16::MAX)));
+        for (i, id) in freed.iter().enumerate() {
+            let next = freed.get(i + 1).map_or(0, |n| n.0);
+            entries.push((id.0, Entry::Free(next, id.1.saturating_add(1))));
+        }
+    }
+
+    // An xref section without entries is invalid, and there's nothing to add anyway
+    if entries.is_empty() {
+        return target.write_all(source).map(|_| update);
+    }
+
+    let mut trailer = Dictionary::new();
+    for key in [&b"Root"[..], b"Info", b"ID"] {
+        if let Ok(value) = doc.trailer.get(key) {
+            trailer.set(key, value.clone());
+        }
+    }
+    if let Some(security) = security {
+        trailer.set("Encrypt", security.original_entry());
+    }
+    trailer.set("Prev", original.xref_start as i64);
+    let max_id = original.max_id.max(doc.max_id);
+
+    let xref_offset = base + body.len();
+    match original.reference_table.cross_reference_type {
+        XrefType::CrossReferenceStream => {
+            let xref_id = max_id + 1;
+            entries.push((xref_id, Entry::Offset(xref_offset, 0)));
+            let (rows, index, widths) = objstm::xref_stream_rows(&mut entries);
+            trailer.set("Type", "XRef");
+            trailer.set("Size", xref_id as i64 + 1);
+            trailer.set("W", widths);
+            trailer.set("Index", index);
+            trailer.set("Filter", "FlateDecode");
+            objstm::write_stream_object(&mut body, (xref_id, 0), trailer, &objstm::deflate(&rows)?)?;
+        }
+        XrefType::CrossReferenceTable => {
+            objstm::write_xref_table(&mut body, &mut entries)?;
+            trailer.set("Size", max_id as i64 + 1);
+            body.extend_from_slice(b"trailer\n");
+            objstm::write_object(&mut body, &Object::Dictionary(trailer))?;
+            body.push(b'\n');
+        }
+    }
+    write!(body, "startxref\n{}\n%%EOF\n", xref_offset)?;
+
+    target.write_all(source)?;
+    if base > source.len() {
+        target.write_all(b"\n")?;
+    }
+    target.write_all(&body)?;
+    Ok(update)
+}
+
+/// Object streams and xref streams are part of the file structure, not content.
+fn is_structural(object: &Object) -> bool {
+    object.as_stream().is_ok_and(|s| s.dict.type_is(b"ObjStm") || s.dict.type_is(b"XRef"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Stream, dictionary};
+
+    #[test]
+    fn appends_changed_objects_after_the_original_bytes() {
+        let mut doc = Document::with_version("1.4");
+        let pages_id = doc.new_object_id();
+        let image = doc.add_object(Stream::new(dictionary! { "Subtype" => "Image" }, vec![1; 64]));
+        let unused = doc.add_object(dictionary! { "Unused" => true });
+        let page = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Resources" => dictionary! { "XObject" => dictionary! { "Im0" => image } } });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        let mut source = Vec::new();
+        doc.save_to(&mut source).unwrap();
+
+        let original = Document::load_mem(&source).unwrap();
+        let mut changed = original.clone();
+        changed.objects.insert(image, Object::Stream(Stream::new(dictionary! { "Subtype" => "Image" }, vec![2; 8])));
+        changed.objects.remove(&unused);
+
+        let mut buffer = Vec::new();
+        let update = save_incremental(&original, &source, &changed, None, None, &mut buffer).unwrap();
+        assert_eq!((update.written, update.freed), (1, 1));
+        assert!(buffer.starts_with(&source));
+
+        let reloaded = Document::load_mem(&buffer).unwrap();
+        assert_eq!(reloaded.get_object(image).unwrap().as_stream().unwrap().content, vec![2; 8]);
+        assert_eq!(reloaded.get_pages().len(), 1);
+    }
+}
@@ -0,0 +1,161 @@
+use mozjpeg::{ColorSpace, ColorSpaceExt, Decompress};
+use std::panic::{self, AssertUnwindSafe};
+
+/// Pixels of a DCTDecode image, one byte per sample in the colour space the PDF
+/// declares (gray, RGB or CMYK as stored), with the JPEG's own YCbCr/YCCK
+/// encoding already undone.
+pub struct JpegSamples {
+    pub samples: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub components: u32,
+}
+
+/// The transform flag of an Adobe APP14 segment: 0 = stored as is (RGB or CMYK),
+/// 1 = YCbCr, 2 = YCCK. `None` if there is no such segment.
+pub fn adobe_transform(data: &[u8]) -> Option<u8> {
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        // APP segments all come before the scan data
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let segment = data.get(pos + 4..pos + 2 + len)?;
+        // "Adobe", version, flags0, flags1, transform
+        if marker == 0xEE && segment.len() >= 12 && segment.starts_with(b"Adobe") {
+            return Some(segment[11]);
+        }
+        pos += 2 + len;
+    }
+    None
+}
+
+/// Decodes a JPEG the way a PDF reader has to. libjpeg guesses the colour
+/// transform from JFIF markers and component ids, PDF decides it from the Adobe
+/// marker alone and otherwise transforms three components but not four.
+/// So the channels are read back untouched and converted here.
+///
+/// Adobe CMYK JPEGs store inverted samples; that is left to the image's
+/// `/Decode` array, as in every PDF reader.
+pub fn decode(data: &[u8]) -> Result<JpegSamples, String> {
+    // libjpeg reports fatal errors by unwinding
+    let decoded = panic::catch_unwind(AssertUnwindSafe(|| -> std::io::Result<(Vec<u8>, u32, u32, ColorSpace)> {
+        let decompress = Decompress::new_mem(data)?;
+        let stored = decompress.color_space();
+        if stored == ColorSpace::JCS_UNKNOWN {
+            return Ok((Vec::new(), 0, 0, stored));
+        }
+        let mut started = decompress.to_colorspace(stored)?;
+        let samples = started.read_scanlines::<u8>()?;
+        let (width, height) = (started.width() as u32, started.height() as u32);
+        started.finish()?;
+        Ok((samples, width, height, stored))
+    }));
+    let (mut samples, width, height, stored) = match decoded {
+        Ok(result) => result.map_err(|e| format!("JPEG: {}", e))?,
+        Err(panic) => return Err(panic.downcast::<String>().map_or_else(|_| "JPEG decoder failed".to_string(), |msg| *msg)),
+    };
+
+    let components = stored.num_components() as u32;
+    // Without an Adobe marker three components are YCbCr and four are plain CMYK
+    let transform = adobe_transform(data).map_or(components == 3, |flag| flag != 0);
+    match (components, transform) {
+        (1, _) | (3, false) | (4, false) => {}
+        (3, true) => ycc_to_rgb(&mut samples, 3),
+        (4, true) => {
+            // YCCK: the first three channels are inverted CMY after the YCbCr step
+            ycc_to_rgb(&mut samples, 4);
+            for pixel in samples.chunks_exact_mut(4) {
+                for v in &mut pixel[..3] {
+                    *v = 255 - *v;
+                }
+            }
+        }
+        _ => return Err(format!("JPEG in unsupported colour space {:?}", stored)),
+    }
+
+    Ok(JpegSamples { samples, width, height, components })
+}
+
+/// JFIF YCbCr to RGB on the first three of every `stride` samples.
+fn ycc_to_rgb(samples: &mut [u8], stride: usize) {
+    for pixel in samples.chunks_exact_mut(stride) {
+        let y = pixel[0] as f32;
+        let cb = pixel[1] as f32 - 128.0;
+        let cr = pixel[2] as f32 - 128.0;
+        pixel[0] = (y + 1.402 * cr).round().clamp(0.0, 255.0) as u8;
+        pixel[1] = (y - 0.344_136 * cb - 0.714_136 * cr).round().clamp(0.0, 255.0) as u8;
+        pixel[2] = (y + 1.772 * cb).round().clamp(0.0, 255.0) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mozjpeg::Compress;
+
+    fn encode(pixels: &[u8], color_space: ColorSpace) -> Vec<u8> {
+        let mut comp = Compress::new(color_space);
+        comp.set_size(8, 8);
+        comp.set_quality(100.0);
+        let mut out = Vec::new();
+        let mut started = comp.start_compress(&mut out).unwrap();
+        started.write_scanlines(pixels).unwrap();
+        started.finish().unwrap();
+        out
+    }
+
+    fn close(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(&x, &y)| x.abs_diff(y) <= 3)
+    }
+
+    #[test]
+    fn rgb_comes_back_as_rgb() {
+        let pixels = [200, 30, 90].repeat(64);
+        let jpeg = decode(&encode(&pixels, ColorSpace::JCS_RGB)).unwrap();
+        assert_eq!((jpeg.width, jpeg.height, jpeg.components), (8, 8, 3));
+        assert!(close(&jpeg.samples, &pixels));
+    }
+
+    #[test]
+    fn cmyk_comes_back_as_stored() {
+        // libjpeg writes CMYK untransformed, with an Adobe marker saying so
+        let pixels = [10, 200, 60, 240].repeat(64);
+        let data = encode(&pixels, ColorSpace::JCS_CMYK);
+        assert_eq!(adobe_transform(&data), Some(0));
+        let jpeg = decode(&data).unwrap();
+        assert_eq!(jpeg.components, 4);
+        assert!(close(&jpeg.samples, &pixels));
+    }
+
+    #[test]
+    fn ycck_is_turned_into_cmyk() {
+        let mut comp = Compress::new(ColorSpace::JCS_CMYK);
+        comp.set_color_space(ColorSpace::JCS_YCCK);
+        comp.set_size(8, 8);
+        comp.set_quality(100.0);
+        let mut data = Vec::new();
+        let mut started = comp.start_compress(&mut data).unwrap();
+        let pixels = [10, 200, 60, 240].repeat(64);
+        started.write_scanlines(&pixels).unwrap();
+        started.finish().unwrap();
+
+        assert_eq!(adobe_transform(&data), Some(2));
+        assert!(close(&decode(&data).unwrap().samples, &pixels));
+    }
+
+    #[test]
+    fn garbage_is_an_error_not_a_panic() {
+        assert!(decode(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 2, 0xFF, 0xDA, 1, 2, 3]).is_err());
+        assert_eq!(adobe_transform(&[0xFF, 0xD8, 0xFF, 0xEE, 0, 200]), None);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::crypt::Security;
+use crate::error::Error;
+use lopdf::{Document, Object, ObjectId, Reader, Stream};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashSet};
+
+/// Image streams at least this big stay in the file until they are needed.
+/// Smaller ones cost less memory than reading them back would cost time.
+pub const LEAVE_OUT_MIN: usize = 64 * 1024;
+
+/// Load filter for low-memory mode: every object is parsed on its own, so dropping
+/// the data of big images here means they are never all in memory at once. Only
+/// the image dictionary stays in the document.
+pub fn leave_out_images(id: ObjectId, object: &mut Object) -> Option<(ObjectId, Object)> {
+    leave_out(object);
+    // Same contract as the decryption filter: top-level objects are kept as
+    // edited in place, the returned copy only matters inside object streams
+    match object {
+        Object::Stream(_) => Some((id, Object::Null)),
+        _ => Some((id, object.clone())),
+    }
+}
+
+/// lopdf refills empty streams from the file after loading, but only those
+/// with a `start_position`, which streams with a readable `/Length` never have.
+pub fn leave_out(object: &mut Object) {
+    if let Object::Stream(stream) = object
+        && is_image(stream)
+        && stream.content.len() >= LEAVE_OUT_MIN
+        && stream.start_position.is_none()
+    {
+        stream.content = Vec::new();
+    }
+}
+
+/// Size of a stream as stored in the file, also for one whose data was left out.
+pub fn stored_len(doc: &Document, stream: &Stream) -> usize {
+    if !stream.content.is_empty() {
+        return stream.content.len();
+    }
+    stream
+        .dict
+        .get(b"Length")
+        .and_then(|l| doc.dereference(l))
+        .and_then(|(_, l)| l.as_i64())
+        .map_or(0, |l| l.max(0) as usize)
+}
+
+fn is_image(stream: &Stream) -> bool {
+    stream.dict.get(b"Subtype").and_then(|s| s.as_name()).is_ok_and(|s| s == b"Image")
+}
+
+/// The original file behind a document loaded with [`leave_out_images`], to
+/// read the left-out image data back one stream at a time. Streams keep their
+/// dictionary as it was in the file, so the data can be put back and taken out
+/// again without the document noticing.
+pub struct LazySource<'a> {
+    reader: Reader<'a>,
+    security: Option<Security>,
+    left_out: BTreeSet<ObjectId>,
+}
+
+impl<'a> LazySource<'a> {
+    /// `security` is what the file was encrypted with, even if it is saved without.
+    pub fn new(pdf: &'a [u8], doc: &Document, security: Option<&Security>) -> Self {
+        let left_out = doc
+            .objects
+            .iter()
+            .filter(|(_, object)| {
+                object.as_stream().is_ok_and(|s| is_image(s) && s.content.is_empty() && stored_len(doc, s) > 0)
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        // The reader only needs the cross-reference table to find objects
+        let mut index = Document::new();
+        index.reference_table = doc.reference_table.clone();
+        LazySource { reader: Reader { buffer: pdf, document: index }, security: security.cloned(), left_out }
+    }
+
+    /// Whether the data of `id` is currently only in the file. Replaced images
+    /// have new data in the document and are written from there.
+    pub fn is_left_out(&self, id: ObjectId, object: &Object) -> bool {
+        self.left_out.contains(&id) && object.as_stream().is_ok_and(|s| s.content.is_empty())
+    }
+
+    pub fn count(&self) -> usize {
+        self.left_out.len()
+    }
+
+    /// Reads the stream data of `id` from the file, decrypted but still encoded.
+    pub fn fetch(&self, doc: &Document, id: ObjectId) -> Result<Vec<u8>, Error> {
+        let mut object = self.reader.get_object(id, &mut HashSet::new())?;
+        let stream = object.as_stream_mut()?;
+
+        // A /Length stored in an object stream can't be resolved by the reader
+        // alone, lopdf then only records where the data starts
+        if stream.content.is_empty()
+            && let Some(start) = stream.start_position
+        {
+            let len = doc.get_object(id).and_then(|o| o.as_stream()).map(|s| stored_len(doc, s))?;
+            let data = self.reader.buffer.get(start..start + len).ok_or(lopdf::Error::Offset(start + len))?;
+            stream.content = data.to_vec();
+        }
+
+        if let Some(security) = &self.security {
+            security.decrypt_object(id, &mut object)?;
+        }
+        match object {
+            Object::Stream(stream) => Ok(stream.content),
+            _ => unreachable!("checked above"),
+        }
+    }
+
+    /// `object` the way it has to be written: with its data read back from the
+    /// file if it was left out.
+    pub fn complete<'o>(&self, doc: &Document, id: ObjectId, object: &'o Object) -> Result<Cow<'o, Object>, Error> {
+        if !self.is_left_out(id, object) {
+            return Ok(Cow::Borrowed(object));
+        }
+        let mut stream = object.as_stream()?.clone();
+        stream.content = self.fetch(doc, id)?;
+        Ok(Cow::Owned(Object::Stream(stream)))
+    }
+
+    /// Runs `read` with the data of image `id` and its soft mask back in the
+    /// document, and leaves them out again afterwards.
+    pub fn with_data<T>(&self, doc: &mut Document, id: ObjectId, read: impl FnOnce(&Document) -> T) -> Result<T, Error> {
+        let smask = doc
+            .get_object(id)
+            .and_then(|o| o.as_stream())
+            .and_then(|s| s.dict.get(b"SMask"))
+            .and_then(|m| m.as_reference())
+            .ok();
+        let mut restored = Vec::new();
+        for id in std::iter::once(id).chain(smask) {
+            if doc.get_object(id).is_ok_and(|o| self.is_left_out(id, o)) {
+                restored.push((id, self.fetch(doc, id)?));
+            }
+        }
+
+        for (id, data) in &mut restored {
+            if let Ok(stream) = doc.get_object_mut(*id).and_then(|o| o.as_stream_mut()) {
+                stream.content = std::mem::take(data);
+            }
+        }
+        let result = read(doc);
+        for (id, _) in restored {
+            if let Ok(stream) = doc.get_object_mut(id).and_then(|o| o.as_stream_mut()) {
+                stream.content = Vec::new();
+            }
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt;
+    use lopdf::dictionary;
+
+    #[test]
+    fn leaves_big_images_in_the_file_and_reads_them_back() {
+        let big: Vec<u8> = (0..LEAVE_OUT_MIN as u32 + 10).map(|i| (i % 251) as u8).collect();
+        let mut doc = Document::with_version("1.4");
+        let image = doc.add_object(Stream::new(dictionary! { "Subtype" => "Image", "Width" => 10 }, big.clone()));
+        let small = doc.add_object(Stream::new(dictionary! { "Subtype" => "Image" }, vec![7; 100]));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Images" => vec![image.into(), small.into()] });
+        doc.trailer.set("Root", catalog);
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+
+        let (mut loaded, security) = crypt::load(&pdf, None, true).unwrap();
+        let source = LazySource::new(&pdf, &loaded, security.as_ref());
+        assert_eq!(source.count(), 1);
+        assert!(source.is_left_out(image, loaded.get_object(image).unwrap()));
+        assert_eq!(loaded.get_object(small).unwrap().as_stream().unwrap().content, vec![7; 100]);
+        assert_eq!(stored_len(&loaded, loaded.get_object(image).unwrap().as_stream().unwrap()), big.len());
+
+        let seen = source.with_data(&mut loaded, image, |doc| doc.get_object(image).unwrap().as_stream().unwrap().content.clone());
+        assert_eq!(seen.unwrap(), big);
+        assert!(source.is_left_out(image, loaded.get_object(image).unwrap()));
+    }
+}
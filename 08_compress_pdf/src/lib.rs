@@ -0,0 +1,53 @@
+//! Recompresses the images inside PDF files with mozjpeg (or losslessly where
+//! JPEG would hurt), plus a few document-level size passes.
+//!
+//! ```no_run
+//! use compress_pdf::PdfCompressor;
+//!
+//! let compressor = PdfCompressor::new().max_width(1600).quality(70.0);
+//! let summary = compressor.compress_file("in.pdf".as_ref(), "out.pdf".as_ref())?;
+//! println!("{} of {} images optimized", summary.optimized, summary.found);
+//! # Ok::<(), compress_pdf::Error>(())
+//! ```
+
+mod analyze;
+mod colorspace;
+mod compressor;
+mod crypt;
+mod decode;
+mod dedup;
+mod error;
+mod extract;
+mod filters;
+mod icc;
+mod incremental;
+mod jpeg;
+mod lazy;
+mod lossless;
+mod mask;
+mod mono;
+mod objstm;
+mod optimize;
+mod pdfa;
+mod pipeline;
+mod placement;
+pub mod report;
+mod select;
+mod signature;
+mod target;
+mod verify;
+mod watch;
+
+pub use analyze::{Analysis, Analyzer, Estimate, ImageGroup, PageUsage, Usage};
+pub use compressor::{PdfCompressor, Summary};
+pub use dedup::Dedup;
+pub use error::{Error, ImageError};
+pub use extract::{ExtractedImage, ImageExtractor, Manifest};
+pub use icc::CmykProfile;
+pub use lossless::LosslessMode;
+pub use mono::Bilevel;
+pub use optimize::DocPasses;
+pub use pdfa::PdfaReport;
+pub use select::Selection;
+pub use verify::{Quality, Thresholds};
+pub use watch::{FolderWatcher, WatchEvent, WatchOutcome, WatchStats};
@@ -0,0 +1,281 @@
+use flate2::{Compression, write::ZlibEncoder};
+use image::{DynamicImage, RgbImage};
+use lopdf::{Dictionary, Object};
+use std::collections::HashMap;
+use std::io::Write;
+
+/// When images are re-encoded losslessly (Flate/palette) instead of as JPEG.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LosslessMode {
+    /// Line art and screenshots get the smaller of lossless and high-quality JPEG
+    #[default]
+    Auto,
+    /// Never produce JPEG
+    Always,
+    /// Always JPEG
+    Never,
+}
+
+/// What kind of picture we're looking at, decides whether JPEG is acceptable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageClass {
+    /// Photos and scans: smooth gradients, JPEG is fine
+    Photo,
+    /// Screenshots, diagrams, line art: few colours and hard edges, JPEG rings
+    LineArt,
+}
+
+/// Above this many distinct colours an image can't be palette-reduced.
+const MAX_PALETTE: usize = 256;
+
+/// Share of neighbouring pixels that are either identical or a hard edge
+/// above which we call an image line art.
+const LINE_ART_FLAT_OR_SHARP: f64 = 0.92;
+
+/// An image as 8-bit RGB with its colours counted, converted once and shared
+/// by [`classify`] and [`encode_lossless`].
+pub struct RgbPixels {
+    rgb: RgbImage,
+    /// Distinct colours, counted up to `MAX_PALETTE + 1`
+    colors: usize,
+    /// False for gray source images, whose R, G and B are all the same
+    has_color: bool,
+}
+
+impl RgbPixels {
+    pub fn new(img: &DynamicImage) -> Self {
+        let rgb = img.to_rgb8();
+        let colors = count_colors(&rgb, MAX_PALETTE + 1);
+        RgbPixels { rgb, colors, has_color: img.color().has_color() }
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.rgb.dimensions()
+    }
+}
+
+pub fn classify(pixels: &RgbPixels) -> ImageClass {
+    if pixels.colors <= MAX_PALETTE {
+        return ImageClass::LineArt;
+    }
+
+    // Horizontal neighbour differences: photos are full of small steps,
+    // drawings are either flat or jump hard.
+    let (mut flat_or_sharp, mut total) = (0u64, 0u64);
+    for row in pixels.rgb.rows() {
+        let pixels: Vec<_> = row.collect();
+        for pair in pixels.windows(2) {
+            let diff: i32 = (0..3).map(|c| (pair[0][c] as i32 - pair[1][c] as i32).abs()).max().unwrap_or(0);
+            if diff == 0 || diff > 64 {
+                flat_or_sharp += 1;
+            }
+            total += 1;
+        }
+    }
+
+    if total > 0 && flat_or_sharp as f64 / total as f64 >= LINE_ART_FLAT_OR_SHARP {
+        ImageClass::LineArt
+    } else {
+        ImageClass::Photo
+    }
+}
+
+/// Counts distinct colours, giving up once `limit` is reached.
+fn count_colors(rgb: &RgbImage, limit: usize) -> usize {
+    let mut seen = HashMap::new();
+    for p in rgb.pixels() {
+        seen.insert(p.0, ());
+        if seen.len() >= limit {
+            break;
+        }
+    }
+    seen.len()
+}
+
+/// Lossless Flate encoding of an image, with everything needed to describe it in the PDF.
+pub struct LosslessImage {
+    pub data: Vec<u8>,
+    pub color_space: Object,
+    pub bpc: u8,
+    pub decode_parms: Option<Dictionary>,
+}
+
+/// Picks palette reduction when the colours fit, otherwise Flate with PNG predictors.
+pub fn encode_lossless(pixels: &RgbPixels) -> Result<LosslessImage, String> {
+    // A palette only beats plain gray when it lets us drop below 8 bits
+    if pixels.colors <= MAX_PALETTE && (pixels.has_color || pixels.colors <= 16) {
+        return encode_palette(&pixels.rgb);
+    }
+
+    let (w, _) = pixels.dimensions();
+    let gray: Vec<u8>;
+    let (samples, channels, color_space) = if pixels.has_color {
+        (pixels.rgb.as_raw(), 3, "DeviceRGB")
+    } else {
+        gray = pixels.rgb.pixels().map(|p| p.0[0]).collect();
+        (&gray, 1, "DeviceGray")
+    };
+
+    let filtered = png_filter(samples, w as usize * channels, channels);
+    let mut parms = Dictionary::new();
+    parms.set("Predictor", 15);
+    parms.set("Colors", channels as i64);
+    parms.set("BitsPerComponent", 8);
+    parms.set("Columns", w as i64);
+
+    Ok(LosslessImage {
+        data: deflate(&filtered)?,
+        color_space: Object::Name(color_space.as_bytes().to_vec()),
+        bpc: 8,
+        decode_parms: Some(parms),
+    })
+}
+
+fn encode_palette(rgb: &RgbImage) -> Result<LosslessImage, String> {
+    let (w, h) = rgb.dimensions();
+
+    // Palette in order of first appearance, so the output is deterministic
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let mut index_of: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut indices = Vec::with_capacity((w * h) as usize);
+    for p in rgb.pixels() {
+        let idx = *index_of.entry(p.0).or_insert_with(|| {
+            palette.push(p.0);
+            (palette.len() - 1) as u8
+        });
+        indices.push(idx);
+    }
+
+    let bpc: u8 = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let packed = pack_indices(&indices, w as usize, bpc);
+
+    let lookup: Vec<u8> = palette.iter().flatten().copied().collect();
+    let color_space = Object::Array(vec![
+        Object::Name(b"Indexed".to_vec()),
+        Object::Name(b"DeviceRGB".to_vec()),
+        Object::Integer(palette.len() as i64 - 1),
+        Object::String(lookup, lopdf::StringFormat::Hexadecimal),
+    ]);
+
+    Ok(LosslessImage { data: deflate(&packed)?, color_space, bpc, decode_parms: None })
+}
+
+/// Packs one index per byte into `bpc`-bit samples, padding every row to a full byte.
+pub(crate) fn pack_indices(indices: &[u8], width: usize, bpc: u8) -> Vec<u8> {
+    if bpc == 8 {
+        return indices.to_vec();
+    }
+    let per_byte = 8 / bpc as usize;
+    let row_bytes = width.div_ceil(per_byte);
+    let mut out = Vec::with_capacity(row_bytes * indices.len() / width.max(1));
+    for row in indices.chunks(width) {
+        let mut packed = vec![0u8; row_bytes];
+        for (i, &idx) in row.iter().enumerate() {
+            let shift = 8 - bpc as usize * (i % per_byte + 1);
+            packed[i / per_byte] |= idx << shift;
+        }
+        out.extend_from_slice(&packed);
+    }
+    out
+}
+
+/// PNG row filtering (PDF `/Predictor 15`): every row gets the filter type that
+/// gives the smallest sum of absolute residuals, prefixed as one byte.
+fn png_filter(pixels: &[u8], row_bytes: usize, bpp: usize) -> Vec<u8> {
+    let rows = pixels.len() / row_bytes;
+    let mut out = Vec::with_capacity(pixels.len() + rows);
+    let zero_row = vec![0u8; row_bytes];
+    let mut candidate = vec![0u8; row_bytes];
+    let mut best = vec![0u8; row_bytes];
+
+    for r in 0..rows {
+        let row = &pixels[r * row_bytes..(r + 1) * row_bytes];
+        let up = if r == 0 { &zero_row[..] } else { &pixels[(r - 1) * row_bytes..r * row_bytes] };
+
+        let mut best_type = 0u8;
+        let mut best_score = u64::MAX;
+        for filter_type in 0u8..=4 {
+            for i in 0..row_bytes {
+                let a = if i >= bpp { row[i - bpp] } else { 0 };
+                let b = up[i];
+                let c = if i >= bpp { up[i - bpp] } else { 0 };
+                let predicted = match filter_type {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((a as u16 + b as u16) / 2) as u8,
+                    _ => paeth(a, b, c),
+                };
+                candidate[i] = row[i].wrapping_sub(predicted);
+            }
+            let score: u64 = candidate.iter().map(|&v| (v as i8).unsigned_abs() as u64).sum();
+            if score < best_score {
+                best_score = score;
+                best_type = filter_type;
+                best.copy_from_slice(&candidate);
+            }
+        }
+
+        out.push(best_type);
+        out.extend_from_slice(&best);
+    }
+    out
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+pub(crate) fn deflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    #[test]
+    fn few_colours_is_line_art() {
+        let img = ImageBuffer::from_fn(64, 64, |x, _| if x < 32 { Rgb([255, 0, 0]) } else { Rgb([0, 0, 255]) });
+        assert_eq!(classify(&RgbPixels::new(&DynamicImage::ImageRgb8(img))), ImageClass::LineArt);
+    }
+
+    #[test]
+    fn gradient_noise_is_photo() {
+        let img = ImageBuffer::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, ((x * y) % 251) as u8]));
+        assert_eq!(classify(&RgbPixels::new(&DynamicImage::ImageRgb8(img))), ImageClass::Photo);
+    }
+
+    #[test]
+    fn palette_packs_to_two_bits() {
+        let img = ImageBuffer::from_fn(5, 2, |x, _| Rgb([x as u8 % 3, 0, 0]));
+        let out = encode_palette(&img).unwrap();
+        assert_eq!(out.bpc, 2);
+        assert_eq!(pack_indices(&[0, 1, 2, 0, 1], 5, 2), vec![0b00_01_10_00, 0b01_000000]);
+    }
+
+    #[test]
+    fn png_filter_prefixes_each_row() {
+        let pixels = [10u8, 20, 30, 10, 20, 30];
+        let out = png_filter(&pixels, 3, 1);
+        assert_eq!(out.len(), 8);
+        // Second row equals the first, "Up" leaves only zeros
+        assert_eq!(&out[4..], &[2, 0, 0, 0]);
+    }
+}
@@ -1,227 +1,513 @@
-use lopdf::{Document, Object, Stream};
-use image::{DynamicImage, ImageBuffer, imageops::FilterType, GenericImageView};
-use mozjpeg::{Compress, ColorSpace};
-use std::collections::HashSet;
-use std::io::Read; 
-use flate2::read::ZlibDecoder; 
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let input = "input.pdf";
-    let output = "output_compressed.pdf";
-    
-    let max_width = 1200;
-    let jpeg_quality = 60.0;
-
-    println!("📄 Loading PDF: {}", input);
-    let mut doc = Document::load(input)?;
-
-    let mut image_ids = HashSet::new();
-    for (id, obj) in doc.objects.iter() {
-        if is_image_xobject(obj) {
-            image_ids.insert(*id);
-        }
-    }
-    println!("🔍 Found {} images inside PDF", image_ids.len());
-
-    let mut success_count = 0;
-    let mut fail_count = 0;
-    let keys: Vec<_> = image_ids.into_iter().collect();
-
-    for object_id in keys {
-        let filter_name = doc.get_object(object_id)
-            .and_then(|o| o.as_stream())
-            .map(|s| {
-                s.dict.get(b"Filter").ok()
-                 .and_then(|o| o.as_name_str().ok())
-                 .unwrap_or("Unknown")
-                 .to_string()
-            }).unwrap_or("Error".to_string());
-
-        let (raw_data, width, height, colorspace, bpc) = {
-            let obj = doc.get_object(object_id)?;
-            let stream = match obj.as_stream() {
-                Ok(s) => s,
-                Err(_) => continue,
-            };
-
-            let width = stream.dict.get(b"Width").ok().and_then(|v| v.as_i64().ok()).unwrap_or(0) as u32;
-            let height = stream.dict.get(b"Height").ok().and_then(|v| v.as_i64().ok()).unwrap_or(0) as u32;
-            let bpc = stream.dict.get(b"BitsPerComponent").ok().and_then(|v| v.as_i64().ok()).unwrap_or(8) as u32;
-
-            let cs = stream.dict.get(b"ColorSpace").ok()
-                .and_then(|o| match o {
-                    Object::Name(n) => std::str::from_utf8(n).ok(),
-                    Object::Array(arr) => arr.get(0).and_then(|x| x.as_name_str().ok()),
-                    _ => None
-                }).unwrap_or("DeviceRGB").to_string();
-
-            let data_res: Result<Vec<u8>, String> = if filter_name.contains("DCTDecode") {
-                Ok(stream.content.clone())
-            } else {
-                match stream.decompressed_content() {
-                    Ok(d) => Ok(d),
-                    Err(_) => {
-                        if filter_name.contains("FlateDecode") {
-                            let mut decoder = ZlibDecoder::new(&stream.content[..]);
-                            let mut buffer = Vec::new();
-                            match decoder.read_to_end(&mut buffer) {
-                                Ok(_) => Ok(buffer),
-                                Err(e) => Err(format!("Manual Zlib Failed: {}", e))
-                            }
-                        } else {
-                            Err("Unsupported Filter / Decode Failed".to_string())
-                        }
-                    }
+mod cli;
+
+use clap::Parser;
+use cli::{AnalyzeArgs, Cli, Command, ExtractArgs};
+use compress_pdf::report::{Action, FileReport, Report};
+use compress_pdf::{Analysis, Analyzer, Bilevel, Error, FolderWatcher, ImageExtractor, PdfCompressor, Summary, Thresholds};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+const EXIT_PARTIAL: u8 = 1;
+const EXIT_IO: u8 = 3;
+const EXIT_REFUSED: u8 = 4;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let started = Instant::now();
+
+    if let Err(e) = rayon::ThreadPoolBuilder::new().num_threads(cli.jobs).build_global() {
+        eprintln!("❌ Cannot start worker pool: {}", e);
+        return ExitCode::from(EXIT_IO);
+    }
+    match &cli.command {
+        Some(Command::Extract(args)) => return extract(args),
+        Some(Command::Analyze(args)) => return analyze(args),
+        None => {}
+    }
+
+    // --revert-flagged implies --verify
+    let verifying = cli.verify || cli.revert_flagged;
+    let thresholds = Thresholds { min_ssim: cli.min_ssim, min_psnr: cli.min_psnr };
+    let compressor = PdfCompressor::new()
+        .max_width(cli.max_width)
+        .quality(cli.quality)
+        .target_dpi(cli.target_dpi)
+        .target_size(cli.target_size)
+        .keep_cmyk(cli.keep_cmyk)
+        .cmyk_profile(cli.cmyk_profile.clone())
+        .lossless(cli.lossless.into())
+        .grayscale(cli.grayscale)
+        .bilevel(cli.bilevel.map(Bilevel::from))
+        .dedup(!cli.no_dedup)
+        .passes(cli::doc_passes(&cli.passes))
+        .password(cli.password.clone())
+        .decrypt(cli.decrypt)
+        .break_signatures(cli.break_signatures)
+        .incremental(cli.incremental)
+        .verify(verifying.then_some(thresholds))
+        .revert_flagged(cli.revert_flagged)
+        .memory_limit(cli.memory_limit)
+        .selection(cli::selection(&cli))
+        .pdfa(cli.pdfa)
+        .verbose(true);
+    if cli.watch {
+        return watch(&cli, compressor);
+    }
+
+    let (files, batch) = match resolve_inputs(&cli.inputs) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            return ExitCode::from(EXIT_IO);
+        }
+    };
+    if files.is_empty() {
+        eprintln!("❌ No PDF files matched {:?}", cli.inputs);
+        return ExitCode::from(EXIT_IO);
+    }
+
+    if batch
+        && let Some(dir) = &cli.output
+        && let Err(e) = std::fs::create_dir_all(dir)
+    {
+        eprintln!("❌ Cannot create output directory {}: {}", dir.display(), e);
+        return ExitCode::from(EXIT_IO);
+    }
+    if let Some(collision) = find_collision(&files, |input| output_path(input, cli.output.as_deref(), batch)) {
+        eprintln!("❌ {}; compress them in separate runs", collision);
+        return ExitCode::from(EXIT_IO);
+    }
+
+    let mut exit = 0;
+    let mut total = Summary::default();
+    let mut io_failures = 0;
+    let mut refused = 0;
+    let mut report = Report::default();
+
+    for input in &files {
+        let output = output_path(input, cli.output.as_deref(), batch);
+        let file_started = Instant::now();
+        let result = compress_file(&compressor, input, &output, cli.dry_run, verifying);
+        if cli.report.is_some() {
+            report.add(file_report(input, &output, &result, file_started, cli.dry_run));
+        }
+        match result {
+            Ok(summary) => {
+                if batch {
+                    println!(
+                        "📦 {}: {} images, optimized {}, failed {}, deduplicated {} ({}kb), {}kb -> {}kb",
+                        input.display(), summary.found, summary.optimized, summary.failed,
+                        summary.dedup.removed, summary.dedup.bytes_saved / 1024,
+                        summary.input_size / 1024, summary.output_size / 1024
+                    );
                 }
-            };
-
-            let data = match data_res {
-                Ok(d) => d,
-                Err(e) => {
-                    if !e.contains("Unsupported") {
-                         println!("   ❌ Failed extraction Img {}: {}", object_id.0, e);
-                    }
-                    fail_count += 1;
-                    continue; 
+                let pdfa_violations = summary.pdfa.as_ref().is_some_and(|p| !p.violations.is_empty());
+                if summary.failed > 0 || summary.flagged > 0 || summary.target_missed || pdfa_violations {
+                    exit = exit.max(EXIT_PARTIAL);
                 }
-            };
-            
-            (data, width, height, cs, bpc)
-        };
-
-        if width == 0 || height == 0 {
-           continue;
-        }
-
-        println!("➡️ Processing Img {} ({})", object_id.0, filter_name);
-
-        let img_result = decode_pdf_image(&raw_data, width, height, &colorspace, bpc);
-
-        match img_result {
-            Ok(dynamic_img) => {
-                match compress_image_logic(dynamic_img, max_width, jpeg_quality) {
-                    Ok((compressed_data, new_w, new_h)) => {
-                        let is_worth_it = compressed_data.len() < raw_data.len();
-                        
-                        // FIX: Simpan size dulu sebelum variable 'compressed_data' dipindahkan (moved)
-                        let new_size = compressed_data.len(); 
-
-                        if is_worth_it || filter_name.contains("FlateDecode") {
-                            if let Ok(obj) = doc.get_object_mut(object_id) {
-                                if let Ok(stream) = obj.as_stream_mut() {
-                                    
-                                    // Di sini ownership compressed_data pindah ke fungsi replace
-                                    replace_stream_with_jpeg(stream, compressed_data, new_w, new_h);
-                                    
-                                    success_count += 1;
-                                    
-                                    // Pake variable 'new_size' yg kita simpan tadi
-                                    println!("   ✨ Optimized: {}kb -> {}kb", raw_data.len()/1024, new_size/1024);
-                                }
-                            }
-                        } else {
-                            println!("   SKIP: Compressed is larger.");
-                        }
-                    },
-                    Err(e) => {
-                        println!("   ❌ Compression Error: {}", e);
-                        fail_count += 1;
-                    }
+                total.found += summary.found;
+                total.optimized += summary.optimized;
+                total.failed += summary.failed;
+                total.dedup.removed += summary.dedup.removed;
+                total.dedup.bytes_saved += summary.dedup.bytes_saved;
+                total.input_size += summary.input_size;
+                total.output_size += summary.output_size;
+            }
+            Err(e @ Error::Signed(_)) => {
+                eprintln!("⛔ {}: {}; use --incremental to keep the signatures or --break-signatures", input.display(), e);
+                refused += 1;
+                exit = exit.max(EXIT_REFUSED);
+            }
+            Err(e) if e.is_refusal() => {
+                eprintln!("⛔ {}: {}", input.display(), e);
+                refused += 1;
+                exit = exit.max(EXIT_REFUSED);
+            }
+            Err(e) => {
+                eprintln!("❌ {}: {}", input.display(), e);
+                io_failures += 1;
+                exit = exit.max(EXIT_IO);
+            }
+        }
+    }
+
+    if batch {
+        println!("================================================");
+        println!(
+            "📚 Batch: {} files ({} unreadable/unwritable, {} refused), optimized {}, failed {}, deduplicated {} ({}kb), {}kb -> {}kb",
+            files.len(), io_failures, refused, total.optimized, total.failed,
+            total.dedup.removed, total.dedup.bytes_saved / 1024,
+            total.input_size / 1024, total.output_size / 1024
+        );
+    }
+
+    if let Some(path) = &cli.report {
+        report.elapsed_ms = started.elapsed().as_millis();
+        match report.write(path) {
+            Ok(()) => println!("📝 Report written to {}", path.display()),
+            Err(e) => {
+                eprintln!("❌ Cannot write {}: {}", path.display(), e);
+                exit = exit.max(EXIT_IO);
+            }
+        }
+    }
+
+    ExitCode::from(exit)
+}
+
+/// `--watch`: compress whatever lands in the inbox until Ctrl-C or SIGTERM.
+fn watch(cli: &Cli, compressor: PdfCompressor) -> ExitCode {
+    let inbox = match cli.inputs.as_slice() {
+        [dir] if Path::new(dir).is_dir() => PathBuf::from(dir),
+        _ => {
+            eprintln!("❌ --watch takes exactly one directory to watch, got {:?}", cli.inputs);
+            return ExitCode::from(EXIT_IO);
+        }
+    };
+    let output = cli.output.clone().unwrap_or_else(|| inbox.join("compressed"));
+    let same_dir = |dir: &Path| dir.canonicalize().ok().is_some_and(|d| inbox.canonicalize().ok() == Some(d));
+    let targets = [Some(&output), cli.archive.as_ref(), cli.quarantine.as_ref()];
+    if targets.into_iter().flatten().any(|dir| same_dir(dir)) {
+        eprintln!("❌ --watch can't write into the directory it watches");
+        return ExitCode::from(EXIT_IO);
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = stop.clone();
+    let handler = ctrlc::set_handler(move || {
+        println!("🛑 Stopping once the current file is done...");
+        handler_stop.store(true, Ordering::SeqCst);
+    });
+    if let Err(e) = handler {
+        eprintln!("❌ Cannot install the signal handler: {}", e);
+        return ExitCode::from(EXIT_IO);
+    }
+
+    let mut watcher = FolderWatcher::new(compressor, &inbox, &output)
+        .retries(cli.retries)
+        .settle(Duration::from_secs_f64(cli.settle))
+        .log(cli.watch_log.clone())
+        .verbose(true);
+    if let Some(dir) = &cli.archive {
+        watcher = watcher.archive(dir);
+    }
+    if let Some(dir) = &cli.quarantine {
+        watcher = watcher.quarantine(dir);
+    }
+
+    match watcher.run(&stop) {
+        Ok(stats) => {
+            println!(
+                "📚 Watched: {} compressed, {} quarantined, {} retries, {}kb -> {}kb",
+                stats.compressed, stats.quarantined, stats.retried, stats.input_size / 1024, stats.output_size / 1024
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("❌ Watching {} failed: {}", inbox.display(), e);
+            ExitCode::from(EXIT_IO)
+        }
+    }
+}
+
+/// The `extract` subcommand: every image of every input into a directory.
+fn extract(args: &ExtractArgs) -> ExitCode {
+    let (files, batch) = match resolve_inputs(&args.inputs) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            return ExitCode::from(EXIT_IO);
+        }
+    };
+    if files.is_empty() {
+        eprintln!("❌ No PDF files matched {:?}", args.inputs);
+        return ExitCode::from(EXIT_IO);
+    }
+
+    if let Some(collision) = find_collision(&files, |input| images_dir(input, args.output.as_deref(), batch)) {
+        eprintln!("❌ {}; extract them in separate runs", collision);
+        return ExitCode::from(EXIT_IO);
+    }
+
+    let extractor = ImageExtractor::new().password(args.password.clone()).verbose(true);
+    let mut exit = 0;
+    for input in &files {
+        let dir = images_dir(input, args.output.as_deref(), batch);
+        println!("📄 Extracting images from {}", input.display());
+        match extractor.extract_file(input, &dir) {
+            Ok(manifest) => {
+                println!("🖼️  {} image(s) written to {}, {} failed", manifest.written(), dir.display(), manifest.failed());
+                if manifest.failed() > 0 {
+                    exit = exit.max(EXIT_PARTIAL);
                 }
-            },
+            }
+            Err(e) if e.is_refusal() => {
+                eprintln!("⛔ {}: {}", input.display(), e);
+                exit = exit.max(EXIT_REFUSED);
+            }
             Err(e) => {
-                println!("   ❌ Decode Pixel Error: {} (CS: {})", e, colorspace);
-                fail_count += 1;
+                eprintln!("❌ {}: {}", input.display(), e);
+                exit = exit.max(EXIT_IO);
             }
         }
     }
+    ExitCode::from(exit)
+}
 
-    doc.prune_objects();
-    doc.save(output)?;
-    
-    println!("------------------------------------------------");
-    println!("✅ Final: Optimized: {}, Failed: {}", success_count, fail_count);
+/// The `analyze` subcommand: a size breakdown per input, nothing is written.
+fn analyze(args: &AnalyzeArgs) -> ExitCode {
+    let (files, _) = match resolve_inputs(&args.inputs) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            return ExitCode::from(EXIT_IO);
+        }
+    };
+    if files.is_empty() {
+        eprintln!("❌ No PDF files matched {:?}", args.inputs);
+        return ExitCode::from(EXIT_IO);
+    }
+
+    let mut analyzer = Analyzer::new().password(args.password.clone());
+    if !args.no_estimate {
+        for &quality in &args.quality {
+            for &max_width in &args.max_width {
+                analyzer = analyzer.estimate(quality, max_width);
+            }
+        }
+    }
 
-    Ok(())
+    let mut exit = 0;
+    let mut results = Vec::new();
+    for input in &files {
+        match analyzer.analyze_file(input) {
+            Ok(analysis) => {
+                print_analysis(input, &analysis);
+                results.push(FileAnalysis { input: input.display().to_string(), analysis });
+            }
+            Err(e) if e.is_refusal() => {
+                eprintln!("⛔ {}: {}", input.display(), e);
+                exit = exit.max(EXIT_REFUSED);
+            }
+            Err(e) => {
+                eprintln!("❌ {}: {}", input.display(), e);
+                exit = exit.max(EXIT_IO);
+            }
+        }
+    }
+
+    if let Some(path) = &args.json {
+        let written = std::fs::File::create(path)
+            .map_err(|e| e.to_string())
+            .and_then(|file| serde_json::to_writer_pretty(std::io::BufWriter::new(file), &results).map_err(|e| e.to_string()));
+        match written {
+            Ok(()) => println!("📝 Analysis written to {}", path.display()),
+            Err(e) => {
+                eprintln!("❌ Cannot write {}: {}", path.display(), e);
+                exit = exit.max(EXIT_IO);
+            }
+        }
+    }
+    ExitCode::from(exit)
 }
 
-fn compress_image_logic(img: DynamicImage, max_width: u32, quality: f32) -> Result<(Vec<u8>, u32, u32), Box<dyn std::error::Error>> {
-    let target_w = if img.width() > max_width { max_width } else { img.width() };
-    let resized_img = img.resize(target_w, u32::MAX, FilterType::Lanczos3);
-    let (w, h) = resized_img.dimensions();
-    let rgb_img = resized_img.to_rgb8();
-
-    let mut comp = Compress::new(ColorSpace::JCS_RGB);
-    comp.set_size(w as usize, h as usize);
-    comp.set_quality(quality);
-    let mut comp_buf = Vec::new();
-    let mut compressor = comp.start_compress(&mut comp_buf)?;
-    compressor.write_scanlines(rgb_img.as_raw())?;
-    compressor.finish()?;
-    Ok((comp_buf, w, h))
+/// One entry of the `--json` file.
+#[derive(serde::Serialize)]
+struct FileAnalysis {
+    input: String,
+    #[serde(flatten)]
+    analysis: Analysis,
 }
 
-// FIX: Tambah underscore _bpc biar ga warning unused variable
-fn decode_pdf_image(data: &[u8], width: u32, height: u32, cs: &str, _bpc: u32) -> Result<DynamicImage, String> {
-    if let Ok(img) = image::load_from_memory(data) {
-        return Ok(img);
-    }
-    
-    let size = (width * height) as usize;
-
-    if cs.contains("DeviceRGB") || cs.contains("RGB") {
-        if data.len() < size * 3 { return Err(format!("Data length mismatch for RGB. Need {}, got {}", size*3, data.len())); }
-        let buf = ImageBuffer::from_raw(width, height, data[..size*3].to_vec()).ok_or("Failed to create RGB buffer")?;
-        return Ok(DynamicImage::ImageRgb8(buf));
-    } 
-    else if cs.contains("DeviceGray") || cs.contains("Gray") {
-        if data.len() < size { return Err(format!("Data length mismatch for Gray. Need {}, got {}", size, data.len())); }
-        let buf = ImageBuffer::from_raw(width, height, data[..size].to_vec()).ok_or("Failed to create Gray buffer")?;
-        return Ok(DynamicImage::ImageLuma8(buf));
-    }
-    else if cs.contains("DeviceCMYK") || cs.contains("CMYK") {
-        if data.len() < size * 4 { return Err("Not enough data for CMYK".into()); }
-        let mut rgb_data = Vec::with_capacity(size * 3);
-        for chunk in data.chunks(4) {
-            if chunk.len() < 4 { break; }
-            let c = chunk[0] as f32 / 255.0;
-            let m = chunk[1] as f32 / 255.0;
-            let y = chunk[2] as f32 / 255.0;
-            let k = chunk[3] as f32 / 255.0;
-            let r = (255.0 * (1.0 - c) * (1.0 - k)) as u8;
-            let g = (255.0 * (1.0 - m) * (1.0 - k)) as u8;
-            let b = (255.0 * (1.0 - y) * (1.0 - k)) as u8;
-            rgb_data.push(r); rgb_data.push(g); rgb_data.push(b);
-        }
-        let buf = ImageBuffer::from_raw(width, height, rgb_data).ok_or("Failed to create RGB buffer from CMYK")?;
-        return Ok(DynamicImage::ImageRgb8(buf));
-    }
-
-    Err(format!("Unsupported Colorspace: {}", cs))
+fn print_analysis(input: &Path, analysis: &Analysis) {
+    let share = |bytes: u64| bytes as f64 * 100.0 / analysis.file_size.max(1) as f64;
+    println!("📊 {}: {}kb, {} objects", input.display(), analysis.file_size / 1024, analysis.objects);
+    for (name, usage) in [
+        ("Images", analysis.images),
+        ("Fonts", analysis.fonts),
+        ("Content streams", analysis.content),
+        ("Metadata", analysis.metadata),
+        ("Unreferenced", analysis.unreferenced),
+        ("Other", analysis.other),
+    ] {
+        println!("   {:<16} {:>6} objects {:>9}kb {:>5.1}%", name, usage.objects, usage.bytes / 1024, share(usage.bytes));
+    }
+
+    if !analysis.image_groups.is_empty() {
+        println!("   🖼️  Images by filter and colour space:");
+        for group in &analysis.image_groups {
+            let kind = format!("{} / {}", group.filter, group.color_space);
+            println!("      {:<44} {:>5} images {:>9}kb {:>5.1}%", kind, group.images, group.bytes / 1024, share(group.bytes));
+        }
+    }
+
+    println!("   📃 Pages:");
+    for page in &analysis.pages {
+        println!(
+            "      {:>4}: content {}kb, {} image(s) {}kb",
+            page.page, page.content_bytes / 1024, page.images, page.image_bytes / 1024
+        );
+    }
+
+    for estimate in &analysis.estimates {
+        let saved = analysis.file_size.saturating_sub(estimate.output_size);
+        println!(
+            "   🔮 quality {}, max width {}: {}kb -> {}kb ({:.1}% saved), images {}kb -> {}kb",
+            estimate.quality, estimate.max_width, analysis.file_size / 1024, estimate.output_size / 1024,
+            share(saved), estimate.image_bytes_before / 1024, estimate.image_bytes_after / 1024
+        );
+    }
+}
+
+/// `-o` is the image directory for a single input and holds one directory per
+/// PDF in batch mode. Without it the images go to `<name>_images` next to the input.
+fn images_dir(input: &Path, output: Option<&Path>, batch: bool) -> PathBuf {
+    let stem = input.file_stem().and_then(|s| s.to_str()).unwrap_or("output");
+    match output {
+        Some(dir) if batch => dir.join(stem),
+        Some(dir) => dir.to_path_buf(),
+        None => input.with_file_name(format!("{}_images", stem)),
+    }
+}
+
+fn file_report(
+    input: &Path,
+    output: &Path,
+    result: &Result<Summary, Error>,
+    started: Instant,
+    dry_run: bool,
+) -> FileReport {
+    let mut file = FileReport {
+        input: input.display().to_string(),
+        output: (!dry_run).then(|| output.display().to_string()),
+        error: None,
+        images_found: 0,
+        optimized: 0,
+        skipped: 0,
+        failed: 0,
+        deduplicated: 0,
+        dedup_bytes_saved: 0,
+        flagged: 0,
+        reverted: 0,
+        input_size: 0,
+        output_size: 0,
+        elapsed_ms: started.elapsed().as_millis(),
+        images: Vec::new(),
+        pdfa: None,
+    };
+
+    match result {
+        Ok(summary) => {
+            file.images_found = summary.found;
+            file.optimized = summary.optimized;
+            file.skipped = summary.images.iter().filter(|i| i.action == Action::Skipped).count();
+            file.failed = summary.failed;
+            file.deduplicated = summary.dedup.removed;
+            file.dedup_bytes_saved = summary.dedup.bytes_saved;
+            file.flagged = summary.flagged;
+            file.reverted = summary.reverted;
+            file.input_size = summary.input_size;
+            file.output_size = summary.output_size;
+            file.images = summary.images.clone();
+            file.pdfa = summary.pdfa.clone();
+        }
+        Err(e) => {
+            file.output = None;
+            file.error = Some(e.to_string());
+        }
+    }
+    file
+}
+
+/// Expands the command line inputs into a sorted list of PDF files.
+/// Returns `true` as the second value when we're in batch mode
+/// (a directory, a glob or more than one input).
+fn resolve_inputs(inputs: &[String]) -> Result<(Vec<PathBuf>, bool), String> {
+    let mut files = Vec::new();
+    let mut batch = inputs.len() > 1;
+
+    for input in inputs {
+        let path = Path::new(input);
+        if path.is_dir() {
+            batch = true;
+            let mut found: Vec<PathBuf> = std::fs::read_dir(path)
+                .map_err(|e| format!("Cannot read directory {}: {}", input, e))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.is_file() && is_pdf(p))
+                .collect();
+            found.sort();
+            files.extend(found);
+        } else if input.contains(['*', '?', '[']) {
+            batch = true;
+            let paths = glob::glob(input).map_err(|e| format!("Bad glob pattern {}: {}", input, e))?;
+            let mut found: Vec<PathBuf> = paths.filter_map(Result::ok).filter(|p| p.is_file()).collect();
+            found.sort();
+            files.extend(found);
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    // The same file given twice (or as `a.pdf` and `./a.pdf`) is only done once
+    let key = |p: &PathBuf| p.canonicalize().unwrap_or_else(|_| p.clone());
+    files.sort_by_cached_key(key);
+    files.dedup_by_key(|p| key(p));
+    Ok((files, batch))
 }
 
-fn replace_stream_with_jpeg(stream: &mut Stream, data: Vec<u8>, w: u32, h: u32) {
-    stream.set_content(data);
-    stream.dict.set("Type", "XObject");
-    stream.dict.set("Subtype", "Image");
-    stream.dict.set("Filter", "DCTDecode");
-    stream.dict.set("ColorSpace", "DeviceRGB");
-    stream.dict.set("BitsPerComponent", 8);
-    stream.dict.set("Width", w as i64);
-    stream.dict.set("Height", h as i64);
-    stream.dict.remove(b"DecodeParms");
-    stream.dict.remove(b"FilterParms");
-    stream.dict.remove(b"Length");
-    stream.dict.remove(b"Predictor");
-    stream.dict.remove(b"Columns");
+fn is_pdf(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.eq_ignore_ascii_case("pdf"))
+        .unwrap_or(false)
 }
 
-fn is_image_xobject(obj: &Object) -> bool {
-    if let Object::Stream(stream) = obj {
-        return stream.dict.get(b"Subtype").ok() 
-            .and_then(|o| o.as_name_str().ok())
-            .map(|s| s == "Image")
-            .unwrap_or(false);
+/// `-o` is the file itself for a single input and the target directory in batch mode
+/// (or when it points at an existing directory).
+/// Without `-o` the result lands next to the input as `<name>_compressed.pdf`.
+fn output_path(input: &Path, output: Option<&Path>, batch: bool) -> PathBuf {
+    let stem = input.file_stem().and_then(|s| s.to_str()).unwrap_or("output");
+    let file_name = format!("{}_compressed.pdf", stem);
+    match output {
+        Some(out) if !batch && !out.is_dir() => out.to_path_buf(),
+        Some(dir) => dir.join(file_name),
+        None => input.with_file_name(file_name),
+    }
+}
+
+/// Outputs are named after the input file only, so `a/scan.pdf` and
+/// `b/scan.pdf` would land in the same place in batch mode. Describes the
+/// first such pair, if any.
+fn find_collision(files: &[PathBuf], output: impl Fn(&Path) -> PathBuf) -> Option<String> {
+    let mut seen: HashMap<PathBuf, &Path> = HashMap::new();
+    for input in files {
+        let target = output(input);
+        if let Some(first) = seen.insert(target.clone(), input) {
+            return Some(format!("{} and {} would both be written to {}", first.display(), input.display(), target.display()));
+        }
     }
-    false
-}
\ No newline at end of file
+    None
+}
+
+/// Compresses one file, printing progress the way the pipeline used to.
+fn compress_file(compressor: &PdfCompressor, input: &Path, output: &Path, dry_run: bool, verifying: bool) -> Result<Summary, Error> {
+    println!("📄 Loading PDF: {}", input.display());
+    let summary = if dry_run {
+        let summary = compressor.compress_file_to(input, &mut std::io::sink())?;
+        println!("🧪 Dry run: would write {}kb to {}", summary.output_size / 1024, output.display());
+        summary
+    } else {
+        compressor.compress_file(input, output)?
+    };
+
+    println!("------------------------------------------------");
+    println!("✅ Final: Optimized: {}, Failed: {}, Deduplicated: {}", summary.optimized, summary.failed, summary.dedup.removed);
+    if verifying {
+        println!("🔬 Verified: {} flagged, {} reverted", summary.flagged, summary.reverted);
+    }
+    Ok(summary)
+}
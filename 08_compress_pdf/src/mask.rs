@@ -0,0 +1,173 @@
+use crate::colorspace::PdfColorSpace;
+use crate::decode::{decode_pdf_image, stream_data};
+use crate::filters::Decoded;
+use flate2::{Compression, write::ZlibEncoder};
+use image::imageops::FilterType;
+use lopdf::{Document, Object, ObjectId, Stream};
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Soft mask (`/SMask`) of an image, copied out of the `Document` next to its parent.
+pub struct MaskJob {
+    pub id: ObjectId,
+    raw_data: Decoded,
+    pub width: u32,
+    pub height: u32,
+    bpc: u32,
+}
+
+/// Every image stream referenced as `/SMask` or `/Mask`, with the number of images using it.
+pub fn collect_mask_parents(doc: &Document) -> HashMap<ObjectId, usize> {
+    let mut parents = HashMap::new();
+    for obj in doc.objects.values() {
+        if let Object::Stream(stream) = obj {
+            for key in [&b"SMask"[..], &b"Mask"[..]] {
+                if let Ok(id) = stream.dict.get(key).and_then(|o| o.as_reference()) {
+                    *parents.entry(id).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+    parents
+}
+
+pub fn extract_mask(doc: &Document, mask_id: ObjectId) -> Result<MaskJob, String> {
+    let stream = doc.get_object(mask_id).and_then(|o| o.as_stream()).map_err(|e| e.to_string())?;
+
+    let width = stream.dict.get(b"Width").ok().and_then(|v| v.as_i64().ok()).unwrap_or(0) as u32;
+    let height = stream.dict.get(b"Height").ok().and_then(|v| v.as_i64().ok()).unwrap_or(0) as u32;
+    let bpc = stream.dict.get(b"BitsPerComponent").ok().and_then(|v| v.as_i64().ok()).unwrap_or(8) as u32;
+
+    if width == 0 || height == 0 {
+        return Err("mask without dimensions".to_string());
+    }
+    if stream.dict.has(b"Decode") {
+        return Err("mask with /Decode".to_string());
+    }
+
+    let raw_data = stream_data(doc, stream)?;
+    Ok(MaskJob { id: mask_id, raw_data, width, height, bpc })
+}
+
+/// Resizes the mask to the new image size and Flate-compresses it as 8-bit gray.
+pub fn compress_mask(mask: &MaskJob, width: u32, height: u32) -> Result<Vec<u8>, String> {
+    let img = decode_pdf_image(&mask.raw_data, mask.width, mask.height, &PdfColorSpace::Gray, mask.bpc, &[])?;
+    let resized = img.resize_exact(width, height, FilterType::Lanczos3).to_luma8();
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(resized.as_raw()).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+/// Stores the resized mask. A mask shared by several images gets a fresh object
+/// for this parent, so the other images keep the size they expect.
+pub fn write_mask(doc: &mut Document, parent_id: ObjectId, mask_id: ObjectId, shared: bool, data: Vec<u8>, w: u32, h: u32) {
+    let target_id = if shared {
+        let Ok(original) = doc.get_object(mask_id).and_then(|o| o.as_stream()).cloned() else { return };
+        let new_id = doc.add_object(original);
+        if let Ok(parent) = doc.get_object_mut(parent_id).and_then(|o| o.as_stream_mut()) {
+            parent.dict.set("SMask", new_id);
+        }
+        new_id
+    } else {
+        mask_id
+    };
+
+    if let Ok(stream) = doc.get_object_mut(target_id).and_then(|o| o.as_stream_mut()) {
+        replace_stream_with_flate_gray(stream, data, w, h);
+    }
+}
+
+/// `/Matte` holds the colour the parent was pre-multiplied with, in the parent's
+/// colour space. When the parent now has `components` colour components
+/// (gray instead of RGB, RGB instead of CMYK) the matte is converted to match;
+/// for a palette (`None`) it can't be expressed and goes. A `shared` mask gets
+/// a copy for this parent first, the other users keep theirs.
+pub fn adjust_matte(doc: &mut Document, parent_id: ObjectId, components: Option<usize>, shared: bool) {
+    let Ok(mask_id) = doc.get_object(parent_id).and_then(|o| o.as_stream()).and_then(|s| s.dict.get(b"SMask")).and_then(|o| o.as_reference()) else {
+        return;
+    };
+    let Ok(mask) = doc.get_object(mask_id).and_then(|o| o.as_stream()) else { return };
+    let Ok(matte) = mask.dict.get(b"Matte").and_then(|o| o.as_array()) else { return };
+    let matte: Vec<f32> = matte.iter().filter_map(|v| v.as_float().ok()).collect();
+    if Some(matte.len()) == components {
+        return;
+    }
+    let converted = components.and_then(|n| convert_matte(&matte, n));
+
+    let target_id = if shared {
+        let copy = mask.clone();
+        let new_id = doc.add_object(copy);
+        if let Ok(parent) = doc.get_object_mut(parent_id).and_then(|o| o.as_stream_mut()) {
+            parent.dict.set("SMask", new_id);
+        }
+        new_id
+    } else {
+        mask_id
+    };
+    if let Ok(stream) = doc.get_object_mut(target_id).and_then(|o| o.as_stream_mut()) {
+        match converted {
+            Some(values) => stream.dict.set("Matte", values.into_iter().map(Object::Real).collect::<Vec<_>>()),
+            None => {
+                stream.dict.remove(b"Matte");
+            }
+        }
+    }
+}
+
+/// Matte colour from gray, RGB or CMYK to `components` (1 or 3) components,
+/// going through RGB with the same formulas the pixels went through (the
+/// `image` crate's Rec. 709 luma for gray).
+fn convert_matte(matte: &[f32], components: usize) -> Option<Vec<f32>> {
+    let [r, g, b] = match *matte {
+        [v] => [v; 3],
+        [r, g, b] => [r, g, b],
+        [c, m, y, k] => [(1.0 - c) * (1.0 - k), (1.0 - m) * (1.0 - k), (1.0 - y) * (1.0 - k)],
+        _ => return None,
+    };
+    match components {
+        1 => Some(vec![0.2126 * r + 0.7152 * g + 0.0722 * b]),
+        3 => Some(vec![r, g, b]),
+        _ => None,
+    }
+}
+
+fn replace_stream_with_flate_gray(stream: &mut Stream, data: Vec<u8>, w: u32, h: u32) {
+    stream.set_content(data);
+    stream.dict.set("Type", "XObject");
+    stream.dict.set("Subtype", "Image");
+    stream.dict.set("Filter", "FlateDecode");
+    stream.dict.set("ColorSpace", "DeviceGray");
+    stream.dict.set("BitsPerComponent", 8);
+    stream.dict.set("Width", w as i64);
+    stream.dict.set("Height", h as i64);
+    stream.dict.remove(b"DecodeParms");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    #[test]
+    fn matte_follows_a_parent_turned_gray_without_touching_other_users() {
+        let mut doc = Document::with_version("1.5");
+        let matte: Vec<Object> = vec![1.into(), 0.into(), 0.into()];
+        let mask_id = doc.add_object(Stream::new(dictionary! { "Subtype" => "Image", "Matte" => matte }, vec![0; 4]));
+        let parent = |doc: &mut Document| doc.add_object(Stream::new(dictionary! { "Subtype" => "Image", "SMask" => mask_id }, vec![]));
+        let (gray, rgb) = (parent(&mut doc), parent(&mut doc));
+
+        adjust_matte(&mut doc, gray, Some(1), true);
+        adjust_matte(&mut doc, rgb, Some(3), true);
+
+        let matte_of = |id: ObjectId| {
+            let mask = doc.get_object(id).unwrap().as_stream().unwrap().dict.get(b"SMask").unwrap().as_reference().unwrap();
+            let matte = doc.get_object(mask).unwrap().as_stream().unwrap().dict.get(b"Matte").unwrap().as_array().unwrap();
+            (mask, matte.iter().map(|v| v.as_float().unwrap()).collect::<Vec<_>>())
+        };
+        let (gray_mask, gray_matte) = matte_of(gray);
+        assert_ne!(gray_mask, mask_id);
+        assert!((gray_matte[0] - 0.2126).abs() < 1e-6 && gray_matte.len() == 1);
+        assert_eq!(matte_of(rgb), (mask_id, vec![1.0, 0.0, 0.0]));
+    }
+}
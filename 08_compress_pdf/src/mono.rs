@@ -0,0 +1,187 @@
+use crate::lossless;
+use fax::{Color, VecWriter, encoder::Encoder};
+use image::{DynamicImage, GrayImage, Luma};
+
+/// How the 1-bit images made for text pages are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Bilevel {
+    /// Whichever of CCITT G4 and Flate is smaller
+    #[default]
+    Auto,
+    /// CCITT Group 4, what fax machines and most scanners use
+    Ccitt,
+    /// Flate on packed 1-bit rows
+    Flate,
+}
+
+/// Spread between the R, G and B of a pixel (max - min) that still counts as gray.
+const GRAY_TOLERANCE: u8 = 24;
+
+/// Share of pixels allowed above the tolerance: scanners leave colour fringes
+/// along edges and the odd speck even on black-and-white originals.
+const GRAY_OUTLIERS: f64 = 0.005;
+
+/// Whether a colour image is gray in all but name, so DeviceGray loses nothing visible.
+pub fn is_near_gray(img: &DynamicImage) -> bool {
+    if !img.color().has_color() {
+        return true;
+    }
+    let rgb = img.to_rgb8();
+    let allowed = (rgb.width() as f64 * rgb.height() as f64 * GRAY_OUTLIERS) as u64;
+    let mut outliers = 0u64;
+    for p in rgb.pixels() {
+        let [r, g, b] = p.0;
+        if r.max(g).max(b) - r.min(g).min(b) > GRAY_TOLERANCE {
+            outliers += 1;
+            if outliers > allowed {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Share of pixels that have to be paper or ink for an image to count as a text page.
+const TEXT_PAGE_SHARE: f64 = 0.9;
+
+/// How much darker than the paper a pixel may be and still be paper.
+const PAPER_SPREAD: usize = 40;
+
+/// Scans of text and forms: mostly one light paper tone, plus ink well below
+/// it. Photos and shaded drawings have too many tones in between.
+pub fn is_text_page(gray: &GrayImage) -> bool {
+    let mut histogram = [0u64; 256];
+    for p in gray.pixels() {
+        histogram[p.0[0] as usize] += 1;
+    }
+    // The most common tone in the light half
+    let paper = (128..256).max_by_key(|&v| histogram[v]).unwrap_or(255);
+    if histogram[paper] == 0 {
+        return false;
+    }
+    let paper_or_ink: u64 = histogram
+        .iter()
+        .enumerate()
+        .filter(|&(v, _)| v + PAPER_SPREAD >= paper || v * 2 <= paper)
+        .map(|(_, &n)| n)
+        .sum();
+    paper_or_ink as f64 >= gray.width() as f64 * gray.height() as f64 * TEXT_PAGE_SHARE
+}
+
+/// Threshold window, as a fraction of the longer side (but at least 15 px).
+const WINDOW_DIVISOR: u32 = 16;
+
+/// Sauvola's k: how far below the local mean ink has to be where there is no contrast.
+const SAUVOLA_K: f64 = 0.2;
+
+/// Darker than this is ink whatever the neighbourhood, so solid areas don't hollow out.
+const ALWAYS_INK: u8 = 64;
+
+/// Adaptive (Sauvola) threshold: each pixel is compared with the mean and
+/// spread of its neighbourhood, so shadows and uneven lighting from the
+/// scanner don't swallow the text. Returns 0 for ink and 255 for paper.
+pub fn binarize(gray: &GrayImage) -> GrayImage {
+    let (w, h) = gray.dimensions();
+    let (sum, squares) = integral_images(gray);
+    let radius = (w.max(h) / WINDOW_DIVISOR).max(15) / 2;
+    let at = |table: &[u64], x: u32, y: u32| table[y as usize * (w as usize + 1) + x as usize];
+
+    GrayImage::from_fn(w, h, |x, y| {
+        let (x0, y0) = (x.saturating_sub(radius), y.saturating_sub(radius));
+        let (x1, y1) = ((x + radius + 1).min(w), (y + radius + 1).min(h));
+        let area = |table: &[u64]| at(table, x1, y1) + at(table, x0, y0) - at(table, x1, y0) - at(table, x0, y1);
+        let n = ((x1 - x0) * (y1 - y0)) as f64;
+        let mean = area(&sum) as f64 / n;
+        let deviation = (area(&squares) as f64 / n - mean * mean).max(0.0).sqrt();
+        let threshold = mean * (1.0 + SAUVOLA_K * (deviation / 128.0 - 1.0));
+
+        let v = gray.get_pixel(x, y).0[0];
+        Luma([if v <= ALWAYS_INK || (v as f64) < threshold { 0 } else { 255 }])
+    })
+}
+
+/// Summed-area tables of the samples and their squares, one row and column
+/// larger than the image so the borders need no special case.
+fn integral_images(gray: &GrayImage) -> (Vec<u64>, Vec<u64>) {
+    let (w, h) = (gray.width() as usize, gray.height() as usize);
+    let mut sum = vec![0u64; (w + 1) * (h + 1)];
+    let mut squares = vec![0u64; (w + 1) * (h + 1)];
+    for (y, row) in gray.rows().enumerate() {
+        let (mut row_sum, mut row_squares) = (0u64, 0u64);
+        for (x, p) in row.enumerate() {
+            let v = p.0[0] as u64;
+            row_sum += v;
+            row_squares += v * v;
+            let i = (y + 1) * (w + 1) + x + 1;
+            sum[i] = sum[i - w - 1] + row_sum;
+            squares[i] = squares[i - w - 1] + row_squares;
+        }
+    }
+    (sum, squares)
+}
+
+/// CCITT G4 (`/K -1`) with black as 0, the PDF default. `None` for images
+/// larger than the format's 65535 px in either direction.
+pub fn encode_ccitt(bilevel: &GrayImage) -> Option<Vec<u8>> {
+    let width = u16::try_from(bilevel.width()).ok()?;
+    u16::try_from(bilevel.height()).ok()?;
+
+    let mut encoder = Encoder::new(VecWriter::new());
+    for row in bilevel.rows() {
+        let pels = row.map(|p| if p.0[0] < 128 { Color::Black } else { Color::White });
+        encoder.encode_line(pels, width).ok()?;
+    }
+    Some(encoder.finish().ok()?.finish())
+}
+
+/// Flate on 1-bit DeviceGray rows, 0 for black.
+pub fn encode_flate(bilevel: &GrayImage) -> Result<Vec<u8>, String> {
+    let bits: Vec<u8> = bilevel.pixels().map(|p| (p.0[0] >= 128) as u8).collect();
+    lossless::deflate(&lossless::pack_indices(&bits, bilevel.width() as usize, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    /// Strokes on paper that gets `shade` darker towards the right, like a
+    /// scan with the lid not quite closed; ink is `ink` times the paper tone.
+    fn shaded_page(shade: u32, ink: f32) -> GrayImage {
+        GrayImage::from_fn(200, 100, |x, y| {
+            let paper = 250 - (x * shade / 200) as u8;
+            let is_ink = x % 20 < 3 && y % 10 < 6;
+            Luma([if is_ink { (paper as f32 * ink) as u8 } else { paper }])
+        })
+    }
+
+    #[test]
+    fn gray_with_colour_fringes_is_still_gray() {
+        let fringed = ImageBuffer::from_fn(100, 100, |x, y| match (x, y) {
+            (0, 0..5) => Rgb([255, 0, 0]),
+            _ => Rgb([(x * 2) as u8, (x * 2) as u8 + 5, (x * 2) as u8]),
+        });
+        assert!(is_near_gray(&DynamicImage::ImageRgb8(fringed)));
+        let colourful = ImageBuffer::from_fn(100, 100, |x, _| Rgb([(x * 2) as u8, 0, 0]));
+        assert!(!is_near_gray(&DynamicImage::ImageRgb8(colourful)));
+    }
+
+    #[test]
+    fn text_pages_are_told_apart_from_photos() {
+        assert!(is_text_page(&shaded_page(30, 0.2)));
+        let photo = GrayImage::from_fn(200, 100, |x, y| Luma([(x + y) as u8]));
+        assert!(!is_text_page(&photo));
+    }
+
+    #[test]
+    fn adaptive_threshold_keeps_text_in_the_shadow() {
+        let bilevel = binarize(&shaded_page(150, 0.67));
+        for (x, y) in [(1, 1), (181, 2)] {
+            assert_eq!(bilevel.get_pixel(x, y).0[0], 0, "ink at {},{}", x, y);
+        }
+        // Paper at the dark edge is darker than ink on the bright one, yet stays paper
+        for (x, y) in [(10, 1), (190, 8), (195, 50)] {
+            assert_eq!(bilevel.get_pixel(x, y).0[0], 255, "paper at {},{}", x, y);
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use crate::crypt::Security;
+use crate::lazy::LazySource;
+use flate2::{Compression, write::ZlibEncoder};
+use lopdf::{Dictionary, Document, Object, ObjectId, StringFormat};
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+/// Objects packed into one object stream. Bigger streams compress better but
+/// every reader has to inflate the whole stream to get at one object.
+const OBJECTS_PER_STREAM: usize = 200;
+
+/// lopdf 0.34 only writes classic xref tables and drops `/ObjStm` on save, so
+/// this writes the document itself: every non-stream object goes into Flate
+/// compressed object streams and the offsets into a cross-reference stream.
+/// Needs PDF 1.5, the header is raised if the document is older.
+///
+/// Without `object_streams` every object is written on its own and listed in a
+/// classic xref table; low-memory mode uses that because objects are written one
+/// at a time, with image data left out of the document read back from `source`
+/// just before it's written.
+///
+/// With `security`, the document must already reference its `/Encrypt`
+/// dictionary; objects are encrypted as they are written, object streams as a whole.
+pub fn save_streaming<W: Write>(
+    doc: &Document,
+    security: Option<&Security>,
+    source: Option<&LazySource>,
+    object_streams: bool,
+    target: &mut W,
+) -> io::Result<()> {
+    let mut out = Counting { inner: target, written: 0 };
+    let version = if object_streams && doc.version.as_str() < "1.5" { "1.5" } else { doc.version.as_str() };
+    writeln!(out, "%PDF-{}", version)?;
+    out.write_all(b"%\xe2\xe3\xcf\xd3\n")?;
+
+    let encrypt = doc.trailer.get(b"Encrypt").and_then(|e| e.as_reference()).ok();
+    let mut entries: Vec<(u32, Entry)> = Vec::with_capacity(doc.objects.len() + 16);
+    let mut packable = Vec::new();
+    let mut next_id = doc.max_id + 1;
+
+    for (&id, object) in &doc.objects {
+        if matches!(type_name(object), Some(b"ObjStm" | b"XRef")) {
+            continue;
+        }
+        // Streams can't live in object streams, and neither can anything with a
+        // generation number or the encryption dictionary
+        if !object_streams || matches!(object, Object::Stream(_)) || id.1 != 0 || Some(id) == encrypt {
+            entries.push((id.0, Entry::Offset(out.written, id.1)));
+            let object = match source {
+                Some(source) => source.complete(doc, id, object).map_err(io::Error::other)?,
+                None => Cow::Borrowed(object),
+            };
+            match security {
+                Some(security) if Some(id) != encrypt => {
+                    let mut object = object.into_owned();
+                    security.encrypt_object(id, &mut object);
+                    write_indirect(&mut out, id, &object)?;
+                }
+                _ => write_indirect(&mut out, id, &object)?,
+            }
+        } else {
+            packable.push((id.0, object));
+        }
+    }
+
+    for chunk in packable.chunks(OBJECTS_PER_STREAM) {
+        let container = next_id;
+        next_id += 1;
+
+        let mut header = Vec::new();
+        let mut body = Vec::new();
+        for (index, (num, object)) in chunk.iter().enumerate() {
+            write!(header, "{} {} ", num, body.len())?;
+            write_object(&mut body, object)?;
+            body.push(b'\n');
+            entries.push((*num, Entry::InStream(container, index as u16)));
+        }
+
+        let mut dict = Dictionary::new();
+        dict.set("Type", "ObjStm");
+        dict.set("N", chunk.len() as i64);
+        dict.set("First", header.len() as i64);
+        dict.set("Filter", "FlateDecode");
+        header.extend_from_slice(&body);
+
+        let mut content = deflate(&header)?;
+        if let Some(security) = security {
+            content = security.encrypt_stream_data((container, 0), &content);
+        }
+        entries.push((container, Entry::Offset(out.written, 0)));
+        write_stream_object(&mut out, (container, 0), dict, &content)?;
+    }
+
+    let mut dict = Dictionary::new();
+    for key in [&b"Root"[..], b"Info", b"ID", b"Encrypt"] {
+        if let Ok(value) = doc.trailer.get(key) {
+            dict.set(key, value.clone());
+        }
+    }
+    entries.push((0, Entry::Free(0, u16::MAX))); // head of the free list
+    let xref_offset = out.written;
+
+    if object_streams {
+        // The xref stream lists itself too
+        let xref_id = next_id;
+        entries.push((xref_id, Entry::Offset(xref_offset, 0)));
+        let (rows, index, widths) = xref_stream_rows(&mut entries);
+        dict.set("Type", "XRef");
+        dict.set("Size", xref_id as i64 + 1);
+        dict.set("W", widths);
+        dict.set("Index", index);
+        dict.set("Filter", "FlateDecode");
+        write_stream_object(&mut out, (xref_id, 0), dict, &deflate(&rows)?)?;
+    } else {
+        let mut table = Vec::new();
+        write_xref_table(&mut table, &mut entries)?;
+        dict.set("Size", next_id as i64);
+        table.extend_from_slice(b"trailer\n");
+        write_object(&mut table, &Object::Dictionary(dict))?;
+        table.push(b'\n');
+        out.write_all(&table)?;
+    }
+
+    write!(out, "startxref\n{}\n%%EOF\n", xref_offset)
+}
+
+pub enum Entry {
+    /// Next free object number and the generation to use when it's reused
+    Free(u32, u16),
+    /// Byte offset of a plain `n g obj`
+    Offset(usize, u16),
+    /// (object stream number, index inside it)
+    InStream(u32, u16),
+}
+
+/// Sorts `entries` and packs them into xref stream rows, together with the
+/// `/Index` array of subsections and the `/W` array. The offset field is 4 bytes
+/// wide unless the file goes past 4 GiB, then as wide as the largest offset needs.
+pub fn xref_stream_rows(entries: &mut [(u32, Entry)]) -> (Vec<u8>, Vec<Object>, Vec<Object>) {
+    entries.sort_by_key(|(num, _)| *num);
+    let largest = entries.iter().map(|(_, entry)| match entry {
+        Entry::Offset(offset, _) => *offset as u64,
+        Entry::Free(next, _) | Entry::InStream(next, _) => *next as u64,
+    });
+    let width = largest.max().map_or(0, |n| 8 - n.leading_zeros() as usize / 8).max(4);
+
+    let mut rows = Vec::with_capacity(entries.len() * (width + 3));
+    let mut index = Vec::new();
+    let mut expected = None;
+    for (num, entry) in entries.iter() {
+        // A new subsection starts whenever the object numbers have a gap
+        if expected != Some(*num) {
+            index.extend([Object::Integer(*num as i64), Object::Integer(0)]);
+        }
+        if let Some(Object::Integer(count)) = index.last_mut() {
+            *count += 1;
+        }
+        expected = Some(num + 1);
+        let (kind, field2, field3) = match entry {
+            Entry::Free(next, generation) => (0, *next as u64, *generation),
+            Entry::Offset(offset, generation) => (1, *offset as u64, *generation),
+            Entry::InStream(container, index) => (2, *container as u64, *index),
+        };
+        rows.push(kind);
+        rows.extend_from_slice(&field2.to_be_bytes()[8 - width..]);
+        rows.extend_from_slice(&field3.to_be_bytes());
+    }
+    let widths = vec![1.into(), (width as i64).into(), 2.into()];
+    (rows, index, widths)
+}
+
+/// Classic `xref` section, one subsection per run of consecutive object numbers.
+pub fn write_xref_table(out: &mut Vec<u8>, entries: &mut [(u32, Entry)]) -> io::Result<()> {
+    entries.sort_by_key(|(num, _)| *num);
+    out.extend_from_slice(b"xref\n");
+
+    let mut start = 0;
+    while start < entries.len() {
+        let mut end = start + 1;
+        while end < entries.len() && entries[end].0 == entries[end - 1].0 + 1 {
+            end += 1;
+        }
+        writeln!(out, "{} {}", entries[start].0, end - start)?;
+        for (_, entry) in &entries[start..end] {
+            // Every line is exactly 20 bytes, hence the two-character end of line
+            match entry {
+                Entry::Free(next, generation) => write!(out, "{:010} {:05} f\r\n", next, generation)?,
+                Entry::Offset(offset, generation) => write!(out, "{:010} {:05} n\r\n", offset, generation)?,
+                Entry::InStream(..) => unreachable!("tables can't point into object streams"),
+            }
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+pub(crate) fn type_name(object: &Object) -> Option<&[u8]> {
+    let dict = match object {
+        Object::Dictionary(d) => d,
+        Object::Stream(s) => &s.dict,
+        _ => return None,
+    };
+    dict.get(b"Type").and_then(|t| t.as_name()).ok()
+}
+
+pub fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Passes writes through and counts the bytes, for offsets and output sizes.
+pub struct Counting<W> {
+    pub inner: W,
+    pub written: usize,
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn write_indirect(out: &mut impl Write, id: ObjectId, object: &Object) -> io::Result<()> {
+    match object {
+        Object::Stream(stream) => write_stream_object(out, id, stream.dict.clone(), &stream.content),
+        _ => {
+            writeln!(out, "{} {} obj", id.0, id.1)?;
+            write_object(out, object)?;
+            out.write_all(b"\nendobj\n")
+        }
+    }
+}
+
+pub fn write_stream_object(out: &mut impl Write, id: ObjectId, mut dict: Dictionary, content: &[u8]) -> io::Result<()> {
+    dict.set("Length", content.len() as i64);
+    writeln!(out, "{} {} obj", id.0, id.1)?;
+    write_dictionary(out, &dict)?;
+    out.write_all(b"\nstream\n")?;
+    out.write_all(content)?;
+    out.write_all(b"\nendstream\nendobj\n")
+}
+
+/// Serializes one direct object, escaping names and strings the way lopdf does.
+pub fn write_object(out: &mut impl Write, object: &Object) -> io::Result<()> {
+    match object {
+        Object::Null => out.write_all(b"null"),
+        Object::Boolean(b) => out.write_all(if *b { b"true" } else { b"false" }),
+        Object::Integer(i) => write!(out, "{}", i),
+        Object::Real(r) => write!(out, "{}", r),
+        Object::Name(name) => write_name(out, name),
+        Object::String(text, StringFormat::Literal) => write_literal(out, text),
+        Object::String(text, StringFormat::Hexadecimal) => {
+            out.write_all(b"<")?;
+            for byte in text {
+                write!(out, "{:02X}", byte)?;
+            }
+            out.write_all(b">")
+        }
+        Object::Array(items) => {
+            out.write_all(b"[")?;
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.write_all(b" ")?;
+                }
+                write_object(out, item)?;
+            }
+            out.write_all(b"]")
+        }
+        Object::Dictionary(dict) => write_dictionary(out, dict),
+        // Only reachable for a stream nested inside another object, which PDF doesn't allow
+        Object::Stream(stream) => write_dictionary(out, &stream.dict),
+        Object::Reference(id) => write!(out, "{} {} R", id.0, id.1),
+    }
+}
+
+fn write_dictionary(out: &mut impl Write, dict: &Dictionary) -> io::Result<()> {
+    out.write_all(b"<<")?;
+    for (key, value) in dict.iter() {
+        write_name(out, key)?;
+        out.write_all(b" ")?;
+        write_object(out, value)?;
+    }
+    out.write_all(b">>")
+}
+
+fn write_name(out: &mut impl Write, name: &[u8]) -> io::Result<()> {
+    out.write_all(b"/")?;
+    for &byte in name {
+        // Delimiters, whitespace and anything outside printable ASCII become #xx
+        if b" \t\n\r\x0C()<>[]{}/%#".contains(&byte) || !(33..=126).contains(&byte) {
+            write!(out, "#{:02X}", byte)?;
+        } else {
+            out.write_all(&[byte])?;
+        }
+    }
+    Ok(())
+}
+
+fn write_literal(out: &mut impl Write, text: &[u8]) -> io::Result<()> {
+    out.write_all(b"(")?;
+    for &byte in text {
+        match byte {
+            b'(' | b')' | b'\\' => out.write_all(&[b'\\', byte])?,
+            b'\r' => out.write_all(b"\\r")?,
+            _ => out.write_all(&[byte])?,
+        }
+    }
+    out.write_all(b")")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    #[test]
+    fn escapes_names_and_strings() {
+        let mut out = Vec::new();
+        write_object(&mut out, &Object::Array(vec![
+            Object::Name(b"A B#".to_vec()),
+            Object::string_literal("(x\\"),
+            Object::Real(0.5),
+            Object::Reference((3, 0)),
+        ]))
+        .unwrap();
+        assert_eq!(out, b"[/A#20B#23 (\\(x\\\\) 0.5 3 0 R]");
+    }
+
+    #[test]
+    fn offsets_past_4_gib_widen_the_xref_field() {
+        let mut small = vec![(1, Entry::Offset(15, 0)), (2, Entry::InStream(1, 3))];
+        let (rows, index, widths) = xref_stream_rows(&mut small);
+        assert_eq!(widths, vec![1.into(), 4.into(), 2.into()]);
+        assert_eq!(index, vec![1.into(), 2.into()]);
+        assert_eq!(rows, [1, 0, 0, 0, 15, 0, 0, 2, 0, 0, 0, 1, 0, 3]);
+
+        let mut large = vec![(1, Entry::Offset(5 << 30, 0)), (2, Entry::Free(0, 1))];
+        let (rows, _, widths) = xref_stream_rows(&mut large);
+        assert_eq!(widths, vec![1.into(), 5.into(), 2.into()]);
+        assert_eq!(rows, [1, 1, 0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn round_trips_through_lopdf() {
+        let mut doc = Document::with_version("1.4");
+        let pages_id = doc.new_object_id();
+        let content = doc.add_object(lopdf::Stream::new(dictionary! {}, b"BT ET".to_vec()));
+        let page = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Contents" => content });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+
+        let mut buffer = Vec::new();
+        save_streaming(&doc, None, None, true, &mut buffer).unwrap();
+        assert!(buffer.starts_with(b"%PDF-1.5"));
+
+        let loaded = Document::load_mem(&buffer).unwrap();
+        assert_eq!(loaded.get_pages().len(), 1);
+        let page = loaded.get_dictionary(page).unwrap();
+        assert_eq!(page.get(b"Contents").unwrap().as_reference().unwrap(), content);
+    }
+
+    #[test]
+    fn writes_a_classic_table_without_object_streams() {
+        let mut doc = Document::with_version("1.4");
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+
+        let mut buffer = Vec::new();
+        save_streaming(&doc, None, None, false, &mut buffer).unwrap();
+        assert!(buffer.starts_with(b"%PDF-1.4"));
+
+        let loaded = Document::load_mem(&buffer).unwrap();
+        assert_eq!(loaded.get_pages().len(), 1);
+        assert!(matches!(loaded.reference_table.cross_reference_type, lopdf::xref::XrefType::CrossReferenceTable));
+    }
+}
@@ -0,0 +1,404 @@
+use crate::crypt::Security;
+use crate::filters::{self, Decoded};
+use crate::lazy::LazySource;
+use crate::objstm;
+use flate2::{Compression, write::ZlibEncoder};
+use lopdf::content::Content;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+/// Form XObjects / patterns nested deeper than this are ignored (and protect us from cycles).
+const MAX_FORM_DEPTH: usize = 12;
+
+/// Key prefixes applications use for their own private copy of the document
+/// (Illustrator keeps a whole `.ai` file in there).
+const PRIVATE_KEY_PREFIXES: [&[u8]; 3] = [b"AIPrivateData", b"AIMetaData", b"AIPDFPrivateData"];
+
+/// Which of the document-level passes run besides image recompression.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DocPasses {
+    /// Flate-compress streams that are stored without a filter
+    pub compress_streams: bool,
+    /// Pack objects into object streams with an xref stream (PDF 1.5)
+    pub object_streams: bool,
+    /// Drop fonts no page, form or annotation uses
+    pub unused_fonts: bool,
+    /// Remove embedded page thumbnails
+    pub thumbnails: bool,
+    /// Remove `/PieceInfo` application data
+    pub piece_info: bool,
+    /// Remove Illustrator's private copy of the document
+    pub private_data: bool,
+}
+
+/// What [`strip`] removed.
+#[derive(Debug, Default)]
+pub struct Stripped {
+    pub thumbnails: usize,
+    pub piece_info: usize,
+    pub private_data: usize,
+    pub fonts: usize,
+}
+
+/// Removes thumbnails, `/PieceInfo`, private application data and fonts no
+/// content stream selects. Runs before image extraction so thumbnails aren't
+/// recompressed just to be thrown away. The objects themselves disappear with
+/// `prune_objects`.
+pub fn strip(doc: &mut Document, passes: &DocPasses) -> Stripped {
+    let mut stripped = Stripped::default();
+
+    if passes.thumbnails {
+        for page_id in doc.get_pages().into_values() {
+            if let Ok(page) = doc.get_dictionary_mut(page_id)
+                && page.remove(b"Thumb").is_some()
+            {
+                stripped.thumbnails += 1;
+            }
+        }
+    }
+
+    if passes.piece_info || passes.private_data {
+        for object in doc.objects.values_mut() {
+            let dict = match object {
+                Object::Dictionary(d) => d,
+                Object::Stream(s) => &mut s.dict,
+                _ => continue,
+            };
+            if passes.piece_info && dict.remove(b"PieceInfo").is_some() {
+                stripped.piece_info += 1;
+            }
+            if passes.private_data {
+                let private: Vec<Vec<u8>> = dict
+                    .iter()
+                    .map(|(k, _)| k)
+                    .filter(|k| PRIVATE_KEY_PREFIXES.iter().any(|p| k.starts_with(p)))
+                    .cloned()
+                    .collect();
+                for key in private {
+                    dict.remove(&key);
+                    stripped.private_data += 1;
+                }
+            }
+        }
+    }
+
+    if passes.unused_fonts {
+        stripped.fonts = drop_unused_fonts(doc);
+    }
+
+    stripped
+}
+
+/// Flate-compresses (best level) every stream that has no filter yet, when it helps.
+pub fn compress_streams(doc: &mut Document) -> usize {
+    let mut count = 0;
+    for object in doc.objects.values_mut() {
+        let Object::Stream(stream) = object else { continue };
+        if stream.dict.has(b"Filter") || !stream.allows_compression || stream.content.is_empty() {
+            continue;
+        }
+        // PDF/A wants XMP metadata readable without decoding
+        if matches!(stream.dict.get(b"Type").and_then(|t| t.as_name()), Ok(b"XRef" | b"ObjStm" | b"Metadata")) {
+            continue;
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        let Ok(data) = encoder.write_all(&stream.content).and_then(|_| encoder.finish()) else { continue };
+        if data.len() < stream.content.len() {
+            stream.dict.set("Filter", "FlateDecode");
+            stream.dict.remove(b"DecodeParms");
+            stream.set_content(data);
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Writes the document, with object streams and an xref stream if that pass is on,
+/// encrypted again if it came in encrypted. Encryption happens in place, `doc`
+/// is not meant to be used after this.
+///
+/// Image data left out of the document (low-memory mode) is read back from
+/// `lazy` while writing, which only our own writer can do.
+pub fn save_to<W: Write>(
+    doc: &mut Document,
+    passes: &DocPasses,
+    security: Option<&Security>,
+    lazy: Option<&LazySource>,
+    target: &mut W,
+) -> io::Result<()> {
+    if passes.compress_streams {
+        compress_streams(doc);
+    }
+    // lopdf's writer leaves out the binary comment after the header, which PDF/A requires
+    if passes.object_streams || lazy.is_some() || is_pdfa(doc) {
+        if let Some(security) = security {
+            security.attach(doc);
+        }
+        objstm::save_streaming(doc, security, lazy, passes.object_streams, target)
+    } else {
+        if let Some(security) = security {
+            security.encrypt_document(doc);
+        }
+        doc.save_to(target).map_err(io::Error::other)
+    }
+}
+
+/// Whether the catalog has a PDF/A output intent.
+fn is_pdfa(doc: &Document) -> bool {
+    let intents = doc
+        .catalog()
+        .and_then(|c| c.get(b"OutputIntents"))
+        .and_then(|i| doc.dereference(i))
+        .and_then(|(_, i)| i.as_array());
+    intents.is_ok_and(|intents| {
+        intents.iter().any(|intent| {
+            let intent = doc.dereference(intent).and_then(|(_, i)| i.as_dict());
+            intent.is_ok_and(|i| i.get(b"S").and_then(|s| s.as_name()).ok() == Some(b"GTS_PDFA1"))
+        })
+    })
+}
+
+/// Where a `/Font` resource dictionary lives, so it can be edited afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum FontDict {
+    /// `/Font 12 0 R`
+    Object(ObjectId),
+    /// Inline `/Font << >>` inside the resources dictionary that is object `.0`
+    InResources(ObjectId),
+    /// Inline resources inside the page / form / pattern that is object `.0`
+    InOwner(ObjectId),
+}
+
+/// Removes `/Font` resource entries that no content stream selects with `Tf`.
+/// A font dictionary is only touched when every content stream using it could be
+/// parsed; anything we can't see (undecodable content, `gs` with a font) keeps it.
+fn drop_unused_fonts(doc: &mut Document) -> usize {
+    let mut usage = FontUsage { doc: &*doc, used: HashMap::new(), unsure: HashSet::new(), visited: HashSet::new() };
+
+    for page_id in usage.doc.get_pages().into_values() {
+        let Ok(page) = usage.doc.get_dictionary(page_id) else { continue };
+        let resources = page_resources(usage.doc, page_id);
+        match usage.doc.get_page_content(page_id) {
+            Ok(content) => usage.walk(&content, resources, 0),
+            Err(_) => usage.mark_unsure(resources),
+        }
+
+        // Annotation appearances are forms with their own resources
+        let annots = page.get(b"Annots").ok().and_then(|a| usage.doc.dereference(a).ok()).and_then(|(_, a)| a.as_array().ok());
+        for annot in annots.into_iter().flatten() {
+            let Some(ap) = usage.doc.dereference(annot).ok().and_then(|(_, a)| a.as_dict().ok()).and_then(|a| a.get(b"AP").ok()) else { continue };
+            let mut streams = Vec::new();
+            collect_streams(usage.doc, ap, &mut streams, 0);
+            for id in streams {
+                usage.draw(id, resources, 0);
+            }
+        }
+    }
+
+    // Form fields pick their fonts from /AcroForm /DR by name at fill-in time
+    let acroform = usage.doc.catalog().ok().and_then(|c| c.get(b"AcroForm").ok()).and_then(|a| usage.doc.dereference(a).ok()).and_then(|(_, a)| a.as_dict().ok());
+    if let Some(Ok(dr)) = acroform.map(|a| a.get(b"DR")) {
+        let catalog_id = usage.doc.trailer.get(b"Root").and_then(|r| r.as_reference()).unwrap_or((0, 0));
+        let dr = resources_of(usage.doc, catalog_id, dr);
+        usage.mark_unsure(dr);
+    }
+
+    let FontUsage { used, unsure, .. } = usage;
+    let mut removed = 0;
+    for (location, names) in used {
+        if unsure.contains(&location) {
+            continue;
+        }
+        let Some(fonts) = font_dict_mut(doc, location) else { continue };
+        let unused: Vec<Vec<u8>> = fonts.iter().map(|(k, _)| k).filter(|k| !names.contains(*k)).cloned().collect();
+        for name in unused {
+            fonts.remove(&name);
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// Resources of a page as (owner, dictionary), inherited from the page tree if needed.
+fn page_resources(doc: &Document, page_id: ObjectId) -> Option<(ObjectId, &Dictionary)> {
+    let mut node_id = page_id;
+    for _ in 0..64 {
+        let node = doc.get_dictionary(node_id).ok()?;
+        if let Ok(res) = node.get(b"Resources") {
+            return resources_of(doc, node_id, res);
+        }
+        node_id = node.get(b"Parent").and_then(|p| p.as_reference()).ok()?;
+    }
+    None
+}
+
+/// Resolves a `/Resources` value. The id returned is the resources object itself
+/// when it's indirect, otherwise the object that contains it inline.
+fn resources_of<'a>(doc: &'a Document, owner: ObjectId, value: &'a Object) -> Option<(ObjectId, &'a Dictionary)> {
+    match value {
+        Object::Reference(id) => Some((*id, doc.get_dictionary(*id).ok()?)),
+        Object::Dictionary(d) => Some((owner, d)),
+        _ => None,
+    }
+}
+
+/// Appearance dictionaries nest (`/N << /On 5 0 R /Off 6 0 R >>`), collect every stream in them.
+fn collect_streams(doc: &Document, obj: &Object, out: &mut Vec<ObjectId>, depth: usize) {
+    if depth > 3 {
+        return;
+    }
+    match obj {
+        Object::Reference(id) => match doc.get_object(*id) {
+            Ok(Object::Stream(_)) => out.push(*id),
+            Ok(other) => collect_streams(doc, other, out, depth + 1),
+            Err(_) => {}
+        },
+        Object::Dictionary(d) => d.iter().for_each(|(_, v)| collect_streams(doc, v, out, depth + 1)),
+        _ => {}
+    }
+}
+
+fn font_dict_mut(doc: &mut Document, location: FontDict) -> Option<&mut Dictionary> {
+    match location {
+        FontDict::Object(id) => doc.get_dictionary_mut(id).ok(),
+        FontDict::InResources(id) => doc.get_dictionary_mut(id).ok()?.get_mut(b"Font").ok()?.as_dict_mut().ok(),
+        FontDict::InOwner(id) => {
+            let owner = match doc.get_object_mut(id).ok()? {
+                Object::Dictionary(d) => d,
+                Object::Stream(s) => &mut s.dict,
+                _ => return None,
+            };
+            owner.get_mut(b"Resources").ok()?.as_dict_mut().ok()?.get_mut(b"Font").ok()?.as_dict_mut().ok()
+        }
+    }
+}
+
+struct FontUsage<'a> {
+    doc: &'a Document,
+    used: HashMap<FontDict, HashSet<Vec<u8>>>,
+    unsure: HashSet<FontDict>,
+    /// Forms and patterns already walked with a given font dictionary
+    visited: HashSet<(ObjectId, Option<FontDict>)>,
+}
+
+impl<'a> FontUsage<'a> {
+    fn font_dict(&self, resources: Option<(ObjectId, &'a Dictionary)>) -> Option<FontDict> {
+        let (id, res) = resources?;
+        let inline_owner = self.doc.get_dictionary(id).map(|d| std::ptr::eq(d, res)).unwrap_or(false);
+        match res.get(b"Font").ok()? {
+            Object::Reference(font_id) => Some(FontDict::Object(*font_id)),
+            Object::Dictionary(_) if inline_owner => Some(FontDict::InResources(id)),
+            Object::Dictionary(_) => Some(FontDict::InOwner(id)),
+            _ => None,
+        }
+    }
+
+    fn mark_unsure(&mut self, resources: Option<(ObjectId, &'a Dictionary)>) {
+        if let Some(fonts) = self.font_dict(resources) {
+            self.unsure.insert(fonts);
+        }
+    }
+
+    fn walk(&mut self, content: &[u8], resources: Option<(ObjectId, &'a Dictionary)>, depth: usize) {
+        let fonts = self.font_dict(resources);
+        if let Some(fonts) = fonts {
+            self.used.entry(fonts).or_default();
+        }
+        let Ok(content) = Content::decode(content) else {
+            self.mark_unsure(resources);
+            return;
+        };
+
+        let res = resources.map(|(_, r)| r);
+        let lookup = |category: &[u8], name: &[u8]| {
+            res.and_then(|r| r.get(category).ok())
+                .and_then(|c| self.doc.dereference(c).ok())
+                .and_then(|(_, c)| c.as_dict().ok())
+                .and_then(|c| c.get(name).ok())
+                .and_then(|o| o.as_reference().ok())
+        };
+
+        let mut drawn = Vec::new();
+        for op in &content.operations {
+            let name = op.operands.first().and_then(|o| o.as_name().ok());
+            match (op.operator.as_str(), name) {
+                ("Tf", Some(name)) => {
+                    if let Some(fonts) = fonts {
+                        self.used.entry(fonts).or_default().insert(name.to_vec());
+                    }
+                }
+                ("Do", Some(name)) => drawn.extend(lookup(b"XObject", name)),
+                // A font set through an ExtGState isn't worth following, keep everything
+                ("gs", Some(name)) => {
+                    let has_font = lookup(b"ExtGState", name)
+                        .and_then(|id| self.doc.get_dictionary(id).ok())
+                        .is_some_and(|gs| gs.has(b"Font"));
+                    if has_font {
+                        self.mark_unsure(resources);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // Tiling patterns carry their own content stream
+        let patterns = res.and_then(|r| r.get(b"Pattern").ok()).and_then(|p| self.doc.dereference(p).ok()).and_then(|(_, p)| p.as_dict().ok());
+        drawn.extend(patterns.into_iter().flat_map(|p| p.iter()).filter_map(|(_, v)| v.as_reference().ok()));
+
+        for id in drawn {
+            self.draw(id, resources, depth);
+        }
+    }
+
+    fn draw(&mut self, id: ObjectId, parent: Option<(ObjectId, &'a Dictionary)>, depth: usize) {
+        let Ok(stream) = self.doc.get_object(id).and_then(|o| o.as_stream()) else { return };
+        if depth >= MAX_FORM_DEPTH || stream.dict.get(b"Subtype").and_then(|s| s.as_name()).is_ok_and(|s| s == b"Image") {
+            return;
+        }
+        // Forms without their own resources use the ones of whoever draws them
+        let resources = match stream.dict.get(b"Resources") {
+            Ok(res) => resources_of(self.doc, id, res),
+            Err(_) => parent,
+        };
+        if !self.visited.insert((id, self.font_dict(resources))) {
+            return;
+        }
+        match filters::decode_stream(self.doc, stream) {
+            Ok(Decoded::Raw(content)) => self.walk(&content, resources, depth + 1),
+            _ => self.mark_unsure(resources),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Stream, dictionary};
+
+    #[test]
+    fn drops_only_fonts_never_selected() {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let f1 = doc.add_object(dictionary! { "Type" => "Font", "BaseFont" => "Helvetica" });
+        let f2 = doc.add_object(dictionary! { "Type" => "Font", "BaseFont" => "Courier" });
+        let f3 = doc.add_object(dictionary! { "Type" => "Font", "BaseFont" => "Times-Roman" });
+        let form = doc.add_object(Stream::new(dictionary! { "Subtype" => "Form" }, b"BT /F3 9 Tf ET".to_vec()));
+        let content = doc.add_object(Stream::new(dictionary! {}, b"BT /F1 12 Tf (Hi) Tj ET /Fm Do".to_vec()));
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page", "Parent" => pages_id, "Contents" => content,
+            "Resources" => dictionary! {
+                "Font" => dictionary! { "F1" => f1, "F2" => f2, "F3" => f3 },
+                "XObject" => dictionary! { "Fm" => form },
+            },
+        });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+
+        assert_eq!(drop_unused_fonts(&mut doc), 1);
+        let fonts = font_dict_mut(&mut doc, FontDict::InOwner(page)).unwrap();
+        assert!(fonts.has(b"F1") && fonts.has(b"F3") && !fonts.has(b"F2"));
+    }
+}
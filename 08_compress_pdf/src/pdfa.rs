@@ -0,0 +1,647 @@
+use crate::filters::{self, Decoded};
+use crate::lazy::LazySource;
+use crate::objstm;
+use lopdf::content::Content;
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream, StringFormat, dictionary};
+use md5::{Digest, Md5};
+use moxcms::ColorProfile;
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// Actions PDF/A-2 doesn't allow anywhere.
+const FORBIDDEN_ACTIONS: [&[u8]; 11] = [
+    b"JavaScript", b"Launch", b"Sound", b"Movie", b"ResetForm", b"ImportData",
+    b"Hide", b"SetOCGState", b"Rendition", b"Trans", b"GoTo3DView",
+];
+
+/// Annotation types PDF/A-2 doesn't allow.
+const FORBIDDEN_ANNOTATIONS: [&[u8]; 4] = [b"3D", b"Sound", b"Screen", b"Movie"];
+
+/// Annotation flags: Invisible, Hidden, NoView, ToggleNoView; and Print.
+const HIDING_FLAGS: i64 = 1 | 2 | 32 | 256;
+const PRINT_FLAG: i64 = 4;
+
+/// Name of the output condition of the sRGB output intent we embed.
+const SRGB: &str = "sRGB IEC61966-2.1";
+
+/// What making a document PDF/A-2b changed, and what is still in the way.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct PdfaReport {
+    pub fixed: Vec<String>,
+    /// Problems that need a human (or a full PDF/A converter), e.g. fonts that
+    /// aren't embedded. With any of these the output is not valid PDF/A-2b.
+    pub violations: Vec<String>,
+}
+
+impl PdfaReport {
+    fn fixed(&mut self, count: usize, what: &str) {
+        if count > 0 {
+            self.fixed.push(format!("{} {}", count, what));
+        }
+    }
+
+    fn violation(&mut self, count: usize, what: &str) {
+        if count > 0 {
+            self.violations.push(format!("{} {}", count, what));
+        }
+    }
+}
+
+/// Turns `doc` into PDF/A-2b as far as that works without touching page content:
+/// an sRGB output intent, XMP metadata with the PDF/A identification (rebuilt
+/// from the document information dictionary, earlier XMP is replaced), no
+/// JavaScript or other forbidden actions, printable annotations, no LZW and a
+/// file identifier. Encryption is left to the caller, who must save without it.
+/// Image data left out of the document (low-memory mode) is read from `source`.
+pub fn convert(doc: &mut Document, source: Option<&LazySource>) -> PdfaReport {
+    let mut report = PdfaReport::default();
+    let Ok(catalog_id) = doc.trailer.get(b"Root").and_then(|r| r.as_reference()) else {
+        report.violations.push("no document catalog".to_string());
+        return report;
+    };
+
+    let rgb_intent = output_intent(doc, catalog_id, &mut report);
+    remove_actions(doc, catalog_id, &mut report);
+    fix_annotations(doc, &mut report);
+    fix_xobjects(doc, rgb_intent, &mut report);
+    reencode_lzw(doc, source, &mut report);
+    check_fonts(doc, &mut report);
+    if rgb_intent {
+        check_cmyk(doc, &mut report);
+    }
+    if let Some(names) = catalog(doc, catalog_id).and_then(|c| c.get(b"Names").ok()).and_then(|n| doc.dereference(n).ok())
+        && let Ok(names) = names.1.as_dict()
+        && names.has(b"EmbeddedFiles")
+    {
+        report.violations.push("embedded files, which PDF/A-2 only allows if they are PDF/A themselves".to_string());
+    }
+
+    write_metadata(doc, catalog_id);
+    if !doc.trailer.has(b"ID") {
+        let id = Object::String(file_id(doc).to_vec(), StringFormat::Hexadecimal);
+        doc.trailer.set("ID", vec![id.clone(), id]);
+    }
+    report
+}
+
+fn catalog(doc: &Document, catalog_id: ObjectId) -> Option<&Dictionary> {
+    doc.get_dictionary(catalog_id).ok()
+}
+
+/// Adds the sRGB output intent unless there already is a PDF/A one.
+/// Returns whether the intent is RGB.
+fn output_intent(doc: &mut Document, catalog_id: ObjectId, report: &mut PdfaReport) -> bool {
+    let existing = catalog(doc, catalog_id)
+        .and_then(|c| c.get(b"OutputIntents").ok())
+        .and_then(|i| doc.dereference(i).ok())
+        .and_then(|(_, i)| i.as_array().ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|intent| doc.dereference(intent).ok().and_then(|(_, i)| i.as_dict().ok()))
+        .find(|intent| intent.get(b"S").and_then(|s| s.as_name()).ok() == Some(b"GTS_PDFA1"));
+    if let Some(intent) = existing {
+        let components = intent
+            .get(b"DestOutputProfile")
+            .and_then(|p| doc.dereference(p))
+            .and_then(|(_, p)| p.as_stream())
+            .and_then(|p| p.dict.get(b"N"))
+            .and_then(|n| n.as_i64());
+        return components.map_or(true, |n| n == 3);
+    }
+
+    let icc = match ColorProfile::new_srgb().encode() {
+        Ok(icc) => icc,
+        Err(e) => {
+            report.violations.push(format!("no output intent, the sRGB profile couldn't be built: {}", e));
+            return true;
+        }
+    };
+    let mut profile = Stream::new(dictionary! { "N" => 3 }, Vec::new());
+    match objstm::deflate(&icc) {
+        Ok(data) => {
+            profile.dict.set("Filter", "FlateDecode");
+            profile.set_content(data);
+        }
+        Err(_) => profile.set_content(icc),
+    }
+    let profile = doc.add_object(profile);
+    let intent = doc.add_object(dictionary! {
+        "Type" => "OutputIntent",
+        "S" => "GTS_PDFA1",
+        "OutputConditionIdentifier" => Object::string_literal(SRGB),
+        "Info" => Object::string_literal(SRGB),
+        "RegistryName" => Object::string_literal("http://www.color.org"),
+        "DestOutputProfile" => profile,
+    });
+    if let Ok(catalog) = doc.get_dictionary_mut(catalog_id) {
+        catalog.set("OutputIntents", vec![intent.into()]);
+    }
+    report.fixed.push(format!("added the {} output intent", SRGB));
+    true
+}
+
+/// Drops JavaScript and every other forbidden action, additional actions
+/// (`/AA`) and the catalog entries PDF/A-2 doesn't allow.
+fn remove_actions(doc: &mut Document, catalog_id: ObjectId, report: &mut PdfaReport) {
+    let forbidden: BTreeSet<ObjectId> = doc
+        .objects
+        .iter()
+        .filter(|(_, object)| object.as_dict().is_ok_and(is_forbidden_action))
+        .map(|(&id, _)| id)
+        .collect();
+
+    let mut actions = 0;
+    let mut additional = 0;
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => &mut stream.dict,
+            _ => continue,
+        };
+        additional += dict.remove(b"AA").is_some() as usize;
+        for key in [&b"A"[..], b"OpenAction", b"Next"] {
+            let remove = match dict.get(key) {
+                Ok(Object::Reference(id)) => forbidden.contains(id),
+                Ok(Object::Dictionary(action)) => is_forbidden_action(action),
+                _ => false,
+            };
+            if remove {
+                dict.remove(key);
+                actions += 1;
+            }
+        }
+    }
+    report.fixed(actions, "JavaScript or other forbidden action(s) removed");
+    report.fixed(additional, "additional-actions (/AA) entries removed");
+
+    let names = catalog(doc, catalog_id).and_then(|c| c.get(b"Names").ok()).cloned();
+    let names = match names {
+        Some(Object::Reference(id)) => doc.get_dictionary_mut(id).ok(),
+        Some(Object::Dictionary(_)) => doc.get_dictionary_mut(catalog_id).ok().and_then(|c| c.get_mut(b"Names").ok()).and_then(|n| n.as_dict_mut().ok()),
+        _ => None,
+    };
+    if names.is_some_and(|names| names.remove(b"JavaScript").is_some()) {
+        report.fixed.push("document-level JavaScript removed".to_string());
+    }
+
+    let acroform = catalog(doc, catalog_id).and_then(|c| c.get(b"AcroForm").ok()).cloned();
+    let acroform = match acroform {
+        Some(Object::Reference(id)) => doc.get_dictionary_mut(id).ok(),
+        Some(Object::Dictionary(_)) => doc.get_dictionary_mut(catalog_id).ok().and_then(|c| c.get_mut(b"AcroForm").ok()).and_then(|a| a.as_dict_mut().ok()),
+        _ => None,
+    };
+    if let Some(acroform) = acroform {
+        if acroform.remove(b"XFA").is_some() {
+            report.fixed.push("XFA form data removed".to_string());
+        }
+        if acroform.get(b"NeedAppearances").and_then(|n| n.as_bool()).unwrap_or(false) {
+            acroform.remove(b"NeedAppearances");
+            report.fixed.push("/NeedAppearances removed".to_string());
+        }
+    }
+    if let Ok(catalog) = doc.get_dictionary_mut(catalog_id)
+        && catalog.remove(b"NeedsRendering").is_some()
+    {
+        report.fixed.push("/NeedsRendering removed".to_string());
+    }
+}
+
+fn is_forbidden_action(dict: &Dictionary) -> bool {
+    dict.get(b"S").and_then(|s| s.as_name()).is_ok_and(|s| FORBIDDEN_ACTIONS.contains(&s))
+}
+
+/// Removes forbidden and hidden annotations and makes the rest printable.
+fn fix_annotations(doc: &mut Document, report: &mut PdfaReport) {
+    let (mut removed, mut printable, mut no_appearance) = (0, 0, 0);
+
+    for page_id in doc.get_pages().into_values() {
+        let annots = doc.get_dictionary(page_id).and_then(|p| p.get(b"Annots")).cloned();
+        let (owner, refs) = match annots {
+            Ok(Object::Reference(id)) => (Some(id), doc.get_object(id).and_then(|a| a.as_array()).cloned().unwrap_or_default()),
+            Ok(Object::Array(refs)) => (None, refs),
+            _ => continue,
+        };
+
+        let mut kept = Vec::with_capacity(refs.len());
+        for annot in refs {
+            let id = annot.as_reference().ok();
+            let dict = match id {
+                Some(id) => doc.get_dictionary_mut(id).ok(),
+                None => None,
+            };
+            let Some(dict) = dict else {
+                kept.push(annot);
+                continue;
+            };
+            let subtype = dict.get(b"Subtype").and_then(|s| s.as_name()).unwrap_or(b"").to_vec();
+            let flags = dict.get(b"F").and_then(|f| f.as_i64()).unwrap_or(0);
+            if FORBIDDEN_ANNOTATIONS.contains(&subtype.as_slice()) || flags & HIDING_FLAGS != 0 {
+                removed += 1;
+                continue;
+            }
+            if subtype != b"Popup" && flags & PRINT_FLAG == 0 {
+                dict.set("F", flags | PRINT_FLAG);
+                printable += 1;
+            }
+            let zero_size = dict
+                .get(b"Rect")
+                .and_then(|r| r.as_array())
+                .is_ok_and(|r| r.len() == 4 && number(&r[0]) == number(&r[2]) && number(&r[1]) == number(&r[3]));
+            if !matches!(subtype.as_slice(), b"Popup" | b"Link") && !zero_size && !dict.has(b"AP") {
+                no_appearance += 1;
+            }
+            kept.push(annot);
+        }
+
+        match owner {
+            Some(id) => {
+                if let Ok(Object::Array(refs)) = doc.get_object_mut(id) {
+                    *refs = kept;
+                }
+            }
+            None => {
+                if let Ok(page) = doc.get_dictionary_mut(page_id) {
+                    page.set("Annots", kept);
+                }
+            }
+        }
+    }
+    report.fixed(removed, "hidden or forbidden (3D, sound, movie, screen) annotation(s) removed");
+    report.fixed(printable, "annotation(s) made printable");
+    report.violation(no_appearance, "annotation(s) without an appearance stream");
+}
+
+fn number(object: &Object) -> Option<f32> {
+    object.as_float().ok().or_else(|| object.as_i64().ok().map(|n| n as f32))
+}
+
+/// Image and form XObject entries PDF/A forbids, and transparency groups
+/// blending in a colour space the output intent doesn't cover.
+fn fix_xobjects(doc: &mut Document, rgb_intent: bool, report: &mut PdfaReport) {
+    let (mut cleaned, mut groups, mut postscript, mut external) = (0, 0, 0, 0);
+    for object in doc.objects.values_mut() {
+        let dict = match object {
+            Object::Dictionary(dict) => dict,
+            Object::Stream(stream) => {
+                external += stream.dict.has(b"F") as usize;
+                &mut stream.dict
+            }
+            _ => continue,
+        };
+        let subtype = dict.get(b"Subtype").and_then(|s| s.as_name()).unwrap_or(b"").to_vec();
+        let is_page = dict.get(b"Type").and_then(|t| t.as_name()).ok() == Some(b"Page");
+        match subtype.as_slice() {
+            b"Image" => {
+                let interpolate = dict.get(b"Interpolate").and_then(|i| i.as_bool()).unwrap_or(false);
+                let removed = [&b"Alternates"[..], b"OPI"].iter().filter(|key| dict.remove(key).is_some()).count();
+                if interpolate {
+                    dict.remove(b"Interpolate");
+                }
+                cleaned += (removed > 0 || interpolate) as usize;
+            }
+            b"Form" => {
+                cleaned += dict.remove(b"OPI").is_some() as usize;
+                postscript += (dict.get(b"Subtype2").and_then(|s| s.as_name()).ok() == Some(b"PS")) as usize;
+            }
+            b"PS" => postscript += 1,
+            _ => {}
+        }
+        if (is_page || subtype == b"Form")
+            && rgb_intent
+            && let Ok(Object::Dictionary(group)) = dict.get_mut(b"Group")
+            && group.get(b"CS").and_then(|cs| cs.as_name()).ok() == Some(b"DeviceCMYK")
+        {
+            group.remove(b"CS");
+            groups += 1;
+        }
+    }
+    report.fixed(cleaned, "image or form XObject(s) without /Alternates, /OPI or /Interpolate");
+    report.fixed(groups, "DeviceCMYK transparency group(s) now blend in the output intent's colour space");
+    report.violation(postscript, "PostScript XObject(s)");
+    report.violation(external, "stream(s) with their data in an external file");
+}
+
+/// LZW isn't allowed, Flate does the same job.
+fn reencode_lzw(doc: &mut Document, source: Option<&LazySource>, report: &mut PdfaReport) {
+    let lzw: Vec<ObjectId> = doc
+        .objects
+        .iter()
+        .filter(|(_, object)| object.as_stream().is_ok_and(|s| filters::chain_name(&s.dict).contains("LZWDecode")))
+        .map(|(&id, _)| id)
+        .collect();
+
+    let (mut fixed, mut failed) = (0, 0);
+    for id in lzw {
+        let Ok(object) = doc.get_object(id) else { continue };
+        let Ok(mut stream) = object.as_stream().cloned() else { continue };
+        if let Some(source) = source
+            && source.is_left_out(id, object)
+        {
+            stream.content = source.fetch(doc, id).unwrap_or_default();
+        }
+        let data = match filters::decode_stream(doc, &stream) {
+            Ok(Decoded::Raw(data)) => objstm::deflate(&data).ok(),
+            _ => None,
+        };
+        let Some(data) = data else {
+            failed += 1;
+            continue;
+        };
+        if let Ok(Object::Stream(stream)) = doc.get_object_mut(id) {
+            stream.dict.set("Filter", "FlateDecode");
+            stream.dict.remove(b"DecodeParms");
+            stream.set_content(data);
+            fixed += 1;
+        }
+    }
+    report.fixed(fixed, "LZW stream(s) re-encoded with Flate");
+    report.violation(failed, "LZW stream(s) that couldn't be re-encoded");
+}
+
+/// Every font has to be embedded. Nothing we can do about that here.
+fn check_fonts(doc: &Document, report: &mut PdfaReport) {
+    let mut missing = BTreeSet::new();
+    for object in doc.objects.values() {
+        let Ok(font) = object.as_dict() else { continue };
+        if font.get(b"Type").and_then(|t| t.as_name()).ok() != Some(b"Font") {
+            continue;
+        }
+        let subtype = font.get(b"Subtype").and_then(|s| s.as_name()).unwrap_or(b"");
+        // Type 3 glyphs are content streams, Type 0 fonts are checked through their descendant
+        if matches!(subtype, b"Type3" | b"Type0") {
+            continue;
+        }
+        let embedded = font
+            .get(b"FontDescriptor")
+            .and_then(|d| doc.dereference(d))
+            .and_then(|(_, d)| d.as_dict())
+            .is_ok_and(|d| [&b"FontFile"[..], b"FontFile2", b"FontFile3"].iter().any(|key| d.has(key)));
+        if !embedded {
+            let name = font.get(b"BaseFont").and_then(|n| n.as_name()).unwrap_or(b"unnamed");
+            missing.insert(String::from_utf8_lossy(name).into_owned());
+        }
+    }
+    if !missing.is_empty() {
+        let names: Vec<String> = missing.into_iter().collect();
+        report.violations.push(format!("fonts not embedded: {}", names.join(", ")));
+    }
+}
+
+/// Device CMYK needs a CMYK output intent. Images are converted to RGB by the
+/// compressor; vector content painted in CMYK would need its content rewritten.
+fn check_cmyk(doc: &Document, report: &mut PdfaReport) {
+    let is_cmyk = |cs: Option<&Object>| cs.and_then(|cs| cs.as_name().ok()) == Some(b"DeviceCMYK");
+    let mut images = 0;
+    let mut streams: Vec<Vec<u8>> = Vec::new();
+    for page_id in doc.get_pages().into_values() {
+        if let Ok(content) = doc.get_page_content(page_id) {
+            streams.push(content);
+        }
+    }
+    for object in doc.objects.values() {
+        let Ok(stream) = object.as_stream() else { continue };
+        match stream.dict.get(b"Subtype").and_then(|s| s.as_name()) {
+            Ok(b"Image") => images += is_cmyk(stream.dict.get(b"ColorSpace").ok()) as usize,
+            Ok(b"Form") => {
+                if let Ok(Decoded::Raw(data)) = filters::decode_stream(doc, stream) {
+                    streams.push(data);
+                }
+            }
+            _ => {}
+        }
+    }
+    let painting = streams
+        .iter()
+        .filter_map(|data| Content::decode(data).ok())
+        .filter(|content| {
+            content.operations.iter().any(|op| match op.operator.as_str() {
+                "k" | "K" => true,
+                "cs" | "CS" => is_cmyk(op.operands.first()),
+                _ => false,
+            })
+        })
+        .count();
+    report.violation(images, "DeviceCMYK image(s) left as they are (skipped, or kept with --keep-cmyk)");
+    report.violation(painting, "content stream(s) painting in DeviceCMYK, which the sRGB output intent doesn't cover");
+}
+
+/// Replaces the document's XMP with one carrying the PDF/A identification and
+/// the document information dictionary, which PDF/A requires to agree.
+fn write_metadata(doc: &mut Document, catalog_id: ObjectId) {
+    let info = doc
+        .trailer
+        .get(b"Info")
+        .and_then(|i| doc.dereference(i))
+        .and_then(|(_, i)| i.as_dict())
+        .cloned()
+        .unwrap_or_default();
+    let text = |key: &[u8]| info.get(key).and_then(|v| v.as_str()).ok().map(|v| xml_escape(&text_string(v)));
+    let date = |key: &[u8]| info.get(key).and_then(|v| v.as_str()).ok().and_then(|v| xmp_date(&String::from_utf8_lossy(v)));
+
+    let mut properties = String::new();
+    let alt = |value: &str| format!("<rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt>", value);
+    if let Some(title) = text(b"Title") {
+        properties += &format!("   <dc:title>{}</dc:title>\n", alt(&title));
+    }
+    if let Some(author) = text(b"Author") {
+        properties += &format!("   <dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>\n", author);
+    }
+    if let Some(subject) = text(b"Subject") {
+        properties += &format!("   <dc:description>{}</dc:description>\n", alt(&subject));
+    }
+    for (key, property) in [(&b"Keywords"[..], "pdf:Keywords"), (b"Producer", "pdf:Producer"), (b"Creator", "xmp:CreatorTool")] {
+        if let Some(value) = text(key) {
+            properties += &format!("   <{0}>{1}</{0}>\n", property, value);
+        }
+    }
+    for (key, property) in [(&b"CreationDate"[..], "xmp:CreateDate"), (b"ModDate", "xmp:ModifyDate")] {
+        if let Some(value) = date(key) {
+            properties += &format!("   <{0}>{1}</{0}>\n", property, value);
+        }
+    }
+
+    let xmp = format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+         <rdf:Description rdf:about=\"\"\n\
+         \x20   xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n\
+         \x20   xmlns:pdf=\"http://ns.adobe.com/pdf/1.3/\"\n\
+         \x20   xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n\
+         \x20   xmlns:pdfaid=\"http://www.aiim.org/pdfa/ns/id/\">\n\
+         \x20  <pdfaid:part>2</pdfaid:part>\n\
+         \x20  <pdfaid:conformance>B</pdfaid:conformance>\n\
+         {}</rdf:Description>\n\
+         </rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>",
+        properties
+    );
+
+    // PDF/A wants the metadata readable without decoding, so it's never compressed
+    let mut stream = Stream::new(dictionary! { "Type" => "Metadata", "Subtype" => "XML" }, xmp.into_bytes());
+    stream.allows_compression = false;
+    let existing = catalog(doc, catalog_id).and_then(|c| c.get(b"Metadata").ok()).and_then(|m| m.as_reference().ok());
+    match existing {
+        Some(id) if doc.objects.contains_key(&id) => {
+            doc.objects.insert(id, Object::Stream(stream));
+        }
+        _ => {
+            let id = doc.add_object(stream);
+            if let Ok(catalog) = doc.get_dictionary_mut(catalog_id) {
+                catalog.set("Metadata", id);
+            }
+        }
+    }
+}
+
+/// A PDF text string: UTF-16BE with a byte order mark, otherwise (close enough
+/// to) PDFDocEncoding.
+fn text_string(bytes: &[u8]) -> String {
+    match bytes.strip_prefix(&[0xFE, 0xFF]) {
+        Some(utf16) => {
+            let units: Vec<u16> = utf16.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        None => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+        .fold(String::new(), |mut out, c| {
+            match c {
+                '&' => out.push_str("&amp;"),
+                '<' => out.push_str("&lt;"),
+                '>' => out.push_str("&gt;"),
+                '"' => out.push_str("&quot;"),
+                c => out.push(c),
+            }
+            out
+        })
+}
+
+/// `D:YYYYMMDDHHmmSSOHH'mm'` (everything after the year optional) as an XMP date.
+fn xmp_date(date: &str) -> Option<String> {
+    let date = date.strip_prefix("D:").unwrap_or(date);
+    let digits: String = date.chars().take_while(|c| c.is_ascii_digit()).collect();
+    if digits.len() < 4 {
+        return None;
+    }
+    let part = |from: usize| digits.get(from..from + 2);
+    let mut xmp = digits[..4].to_string();
+    for (from, separator) in [(4, '-'), (6, '-')] {
+        match part(from) {
+            Some(value) => {
+                xmp.push(separator);
+                xmp += value;
+            }
+            None => return Some(xmp),
+        }
+    }
+    let (Some(hour), Some(minute)) = (part(8), part(10)) else { return Some(xmp) };
+    xmp += &format!("T{}:{}:{}", hour, minute, part(12).unwrap_or("00"));
+
+    let zone = &date[digits.len()..];
+    match zone.chars().next() {
+        Some('Z') => xmp.push('Z'),
+        Some(sign @ ('+' | '-')) => {
+            let zone_digits: String = zone[1..].chars().filter(|c| c.is_ascii_digit()).collect();
+            let hours = zone_digits.get(..2)?;
+            xmp += &format!("{}{}:{}", sign, hours, zone_digits.get(2..4).unwrap_or("00"));
+        }
+        _ => {}
+    }
+    Some(xmp)
+}
+
+/// A file identifier for documents without one, from what identifies this one.
+fn file_id(doc: &Document) -> [u8; 16] {
+    let mut hasher = Md5::new();
+    if let Ok((_, info)) = doc.trailer.get(b"Info").and_then(|i| doc.dereference(i)) {
+        let _ = objstm::write_object(&mut hasher, info);
+    }
+    hasher.update(doc.max_id.to_be_bytes());
+    hasher.update(doc.objects.len().to_be_bytes());
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Document {
+        let mut doc = Document::with_version("1.5");
+        let js = doc.add_object(dictionary! { "S" => "JavaScript", "JS" => Object::string_literal("app.alert(1)") });
+        let font = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica" });
+        let lzw = weezl::encode::Encoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8).encode(b"BT /F1 12 Tf (Hi) Tj ET").unwrap();
+        let content = doc.add_object(Stream::new(dictionary! { "Filter" => "LZWDecode" }, lzw));
+        let hidden = doc.add_object(dictionary! { "Type" => "Annot", "Subtype" => "Text", "F" => 2, "Rect" => vec![0.into(), 0.into(), 10.into(), 10.into()] });
+        let link = doc.add_object(dictionary! { "Type" => "Annot", "Subtype" => "Link", "A" => js, "Rect" => vec![0.into(), 0.into(), 10.into(), 10.into()] });
+
+        let pages_id = doc.new_object_id();
+        let page = doc.add_object(dictionary! {
+            "Type" => "Page",
+            "Parent" => pages_id,
+            "Contents" => content,
+            "Resources" => dictionary! { "Font" => dictionary! { "F1" => font } },
+            "Annots" => vec![hidden.into(), link.into()],
+            "AA" => dictionary! { "O" => js },
+        });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id, "OpenAction" => js });
+        doc.trailer.set("Root", catalog);
+        // UTF-16BE with a byte order mark
+        let title: Vec<u8> = [0xFE, 0xFF].into_iter().chain("Grüße & co".encode_utf16().flat_map(|u| u.to_be_bytes())).collect();
+        let info = doc.add_object(dictionary! {
+            "Title" => Object::String(title, StringFormat::Literal),
+            "CreationDate" => Object::string_literal("D:20240131235959+01'00'"),
+        });
+        doc.trailer.set("Info", info);
+        doc
+    }
+
+    #[test]
+    fn fixes_what_it_can_and_reports_the_rest() {
+        let mut doc = sample();
+        let report = convert(&mut doc, None);
+
+        assert_eq!(report.violations, ["fonts not embedded: Helvetica"]);
+        for fixed in ["output intent", "2 JavaScript", "1 additional-actions", "1 hidden", "1 LZW"] {
+            assert!(report.fixed.iter().any(|f| f.contains(fixed)), "{} missing from {:?}", fixed, report.fixed);
+        }
+
+        let catalog = doc.catalog().unwrap();
+        assert!(!catalog.has(b"OpenAction"));
+        let intents = catalog.get(b"OutputIntents").unwrap().as_array().unwrap();
+        let intent = doc.get_dictionary(intents[0].as_reference().unwrap()).unwrap();
+        let profile = doc.get_object(intent.get(b"DestOutputProfile").unwrap().as_reference().unwrap()).unwrap().as_stream().unwrap();
+        assert!(ColorProfile::new_from_slice(&profile.decompressed_content().unwrap()).is_ok());
+
+        let page_id = doc.page_iter().next().unwrap();
+        let page = doc.get_dictionary(page_id).unwrap();
+        assert!(!page.has(b"AA"));
+        assert_eq!(page.get(b"Annots").unwrap().as_array().unwrap().len(), 1);
+        assert_eq!(doc.get_page_content(page_id).unwrap(), b"BT /F1 12 Tf (Hi) Tj ET");
+
+        let metadata = doc.get_object(catalog.get(b"Metadata").unwrap().as_reference().unwrap()).unwrap().as_stream().unwrap();
+        let xmp = String::from_utf8(metadata.content.clone()).unwrap();
+        assert!(!metadata.dict.has(b"Filter") && !metadata.allows_compression);
+        assert!(xmp.contains("<pdfaid:part>2</pdfaid:part>") && xmp.contains("<pdfaid:conformance>B</pdfaid:conformance>"));
+        assert!(xmp.contains(">Grüße &amp; co<"));
+        assert!(xmp.contains("<xmp:CreateDate>2024-01-31T23:59:59+01:00</xmp:CreateDate>"));
+        assert_eq!(doc.trailer.get(b"ID").unwrap().as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn pdf_dates_become_xmp_dates() {
+        assert_eq!(xmp_date("D:2024").as_deref(), Some("2024"));
+        assert_eq!(xmp_date("D:202401").as_deref(), Some("2024-01"));
+        assert_eq!(xmp_date("D:20240131").as_deref(), Some("2024-01-31"));
+        assert_eq!(xmp_date("D:202401312359").as_deref(), Some("2024-01-31T23:59:00"));
+        assert_eq!(xmp_date("D:20240131235959Z").as_deref(), Some("2024-01-31T23:59:59Z"));
+        assert_eq!(xmp_date("20240131235959-05'30").as_deref(), Some("2024-01-31T23:59:59-05:30"));
+        assert_eq!(xmp_date("garbage"), None);
+    }
+}
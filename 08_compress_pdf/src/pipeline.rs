@@ -0,0 +1,444 @@
+use crate::colorspace::PdfColorSpace;
+use crate::decode::{decode_array, decode_cmyk, decode_pdf_image, stream_data};
+use crate::error::ImageError;
+use crate::filters::{self, Decoded};
+use crate::lossless::{self, ImageClass, LosslessMode, RgbPixels};
+use crate::mask::{self, MaskJob};
+use crate::mono::{self, Bilevel};
+use image::{DynamicImage, GenericImageView, GrayImage, RgbaImage, imageops::{self, FilterType}};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use mozjpeg::{ColorSpace, Compress};
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// Knobs the image workers need, copied out of the compressor options.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct EncodeSettings {
+    pub max_width: u32,
+    pub quality: f32,
+    pub keep_cmyk: bool,
+    pub lossless: LosslessMode,
+    /// Extra downscale on top of `max_width`, used by `--target-size`
+    pub width_scale: f32,
+    /// Colour images that are really gray become DeviceGray
+    pub grayscale: bool,
+    /// Gray text pages become 1-bit images compressed like this
+    pub bilevel: Option<Bilevel>,
+}
+
+/// What happened to one image during write-back.
+pub(crate) enum Outcome {
+    Optimized { before: usize, after: usize, label: &'static str },
+    Skipped(String),
+    Failed(ImageError),
+}
+
+/// 2. Decode + resize + encode on the rayon pool. `collect` keeps the job order,
+///    so the output is the same no matter how many threads ran.
+pub(crate) fn process_all(jobs: &[ImageJob], settings: &EncodeSettings) -> Vec<Result<Compressed, ImageError>> {
+    jobs.par_iter()
+        .map(|job| process_job(job, settings))
+        .collect()
+}
+
+/// 3. Write-back into the Document, again in id order.
+pub(crate) fn write_back(
+    doc: &mut Document,
+    jobs: &[ImageJob],
+    results: Vec<Result<Compressed, ImageError>>,
+    mask_parents: &HashMap<ObjectId, usize>,
+) -> Vec<Outcome> {
+    let mut outcomes = Vec::with_capacity(jobs.len());
+
+    for (job, result) in jobs.iter().zip(results) {
+        let outcome = match result {
+            Ok(Compressed { data: compressed_data, width: new_w, height: new_h, encoding, smask }) => {
+                // Compare against the bytes actually stored in the file, not the decoded pixels
+                let is_worth_it = compressed_data.len() < job.original_size;
+                let new_size = compressed_data.len();
+
+                if !is_worth_it {
+                    Outcome::Skipped("Compressed is larger.".to_string())
+                } else {
+                    match doc.get_object_mut(job.id).and_then(|o| o.as_stream_mut()) {
+                        Ok(stream) => {
+                            let label = encoding.label();
+                            let components = encoding.components();
+
+                            match encoding {
+                                Encoding::Jpeg(color) => {
+                                    let palette = matches!(job.colorspace, PdfColorSpace::Indexed { .. });
+                                    replace_stream_with_jpeg(stream, compressed_data, new_w, new_h, color, palette)
+                                }
+                                Encoding::Flate { color_space, bpc, decode_parms } => {
+                                    replace_stream_with_flate(stream, compressed_data, new_w, new_h, color_space, bpc, decode_parms)
+                                }
+                                Encoding::Ccitt => replace_stream_with_ccitt(stream, compressed_data, new_w, new_h),
+                            }
+
+                            // The mask follows the parent, a mask nobody counts as its user stays alone
+                            if let Some(mask) = &job.smask
+                                && let Some(&users) = mask_parents.get(&mask.id)
+                            {
+                                let resized = smask.is_some();
+                                if let Some(mask_data) = smask {
+                                    mask::write_mask(doc, job.id, mask.id, users > 1, mask_data, new_w, new_h);
+                                }
+                                // A resized shared mask already is this parent's own copy
+                                mask::adjust_matte(doc, job.id, components, users > 1 && !resized);
+                            }
+
+                            Outcome::Optimized { before: job.original_size, after: new_size, label }
+                        }
+                        Err(e) => Outcome::Failed(ImageError::WriteBack(e.to_string())),
+                    }
+                }
+            }
+            Err(e) => Outcome::Failed(e),
+        };
+        outcomes.push(outcome);
+    }
+
+    outcomes
+}
+
+/// Raw image data copied out of the `Document`, so it can be processed off the main thread.
+pub(crate) struct ImageJob {
+    pub id: ObjectId,
+    pub filter_name: String,
+    /// Size of the stream as stored in the file, i.e. what we have to beat
+    pub original_size: usize,
+    pub raw_data: Decoded,
+    pub width: u32,
+    pub height: u32,
+    pub colorspace: PdfColorSpace,
+    pub bpc: u32,
+    /// `/Decode` array, empty for the default; applied when decoding
+    pub decode: Vec<f32>,
+    pub smask: Option<MaskJob>,
+    /// Width needed for `--target-dpi` at the image's largest placement
+    pub dpi_width: Option<u32>,
+}
+
+#[derive(Clone)]
+/// Output of [`process_job`]: the JPEG plus the soft mask resized to match, if it had to be.
+pub(crate) struct Compressed {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    encoding: Encoding,
+    smask: Option<Vec<u8>>,
+}
+
+/// How the new stream data is encoded, decides the image dictionary on write-back.
+#[derive(Clone)]
+enum Encoding {
+    Jpeg(JpegColor),
+    /// Lossless Flate, possibly palette-reduced or with PNG predictors
+    Flate { color_space: Object, bpc: u8, decode_parms: Option<Dictionary> },
+    /// 1-bit DeviceGray, CCITT Group 4
+    Ccitt,
+}
+
+impl Encoding {
+    /// Colour components of the new samples, `None` for a palette.
+    fn components(&self) -> Option<usize> {
+        match self {
+            Encoding::Jpeg(JpegColor::Gray) | Encoding::Ccitt => Some(1),
+            Encoding::Jpeg(JpegColor::Rgb) => Some(3),
+            Encoding::Jpeg(JpegColor::Cmyk) => Some(4),
+            Encoding::Flate { color_space, .. } => match color_space.as_name().ok()? {
+                b"DeviceGray" => Some(1),
+                b"DeviceRGB" => Some(3),
+                b"DeviceCMYK" => Some(4),
+                _ => None,
+            },
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Encoding::Jpeg(_) => "jpeg",
+            Encoding::Flate { .. } => "lossless",
+            Encoding::Ccitt => "ccitt",
+        }
+    }
+}
+
+/// Why an image didn't make it into the job list.
+pub(crate) enum NotExtracted {
+    /// Not a stream, zero-sized, or a stencil mask
+    Ignored,
+    Skipped(String),
+    Failed(ImageError),
+}
+
+/// Pulls the (decompressed) bytes of one image XObject, plus its soft mask if it has one.
+pub(crate) fn extract_image_job(doc: &Document, object_id: ObjectId) -> Result<ImageJob, NotExtracted> {
+    let stream = doc.get_object(object_id).and_then(|o| o.as_stream()).map_err(|_| NotExtracted::Ignored)?;
+
+    // Stencil masks are 1-bit on/off shapes, not pictures
+    if stream.dict.get(b"ImageMask").and_then(|o| o.as_bool()).unwrap_or(false) {
+        return Err(NotExtracted::Ignored);
+    }
+
+    let filter_name = filters::chain_name(&stream.dict);
+
+    let width = stream.dict.get(b"Width").ok().and_then(|v| v.as_i64().ok()).unwrap_or(0) as u32;
+    let height = stream.dict.get(b"Height").ok().and_then(|v| v.as_i64().ok()).unwrap_or(0) as u32;
+    let bpc = stream.dict.get(b"BitsPerComponent").ok().and_then(|v| v.as_i64().ok()).unwrap_or(8) as u32;
+
+    if width == 0 || height == 0 {
+        return Err(NotExtracted::Ignored);
+    }
+
+    let colorspace = match stream.dict.get(b"ColorSpace") {
+        Ok(cs) => PdfColorSpace::resolve(doc, cs),
+        Err(_) => PdfColorSpace::Rgb,
+    };
+
+    if let Ok(Object::Array(_)) = stream.dict.get(b"Mask") {
+        return Err(NotExtracted::Skipped("colour-key /Mask needs exact colours, JPEG would break it".to_string()));
+    }
+    let decode = decode_array(stream, &colorspace, bpc).map_err(NotExtracted::Skipped)?;
+
+    let smask = match stream.dict.get(b"SMask").and_then(|o| o.as_reference()) {
+        Ok(mask_id) => match mask::extract_mask(doc, mask_id) {
+            Ok(m) => Some(m),
+            Err(e) => return Err(NotExtracted::Skipped(format!("can't handle its /SMask: {}", e))),
+        },
+        Err(_) => None,
+    };
+
+    // JPX / JBIG2 have no decoder here, they stay as they are rather than count as failures
+    let raw_data = stream_data(doc, stream).map_err(|e| {
+        if e.ends_with("passthrough") { NotExtracted::Skipped(e) } else { NotExtracted::Failed(ImageError::Stream(e)) }
+    })?;
+
+    Ok(ImageJob { id: object_id, filter_name, original_size: stream.content.len(), raw_data, width, height, colorspace, bpc, decode, smask, dpi_width: None })
+}
+
+/// Decode + resize + JPEG encode. Runs on the rayon pool, so it only touches the job.
+pub(crate) fn process_job(job: &ImageJob, settings: &EncodeSettings) -> Result<Compressed, ImageError> {
+    // Never wider than what the page needs at the target DPI
+    let settings = &EncodeSettings {
+        max_width: job.dpi_width.map_or(settings.max_width, |w| w.min(settings.max_width)),
+        ..*settings
+    };
+
+    let (data, width, height, encoding) = if settings.keep_cmyk && job.colorspace.is_cmyk() {
+        let img = decode_cmyk(&job.raw_data, job.width, job.height, &job.colorspace, job.bpc, &job.decode)
+            .map_err(|e| ImageError::Decode(format!("{} (CS: {})", e, job.colorspace)))?;
+        compress_cmyk_logic(img, settings)
+    } else {
+        let img = decode_pdf_image(&job.raw_data, job.width, job.height, &job.colorspace, job.bpc, &job.decode)
+            .map_err(|e| ImageError::Decode(format!("{} (CS: {})", e, job.colorspace)))?;
+        compress_image_logic(img, settings)
+    }
+    .map_err(|e| ImageError::Encode(e.to_string()))?;
+
+    // A mask drawn at the image's own size has to follow the resize. Masks with
+    // their own resolution are mapped onto the unit square anyway, so they stay.
+    let smask = match &job.smask {
+        Some(mask) if (mask.width, mask.height) == (job.width, job.height) && (width, height) != (job.width, job.height) => {
+            Some(mask::compress_mask(mask, width, height).map_err(|e| ImageError::Mask(e.to_string()))?)
+        }
+        _ => None,
+    };
+
+    Ok(Compressed { data, width, height, encoding, smask })
+}
+
+/// Colour model of the JPEG we wrote; decides `/ColorSpace` and `/Decode` on write-back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JpegColor {
+    Gray,
+    Rgb,
+    /// Stored Adobe-style: inverted samples plus `/Decode [1 0 1 0 1 0 1 0]`
+    Cmyk,
+}
+
+/// Encoded bytes, final width/height and how they were encoded.
+type EncodeResult = Result<(Vec<u8>, u32, u32, Encoding), Box<dyn std::error::Error>>;
+
+/// Line art may still go JPEG if that's smaller, but never below this quality.
+const LINE_ART_MIN_QUALITY: f32 = 90.0;
+
+fn compress_image_logic(img: DynamicImage, settings: &EncodeSettings) -> EncodeResult {
+    // Colour scans of black-and-white originals: the colour channels only carry noise
+    let img = if (settings.grayscale || settings.bilevel.is_some()) && img.color().has_color() && mono::is_near_gray(&img) {
+        DynamicImage::ImageLuma8(img.to_luma8())
+    } else {
+        img
+    };
+
+    let mut pixels = None;
+    let class = match settings.lossless {
+        LosslessMode::Never => ImageClass::Photo,
+        LosslessMode::Always => ImageClass::LineArt,
+        LosslessMode::Auto => lossless::classify(pixels.insert(RgbPixels::new(&img))),
+    };
+
+    let target_w = if img.width() > settings.max_width { settings.max_width } else { img.width() };
+    let target_w = ((target_w as f32 * settings.width_scale).round() as u32).max(1);
+    let resized_img = img.resize(target_w, u32::MAX, FilterType::Lanczos3);
+    let (w, h) = resized_img.dimensions();
+
+    if let Some(filter) = settings.bilevel
+        && !resized_img.color().has_color()
+    {
+        let gray = resized_img.to_luma8();
+        if mono::is_text_page(&gray) {
+            let (data, encoding) = encode_bilevel(&mono::binarize(&gray), filter)?;
+            return Ok((data, w, h, encoding));
+        }
+    }
+
+    if class == ImageClass::Photo {
+        let (data, color) = encode_image_jpeg(&resized_img, settings.quality)?;
+        return Ok((data, w, h, Encoding::Jpeg(color)));
+    }
+
+    // Classified at full size; only a resize makes converting again necessary
+    let pixels = match pixels {
+        Some(pixels) if pixels.dimensions() == (w, h) => pixels,
+        _ => RgbPixels::new(&resized_img),
+    };
+    let lossless = lossless::encode_lossless(&pixels)?;
+    if settings.lossless == LosslessMode::Auto {
+        let (jpeg, color) = encode_image_jpeg(&resized_img, settings.quality.max(LINE_ART_MIN_QUALITY))?;
+        if jpeg.len() < lossless.data.len() {
+            return Ok((jpeg, w, h, Encoding::Jpeg(color)));
+        }
+    }
+
+    let encoding = Encoding::Flate { color_space: lossless.color_space, bpc: lossless.bpc, decode_parms: lossless.decode_parms };
+    Ok((lossless.data, w, h, encoding))
+}
+
+/// `filter` decides between CCITT G4 and Flate; CCITT can't go past 65535 px,
+/// so it falls back to Flate there.
+fn encode_bilevel(bilevel: &GrayImage, filter: Bilevel) -> Result<(Vec<u8>, Encoding), Box<dyn std::error::Error>> {
+    let ccitt = if filter == Bilevel::Flate { None } else { mono::encode_ccitt(bilevel) };
+    match ccitt {
+        Some(ccitt) if filter == Bilevel::Ccitt => Ok((ccitt, Encoding::Ccitt)),
+        ccitt => {
+            let flate = mono::encode_flate(bilevel)?;
+            match ccitt {
+                Some(ccitt) if ccitt.len() <= flate.len() => Ok((ccitt, Encoding::Ccitt)),
+                _ => Ok((flate, bilevel_flate())),
+            }
+        }
+    }
+}
+
+fn bilevel_flate() -> Encoding {
+    Encoding::Flate { color_space: Object::Name(b"DeviceGray".to_vec()), bpc: 1, decode_parms: None }
+}
+
+fn encode_image_jpeg(img: &DynamicImage, quality: f32) -> Result<(Vec<u8>, JpegColor), Box<dyn std::error::Error>> {
+    let (w, h) = img.dimensions();
+
+    // Gray stays single-channel, no point in tripling the data
+    if !img.color().has_color() {
+        let gray_img = img.to_luma8();
+        let comp_buf = encode_jpeg(gray_img.as_raw(), w, h, ColorSpace::JCS_GRAYSCALE, quality)?;
+        return Ok((comp_buf, JpegColor::Gray));
+    }
+
+    let rgb_img = img.to_rgb8();
+    let comp_buf = encode_jpeg(rgb_img.as_raw(), w, h, ColorSpace::JCS_RGB, quality)?;
+    Ok((comp_buf, JpegColor::Rgb))
+}
+
+/// Same as [`compress_image_logic`] for CMYK pixels (packed as C, M, Y, K in an RGBA buffer).
+fn compress_cmyk_logic(img: RgbaImage, settings: &EncodeSettings) -> EncodeResult {
+    let target_w = img.width().min(settings.max_width);
+    let target_w = ((target_w as f32 * settings.width_scale).round() as u32).max(1);
+    let target_h = ((img.height() as u64 * target_w as u64) / img.width() as u64).max(1) as u32;
+    // imageops::resize works per channel, so K isn't treated as alpha
+    let mut resized_img = imageops::resize(&img, target_w, target_h, FilterType::Lanczos3);
+
+    // Adobe convention: CMYK JPEG samples are stored inverted
+    for v in resized_img.iter_mut() {
+        *v = 255 - *v;
+    }
+
+    let comp_buf = encode_jpeg(resized_img.as_raw(), target_w, target_h, ColorSpace::JCS_CMYK, settings.quality)?;
+    Ok((comp_buf, target_w, target_h, Encoding::Jpeg(JpegColor::Cmyk)))
+}
+
+fn encode_jpeg(pixels: &[u8], w: u32, h: u32, color_space: ColorSpace, quality: f32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut comp = Compress::new(color_space);
+    comp.set_size(w as usize, h as usize);
+    comp.set_quality(quality);
+    let mut comp_buf = Vec::new();
+    let mut compressor = comp.start_compress(&mut comp_buf)?;
+    compressor.write_scanlines(pixels)?;
+    compressor.finish()?;
+    Ok(comp_buf)
+}
+
+/// `palette`: the image was Indexed, so its `/ColorSpace` has to go even for CMYK.
+fn replace_stream_with_jpeg(stream: &mut Stream, data: Vec<u8>, w: u32, h: u32, color: JpegColor, palette: bool) {
+    stream.set_content(data);
+    stream.dict.set("Type", "XObject");
+    stream.dict.set("Subtype", "Image");
+    stream.dict.set("Filter", "DCTDecode");
+    match color {
+        JpegColor::Gray => stream.dict.set("ColorSpace", "DeviceGray"),
+        JpegColor::Rgb => stream.dict.set("ColorSpace", "DeviceRGB"),
+        // DeviceCMYK, or an ICCBased profile that should stay with the samples
+        JpegColor::Cmyk if !palette => {}
+        JpegColor::Cmyk => stream.dict.set("ColorSpace", "DeviceCMYK"),
+    }
+    stream.dict.set("BitsPerComponent", 8);
+    stream.dict.set("Width", w as i64);
+    stream.dict.set("Height", h as i64);
+    if color == JpegColor::Cmyk {
+        stream.dict.set("Decode", vec![1.into(), 0.into(), 1.into(), 0.into(), 1.into(), 0.into(), 1.into(), 0.into()]);
+    } else {
+        stream.dict.remove(b"Decode");
+    }
+    stream.dict.remove(b"DecodeParms");
+    stream.dict.remove(b"FilterParms");
+    stream.dict.remove(b"Predictor");
+    stream.dict.remove(b"Columns");
+}
+
+fn replace_stream_with_flate(stream: &mut Stream, data: Vec<u8>, w: u32, h: u32, color_space: Object, bpc: u8, decode_parms: Option<Dictionary>) {
+    stream.set_content(data);
+    stream.dict.set("Type", "XObject");
+    stream.dict.set("Subtype", "Image");
+    stream.dict.set("Filter", "FlateDecode");
+    stream.dict.set("ColorSpace", color_space);
+    stream.dict.set("BitsPerComponent", bpc as i64);
+    stream.dict.set("Width", w as i64);
+    stream.dict.set("Height", h as i64);
+    if let Some(parms) = decode_parms {
+        stream.dict.set("DecodeParms", parms);
+    } else {
+        stream.dict.remove(b"DecodeParms");
+    }
+    stream.dict.remove(b"Decode");
+    stream.dict.remove(b"FilterParms");
+}
+
+fn replace_stream_with_ccitt(stream: &mut Stream, data: Vec<u8>, w: u32, h: u32) {
+    let mut parms = Dictionary::new();
+    parms.set("K", -1);
+    parms.set("Columns", w as i64);
+    parms.set("Rows", h as i64);
+    replace_stream_with_flate(stream, data, w, h, Object::Name(b"DeviceGray".to_vec()), 1, Some(parms));
+    stream.dict.set("Filter", "CCITTFaxDecode");
+}
+
+pub(crate) fn is_image_xobject(obj: &Object) -> bool {
+    if let Object::Stream(stream) = obj {
+        return stream.dict.get(b"Subtype").ok() 
+            .and_then(|o| o.as_name_str().ok())
+            .map(|s| s == "Image")
+            .unwrap_or(false);
+    }
+    false
+}
\ No newline at end of file
@@ -0,0 +1,212 @@
+use lopdf::content::Content;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::{BTreeSet, HashMap};
+
+/// Form XObjects nested deeper than this are ignored (and protect us from cycles).
+const MAX_FORM_DEPTH: usize = 12;
+
+/// Where and how big an image is drawn, over every place it's used.
+#[derive(Debug, Clone, Default)]
+pub struct Placement {
+    /// 1-based page numbers the image appears on (directly or through forms)
+    pub pages: BTreeSet<u32>,
+    /// Largest drawn width/height in points (1/72 inch)
+    pub max_width_pts: f32,
+    pub max_height_pts: f32,
+}
+
+impl Placement {
+    /// Pixel width needed to show a `width` x `height` px image at `dpi` at its
+    /// largest placement. Resizing keeps the aspect ratio, so the direction that
+    /// is stretched more decides; a narrow, tall placement needs its height.
+    pub fn width_for_dpi(&self, dpi: f32, width: u32, height: u32) -> u32 {
+        let px = |pts: f32| pts as f64 / 72.0 * dpi as f64;
+        let for_height = px(self.max_height_pts) * width as f64 / height.max(1) as f64;
+        px(self.max_width_pts).max(for_height).ceil().max(1.0) as u32
+    }
+}
+
+/// `[a b c d e f]` as used by `cm` and `/Matrix`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Matrix([f32; 6]);
+
+impl Matrix {
+    const IDENTITY: Matrix = Matrix([1.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+
+    fn from_operands(operands: &[Object]) -> Option<Matrix> {
+        if operands.len() != 6 {
+            return None;
+        }
+        let mut m = [0.0; 6];
+        for (slot, op) in m.iter_mut().zip(operands) {
+            *slot = number(op)?;
+        }
+        Some(Matrix(m))
+    }
+
+    /// `self × other`, i.e. apply `self` first, then `other`.
+    fn then(&self, other: &Matrix) -> Matrix {
+        let [a, b, c, d, e, f] = self.0;
+        let [a2, b2, c2, d2, e2, f2] = other.0;
+        Matrix([
+            a * a2 + b * c2,
+            a * b2 + b * d2,
+            c * a2 + d * c2,
+            c * b2 + d * d2,
+            e * a2 + f * c2 + e2,
+            e * b2 + f * d2 + f2,
+        ])
+    }
+
+    /// Length of the transformed unit vectors, i.e. the drawn size of the image's unit square.
+    fn scale(&self) -> (f32, f32) {
+        let [a, b, c, d, _, _] = self.0;
+        ((a * a + b * b).sqrt(), (c * c + d * d).sqrt())
+    }
+}
+
+fn number(obj: &Object) -> Option<f32> {
+    match obj {
+        Object::Integer(i) => Some(*i as f32),
+        Object::Real(r) => Some(*r),
+        _ => None,
+    }
+}
+
+/// Walks every page's content stream (following nested Form XObjects) and
+/// records where each image XObject is drawn and how big.
+pub fn collect_placements(doc: &Document) -> HashMap<ObjectId, Placement> {
+    let mut placements = HashMap::new();
+
+    for (page_number, page_id) in doc.get_pages() {
+        let Ok(page) = doc.get_dictionary(page_id) else { continue };
+        let Ok(content) = doc.get_page_content(page_id) else { continue };
+        let resources = page_resources(doc, page);
+
+        let mut walker = Walker { doc, page_number, placements: &mut placements };
+        walker.walk(&content, resources, Matrix::IDENTITY, 0);
+    }
+
+    placements
+}
+
+/// `/Resources` of a page, inherited from the page tree if the page has none.
+fn page_resources<'a>(doc: &'a Document, page: &'a Dictionary) -> Option<&'a Dictionary> {
+    let mut node = page;
+    for _ in 0..64 {
+        if let Ok(res) = node.get(b"Resources") {
+            return doc.dereference(res).ok().and_then(|(_, o)| o.as_dict().ok());
+        }
+        let parent = node.get(b"Parent").and_then(|p| p.as_reference()).ok()?;
+        node = doc.get_dictionary(parent).ok()?;
+    }
+    None
+}
+
+struct Walker<'a> {
+    doc: &'a Document,
+    page_number: u32,
+    placements: &'a mut HashMap<ObjectId, Placement>,
+}
+
+impl Walker<'_> {
+    fn walk(&mut self, content: &[u8], resources: Option<&Dictionary>, base: Matrix, depth: usize) {
+        let Ok(content) = Content::decode(content) else { return };
+        let xobjects = resources
+            .and_then(|r| r.get(b"XObject").ok())
+            .and_then(|x| self.doc.dereference(x).ok())
+            .and_then(|(_, o)| o.as_dict().ok());
+
+        let mut ctm = base;
+        let mut stack = Vec::new();
+
+        for op in &content.operations {
+            match op.operator.as_str() {
+                "q" => stack.push(ctm),
+                "Q" => ctm = stack.pop().unwrap_or(base),
+                "cm" => {
+                    if let Some(m) = Matrix::from_operands(&op.operands) {
+                        ctm = m.then(&ctm);
+                    }
+                }
+                "Do" => {
+                    let Some(name) = op.operands.first().and_then(|o| o.as_name().ok()) else { continue };
+                    let Some(id) = xobjects.and_then(|x| x.get(name).ok()).and_then(|o| o.as_reference().ok()) else { continue };
+                    self.draw(id, resources, ctm, depth);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn draw(&mut self, id: ObjectId, parent_resources: Option<&Dictionary>, ctm: Matrix, depth: usize) {
+        let Ok(stream) = self.doc.get_object(id).and_then(|o| o.as_stream()) else { return };
+
+        match stream.dict.get(b"Subtype").and_then(|s| s.as_name()) {
+            Ok(b"Image") => {
+                let (w, h) = ctm.scale();
+                let placement = self.placements.entry(id).or_default();
+                placement.pages.insert(self.page_number);
+                placement.max_width_pts = placement.max_width_pts.max(w);
+                placement.max_height_pts = placement.max_height_pts.max(h);
+            }
+            Ok(b"Form") if depth < MAX_FORM_DEPTH => {
+                let matrix = stream.dict.get(b"Matrix").ok()
+                    .and_then(|m| m.as_array().ok())
+                    .and_then(|m| Matrix::from_operands(m))
+                    .unwrap_or(Matrix::IDENTITY);
+                // Forms without their own resources use the ones of the page that draws them
+                let resources = stream.dict.get(b"Resources").ok()
+                    .and_then(|r| self.doc.dereference(r).ok())
+                    .and_then(|(_, o)| o.as_dict().ok())
+                    .or(parent_resources);
+                let content = stream.decompressed_content().unwrap_or_else(|_| stream.content.clone());
+                self.walk(&content, resources, matrix.then(&ctm), depth + 1);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Stream, dictionary};
+
+    #[test]
+    fn follows_cm_and_nested_forms() {
+        let mut doc = Document::with_version("1.5");
+        let image_id = doc.add_object(Stream::new(dictionary! { "Subtype" => "Image", "Width" => 3000, "Height" => 3000 }, vec![]));
+        let form_id = doc.add_object(Stream::new(
+            dictionary! {
+                "Subtype" => "Form",
+                "Matrix" => vec![0.5.into(), 0.into(), 0.into(), 0.5.into(), 0.into(), 0.into()],
+                "Resources" => dictionary! { "XObject" => dictionary! { "Img" => image_id } },
+            },
+            b"q 144 0 0 72 0 0 cm /Img Do Q".to_vec(),
+        ));
+        let pages_id = doc.new_object_id();
+        let page1 = doc.add_object(Stream::new(dictionary! {}, b"q 72 0 0 72 10 10 cm /Im0 Do Q".to_vec()));
+        let page2 = doc.add_object(Stream::new(dictionary! {}, b"2 0 0 2 0 0 cm /Fm0 Do".to_vec()));
+        let resources = dictionary! { "XObject" => dictionary! { "Im0" => image_id, "Fm0" => form_id } };
+        let p1 = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Contents" => page1 });
+        let p2 = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Contents" => page2 });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! {
+            "Type" => "Pages", "Kids" => vec![p1.into(), p2.into()], "Count" => 2, "Resources" => resources,
+        }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+
+        let placements = collect_placements(&doc);
+        let p = &placements[&image_id];
+        assert_eq!(p.pages.iter().copied().collect::<Vec<_>>(), vec![1, 2]);
+        // page 2: 144pt * form 0.5 * page 2 = 144pt wide, 72pt high
+        assert_eq!(p.max_width_pts, 144.0);
+        assert_eq!(p.max_height_pts, 72.0);
+        assert_eq!(p.width_for_dpi(150.0, 3000, 3000), 300);
+
+        // Squeezed to a narrow column: 0.5in wide but 4in high
+        let column = Placement { max_width_pts: 36.0, max_height_pts: 288.0, ..Placement::default() };
+        assert_eq!(column.width_for_dpi(150.0, 1000, 1000), 600);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::colorspace::PdfColorSpace;
+use crate::error::{Error, ImageError};
+use crate::filters;
+use crate::lazy;
+use crate::pdfa::PdfaReport;
+use lopdf::{Document, ObjectId};
+use serde::Serialize;
+use std::path::Path;
+
+/// What happened to one image.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Optimized,
+    Skipped,
+    Failed,
+    /// Identical to another image and merged into it
+    Deduplicated,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ImageReport {
+    pub id: u32,
+    pub generation: u16,
+    pub filter: String,
+    pub color_space: String,
+    pub width: u32,
+    pub height: u32,
+    pub bits_per_component: u32,
+    pub bytes_before: usize,
+    /// Same as `bytes_before` unless the image was optimized (0 when deduplicated)
+    pub bytes_after: usize,
+    pub action: Action,
+    /// "jpeg", "lossless" or "ccitt" for optimized images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<&'static str>,
+    /// Why it was skipped or failed, or why `--verify` flagged it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// `--verify` scores of the replacement against the original
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssim: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub psnr: Option<f64>,
+    /// The typed error behind `reason` for failed images
+    #[serde(skip)]
+    pub error: Option<ImageError>,
+}
+
+impl ImageReport {
+    /// Reads what the report needs straight from the image dictionary. The
+    /// action starts as skipped and is filled in once we know what happened.
+    pub fn describe(doc: &Document, id: ObjectId) -> ImageReport {
+        let stream = doc.get_object(id).and_then(|o| o.as_stream()).ok();
+        let int = |key: &[u8]| {
+            stream.and_then(|s| s.dict.get(key).ok()).and_then(|v| v.as_i64().ok())
+        };
+
+        ImageReport {
+            id: id.0,
+            generation: id.1,
+            filter: stream.map_or_else(|| "None".to_string(), |s| filters::chain_name(&s.dict)),
+            color_space: match stream.and_then(|s| s.dict.get(b"ColorSpace").ok()) {
+                Some(cs) => PdfColorSpace::resolve(doc, cs).to_string(),
+                None => "None".to_string(),
+            },
+            width: int(b"Width").unwrap_or(0) as u32,
+            height: int(b"Height").unwrap_or(0) as u32,
+            bits_per_component: int(b"BitsPerComponent").unwrap_or(8) as u32,
+            bytes_before: stream.map_or(0, |s| lazy::stored_len(doc, s)),
+            bytes_after: stream.map_or(0, |s| lazy::stored_len(doc, s)),
+            action: Action::Skipped,
+            encoding: None,
+            reason: None,
+            ssim: None,
+            psnr: None,
+            error: None,
+        }
+    }
+
+    pub fn finish(&mut self, action: Action, reason: Option<String>) {
+        self.action = action;
+        self.reason = reason;
+    }
+}
+
+/// One input file. `error` is set when the PDF couldn't be loaded or saved.
+#[derive(Serialize, Debug)]
+pub struct FileReport {
+    pub input: String,
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub images_found: usize,
+    pub optimized: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub deduplicated: usize,
+    pub dedup_bytes_saved: u64,
+    /// Images below the `--verify` thresholds that were kept / put back
+    pub flagged: usize,
+    pub reverted: usize,
+    pub input_size: u64,
+    pub output_size: u64,
+    pub elapsed_ms: u128,
+    pub images: Vec<ImageReport>,
+    /// `--pdfa`: what was fixed and what is still in the way
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pdfa: Option<PdfaReport>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Totals {
+    pub files: usize,
+    pub files_failed: usize,
+    pub images_found: usize,
+    pub optimized: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub deduplicated: usize,
+    pub dedup_bytes_saved: u64,
+    pub flagged: usize,
+    pub reverted: usize,
+    pub input_size: u64,
+    pub output_size: u64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    pub files: Vec<FileReport>,
+    pub totals: Totals,
+    pub elapsed_ms: u128,
+}
+
+impl Report {
+    pub fn add(&mut self, file: FileReport) {
+        let t = &mut self.totals;
+        t.files += 1;
+        t.files_failed += file.error.is_some() as usize;
+        t.images_found += file.images_found;
+        t.optimized += file.optimized;
+        t.skipped += file.skipped;
+        t.failed += file.failed;
+        t.deduplicated += file.deduplicated;
+        t.dedup_bytes_saved += file.dedup_bytes_saved;
+        t.flagged += file.flagged;
+        t.reverted += file.reverted;
+        t.input_size += file.input_size;
+        t.output_size += file.output_size;
+        self.files.push(file);
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self).map_err(std::io::Error::from)?;
+        Ok(())
+    }
+}
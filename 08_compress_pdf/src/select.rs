@@ -0,0 +1,100 @@
+use crate::lazy;
+use crate::placement::Placement;
+use lopdf::{Document, ObjectId};
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+
+/// Which images get recompressed. The default selects every image; the others
+/// are left exactly as they are and reported as skipped.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    /// 1-based page ranges. An image qualifies only if every page it is drawn
+    /// on (directly or through Form XObjects) is in one of them, since all its
+    /// uses share the one object. Empty means every page.
+    pub pages: Vec<RangeInclusive<u32>>,
+    /// Images stored in fewer bytes than this aren't worth it
+    pub min_bytes: u64,
+    /// Images narrower or lower than this (in pixels) stay as they are
+    pub min_width: u32,
+    pub min_height: u32,
+    /// Object numbers to leave alone
+    pub exclude: BTreeSet<u32>,
+}
+
+impl Selection {
+    /// Whether pages matter at all, so placements have to be collected.
+    pub(crate) fn by_page(&self) -> bool {
+        !self.pages.is_empty()
+    }
+
+    /// Why image `id` is not selected, `None` if it is. `placement` is where it
+    /// is drawn, only looked at when selecting by page.
+    pub(crate) fn reject(&self, doc: &Document, id: ObjectId, placement: Option<&Placement>) -> Option<String> {
+        if self.exclude.contains(&id.0) {
+            return Some("excluded".to_string());
+        }
+
+        if self.by_page() {
+            let pages = placement.map(|p| &p.pages).filter(|pages| !pages.is_empty());
+            let Some(pages) = pages else { return Some("not drawn on any page".to_string()) };
+            let outside: Vec<u32> = pages.iter().copied().filter(|n| !self.pages.iter().any(|r| r.contains(n))).collect();
+            if outside.len() == pages.len() {
+                return Some("not on the selected pages".to_string());
+            }
+            if let Some(page) = outside.first() {
+                return Some(format!("also drawn on page {}, outside the selected pages", page));
+            }
+        }
+
+        let stream = doc.get_object(id).and_then(|o| o.as_stream()).ok()?;
+        let bytes = lazy::stored_len(doc, stream) as u64;
+        if bytes < self.min_bytes {
+            return Some(format!("{} bytes, below the minimum of {}", bytes, self.min_bytes));
+        }
+        let int = |key: &[u8]| stream.dict.get(key).and_then(|v| v.as_i64()).unwrap_or(0).max(0) as u32;
+        let (width, height) = (int(b"Width"), int(b"Height"));
+        if width < self.min_width || height < self.min_height {
+            return Some(format!("{}x{} px, below the minimum of {}x{}", width, height, self.min_width, self.min_height));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Stream, dictionary};
+
+    fn placed_on(pages: &[u32]) -> Placement {
+        Placement { pages: pages.iter().copied().collect(), ..Placement::default() }
+    }
+
+    #[test]
+    fn every_page_of_an_image_has_to_be_selected() {
+        let mut doc = Document::with_version("1.5");
+        let id = doc.add_object(Stream::new(dictionary! { "Subtype" => "Image", "Width" => 10, "Height" => 10 }, vec![0; 300]));
+        let selection = Selection { pages: vec![2..=3, 5..=u32::MAX], ..Selection::default() };
+
+        assert_eq!(selection.reject(&doc, id, Some(&placed_on(&[2, 7]))), None);
+        assert_eq!(selection.reject(&doc, id, Some(&placed_on(&[1]))).unwrap(), "not on the selected pages");
+        assert!(selection.reject(&doc, id, Some(&placed_on(&[1, 3]))).unwrap().contains("page 1"));
+        assert_eq!(selection.reject(&doc, id, None).unwrap(), "not drawn on any page");
+        // Without page ranges, images that are never drawn still count
+        assert_eq!(Selection::default().reject(&doc, id, None), None);
+    }
+
+    #[test]
+    fn size_dimensions_and_exclusions() {
+        let mut doc = Document::with_version("1.5");
+        let id = doc.add_object(Stream::new(dictionary! { "Subtype" => "Image", "Width" => 400, "Height" => 20 }, vec![0; 300]));
+
+        let by_bytes = Selection { min_bytes: 301, ..Selection::default() };
+        assert!(by_bytes.reject(&doc, id, None).unwrap().contains("300 bytes"));
+        let by_size = Selection { min_width: 100, min_height: 100, ..Selection::default() };
+        assert!(by_size.reject(&doc, id, None).unwrap().contains("400x20"));
+        let excluded = Selection { exclude: BTreeSet::from([id.0]), ..Selection::default() };
+        assert_eq!(excluded.reject(&doc, id, None).unwrap(), "excluded");
+        let lenient = Selection { min_bytes: 300, min_width: 400, min_height: 20, ..Selection::default() };
+        assert_eq!(lenient.reject(&doc, id, None), None);
+    }
+}
@@ -0,0 +1,68 @@
+use lopdf::{Document, Object};
+
+/// Everything in the document that a full rewrite would invalidate: signed
+/// signature fields, bare signature dictionaries and `/Perms` (certification
+/// and usage rights). Empty when the document isn't signed.
+pub fn find_signatures(doc: &Document) -> Vec<String> {
+    let mut found = Vec::new();
+
+    for (id, object) in &doc.objects {
+        let Ok(dict) = object.as_dict() else { continue };
+        let is_field = dict.get(b"FT").and_then(|ft| ft.as_name()).is_ok_and(|ft| ft == b"Sig");
+        if is_field && dict.has(b"V") {
+            let name = dict
+                .get(b"T")
+                .and_then(|t| t.as_str())
+                .map(|t| String::from_utf8_lossy(t).into_owned())
+                .unwrap_or_else(|_| format!("object {}", id.0));
+            found.push(format!("signature field \"{}\"", name));
+        } else if !is_field && dict.has(b"ByteRange") && dict.has(b"Contents") && dict.type_is(b"Sig") {
+            // Signature dictionaries are usually reached through a field, but
+            // not every producer makes a proper form
+            if !signed_through_field(doc, id) {
+                found.push(format!("signature object {}", id.0));
+            }
+        }
+    }
+
+    let perms = doc.catalog().ok().and_then(|catalog| catalog.get(b"Perms").ok());
+    if let Some(Object::Dictionary(perms)) = perms.and_then(|p| doc.dereference(p).ok()).map(|(_, p)| p) {
+        for (key, _) in perms.iter() {
+            found.push(format!("/Perms /{}", String::from_utf8_lossy(key)));
+        }
+    }
+
+    found
+}
+
+fn signed_through_field(doc: &Document, sig: &lopdf::ObjectId) -> bool {
+    doc.objects.values().any(|object| {
+        object
+            .as_dict()
+            .and_then(|d| d.get(b"V"))
+            .and_then(|v| v.as_reference())
+            .is_ok_and(|v| v == *sig)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    #[test]
+    fn finds_signed_fields_only() {
+        let mut doc = Document::with_version("1.7");
+        let sig = doc.add_object(dictionary! {
+            "Type" => "Sig",
+            "ByteRange" => vec![0.into(), 10.into(), 20.into(), 30.into()],
+            "Contents" => Object::String(vec![0; 8], lopdf::StringFormat::Hexadecimal),
+        });
+        doc.add_object(dictionary! { "FT" => "Sig", "T" => Object::string_literal("Approval"), "V" => sig });
+        doc.add_object(dictionary! { "FT" => "Sig", "T" => Object::string_literal("Empty") });
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog" });
+        doc.trailer.set("Root", catalog);
+
+        assert_eq!(find_signatures(&doc), vec!["signature field \"Approval\"".to_string()]);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::crypt::Security;
+use crate::error::Error;
+use crate::optimize::{self, DocPasses};
+use crate::pipeline::{EncodeSettings, ImageJob, Outcome, process_all, process_job, write_back};
+use lopdf::{Document, ObjectId};
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// Steps every image walks down in `--target-size` mode, as
+/// (quality multiplier, width multiplier) on top of the user's settings.
+const STEPS: [(f32, f32); 7] = [
+    (1.0, 1.0),
+    (0.85, 1.0),
+    (0.7, 0.85),
+    (0.55, 0.7),
+    (0.45, 0.55),
+    (0.35, 0.45),
+    (0.25, 0.35),
+];
+
+/// JPEG quality never goes below this, whatever the budget.
+const MIN_QUALITY: f32 = 10.0;
+
+/// Rough share of an image's bytes one step down saves, used to decide how
+/// many images to touch per round.
+const STEP_SAVING: f64 = 0.3;
+
+pub struct Fit {
+    pub doc: Document,
+    pub outcomes: Vec<Outcome>,
+    /// Step index per job, see [`STEPS`]
+    pub steps: Vec<usize>,
+    pub size: u64,
+    /// Saved size after every round
+    pub rounds: Vec<u64>,
+}
+
+pub fn step_settings(base: &EncodeSettings, step: usize) -> EncodeSettings {
+    let (q, w) = STEPS[step];
+    EncodeSettings {
+        quality: (base.quality * q).max(MIN_QUALITY.min(base.quality)),
+        width_scale: base.width_scale * w,
+        ..*base
+    }
+}
+
+/// Recompresses with gradually lower quality / resolution until the saved
+/// document fits into `budget` bytes. The biggest images are lowered first;
+/// only images whose step changed get re-encoded in the next round.
+pub fn fit_to_size(
+    original: &Document,
+    jobs: &[ImageJob],
+    base: &EncodeSettings,
+    mask_parents: &HashMap<ObjectId, usize>,
+    passes: &DocPasses,
+    security: Option<&Security>,
+    budget: u64,
+) -> Result<Fit, Error> {
+    let mut steps = vec![0usize; jobs.len()];
+    let mut results = process_all(jobs, base);
+    let mut rounds = Vec::new();
+
+    loop {
+        let mut doc = original.clone();
+        let outcomes = write_back(&mut doc, jobs, results.clone(), mask_parents);
+        doc.prune_objects();
+
+        let size = saved_size(&doc, passes, security)?;
+        rounds.push(size);
+
+        // Largest contributors first: what each image currently occupies in the file
+        let mut order: Vec<usize> = (0..jobs.len())
+            .filter(|&i| steps[i] + 1 < STEPS.len() && !matches!(outcomes[i], Outcome::Failed(_)))
+            .collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(current_size(&jobs[i], &outcomes[i])));
+
+        if size <= budget || order.is_empty() {
+            return Ok(Fit { doc, outcomes, steps, size, rounds });
+        }
+
+        let excess = (size - budget) as f64;
+        let mut expected = 0.0;
+        let mut changed = Vec::new();
+        for i in order {
+            steps[i] += 1;
+            changed.push(i);
+            expected += current_size(&jobs[i], &outcomes[i]) as f64 * STEP_SAVING;
+            if expected >= excess {
+                break;
+            }
+        }
+
+        let redone: Vec<_> = changed
+            .par_iter()
+            .map(|&i| process_job(&jobs[i], &step_settings(base, steps[i])))
+            .collect();
+        for (i, result) in changed.into_iter().zip(redone) {
+            results[i] = result;
+        }
+    }
+}
+
+/// Size of `doc` saved the way it will be, object streams and all. Saving
+/// encrypts in place, so that happens to a copy.
+fn saved_size(doc: &Document, passes: &DocPasses, security: Option<&Security>) -> Result<u64, Error> {
+    let mut probe = doc.clone();
+    let mut buffer = Vec::new();
+    optimize::save_to(&mut probe, passes, security, None, &mut buffer)?;
+    Ok(buffer.len() as u64)
+}
+
+fn current_size(job: &ImageJob, outcome: &Outcome) -> usize {
+    match outcome {
+        Outcome::Optimized { after, .. } => *after,
+        _ => job.original_size,
+    }
+}
+
+/// Prints which settings were needed to get under the budget.
+pub fn print_report(fit: &Fit, base: &EncodeSettings, budget: u64) {
+    for (round, size) in fit.rounds.iter().enumerate() {
+        println!("🎯 Round {}: {}kb (budget {}kb)", round + 1, size / 1024, budget / 1024);
+    }
+    if fit.size <= budget {
+        println!("🎯 Fits: {}kb <= {}kb after {} round(s)", fit.size / 1024, budget / 1024, fit.rounds.len());
+    } else {
+        println!("⚠️ Could not reach {}kb, smallest result is {}kb", budget / 1024, fit.size / 1024);
+    }
+
+    for step in 0..STEPS.len() {
+        let count = fit.steps.iter().filter(|&&s| s == step).count();
+        if count == 0 {
+            continue;
+        }
+        let settings = step_settings(base, step);
+        println!(
+            "   {} image(s) at quality {:.0}, max width {}px",
+            count,
+            settings.quality,
+            (settings.max_width as f32 * settings.width_scale).round()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt;
+    use lopdf::{Stream, dictionary};
+
+    #[test]
+    fn measuring_an_encrypted_document_leaves_it_unencrypted() {
+        let mut doc = Document::with_version("1.4");
+        let content = doc.add_object(Stream::new(dictionary! {}, b"q Q".to_vec()));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Contents" => content });
+        doc.trailer.set("Root", catalog);
+        let security = crypt::encrypt_for_tests(&mut doc.clone());
+
+        assert!(saved_size(&doc, &DocPasses::default(), Some(&security)).unwrap() > 0);
+        assert!(!doc.trailer.has(b"Encrypt"));
+        assert_eq!(doc.get_object(content).unwrap().as_stream().unwrap().content, b"q Q");
+    }
+}
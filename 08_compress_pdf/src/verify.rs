@@ -0,0 +1,179 @@
+use crate::colorspace::PdfColorSpace;
+use crate::decode::{decode_array, decode_pdf_image, stream_data};
+use crate::pipeline::{ImageJob, Outcome};
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage};
+use lopdf::Document;
+use rayon::prelude::*;
+
+/// SSIM window size and step; overlapping 8x8 windows as in most SSIM tools.
+const WINDOW: u32 = 8;
+const STEP: u32 = 4;
+
+/// PSNR of two identical images is infinite, reported as this instead.
+const PSNR_IDENTICAL: f64 = 100.0;
+
+/// How close a replaced image is to its original.
+#[derive(Debug, Clone, Copy)]
+pub struct Quality {
+    /// Structural similarity of the luma channel, 1.0 = identical
+    pub ssim: f64,
+    /// Peak signal-to-noise ratio over RGB, in dB
+    pub psnr: f64,
+}
+
+/// Below either of these an image counts as damaged.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    pub min_ssim: f64,
+    pub min_psnr: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds { min_ssim: 0.9, min_psnr: 30.0 }
+    }
+}
+
+impl Quality {
+    /// Why the image fails `thresholds`, or `None` if it passes.
+    pub fn problem(&self, thresholds: &Thresholds) -> Option<String> {
+        if self.ssim < thresholds.min_ssim {
+            Some(format!("SSIM {:.4} below {}", self.ssim, thresholds.min_ssim))
+        } else if self.psnr < thresholds.min_psnr {
+            Some(format!("PSNR {:.1} dB below {} dB", self.psnr, thresholds.min_psnr))
+        } else {
+            None
+        }
+    }
+}
+
+/// Decodes the original and the replacement of every optimized image and
+/// compares them, in parallel. `None` for images that weren't replaced.
+///
+/// Downscaling is intended, so the original is resized to the new size first and
+/// only what the encoding did to the picture is measured.
+pub fn check_all(doc: &Document, jobs: &[ImageJob], outcomes: &[Outcome]) -> Vec<Option<Result<Quality, String>>> {
+    jobs.par_iter()
+        .zip(outcomes)
+        .map(|(job, outcome)| match outcome {
+            Outcome::Optimized { .. } => Some(check(doc, job)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn check(doc: &Document, job: &ImageJob) -> Result<Quality, String> {
+    let original = decode_pdf_image(&job.raw_data, job.width, job.height, &job.colorspace, job.bpc, &job.decode)
+        .map_err(|e| format!("original: {}", e))?;
+    let replaced = decode_current(doc, job).map_err(|e| format!("replacement: {}", e))?;
+    Ok(measure(&original, &replaced))
+}
+
+/// Decodes the image XObject currently stored for `job`. A CMYK replacement is
+/// converted with the same profile as the original, even if it wasn't stored with it.
+fn decode_current(doc: &Document, job: &ImageJob) -> Result<DynamicImage, String> {
+    let stream = doc.get_object(job.id).and_then(|o| o.as_stream()).map_err(|e| e.to_string())?;
+    let int = |key: &[u8]| stream.dict.get(key).and_then(|v| v.as_i64()).unwrap_or(0) as u32;
+    let mut colorspace = match stream.dict.get(b"ColorSpace") {
+        Ok(cs) => PdfColorSpace::resolve(doc, cs),
+        Err(_) => PdfColorSpace::Rgb,
+    };
+    if let Some(profile) = job.colorspace.cmyk_profile() {
+        colorspace = colorspace.with_cmyk_profile(profile);
+    }
+    let bpc = int(b"BitsPerComponent").max(1);
+    let decode = decode_array(stream, &colorspace, bpc)?;
+    let data = stream_data(doc, stream)?;
+    decode_pdf_image(&data, int(b"Width"), int(b"Height"), &colorspace, bpc, &decode)
+}
+
+/// Compares `replaced` with `original` scaled to the same size.
+pub fn measure(original: &DynamicImage, replaced: &DynamicImage) -> Quality {
+    let (w, h) = (replaced.width(), replaced.height());
+    let original = if (original.width(), original.height()) == (w, h) {
+        original.clone()
+    } else {
+        original.resize_exact(w, h, FilterType::Lanczos3)
+    };
+
+    Quality {
+        ssim: ssim(&original.to_luma8(), &replaced.to_luma8()),
+        psnr: psnr(original.to_rgb8().as_raw(), replaced.to_rgb8().as_raw()),
+    }
+}
+
+fn psnr(a: &[u8], b: &[u8]) -> f64 {
+    let squared: u64 = a.iter().zip(b).map(|(&x, &y)| (x as i64 - y as i64).pow(2) as u64).sum();
+    if squared == 0 {
+        return PSNR_IDENTICAL;
+    }
+    let mse = squared as f64 / a.len() as f64;
+    (10.0 * (255.0 * 255.0 / mse).log10()).min(PSNR_IDENTICAL)
+}
+
+/// Mean SSIM over windows (Wang et al. 2004, uniform instead of Gaussian weights).
+/// Images smaller than one window are compared as a single window.
+fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (w, h) = a.dimensions();
+    let (win_w, win_h) = (WINDOW.min(w), WINDOW.min(h));
+    let mut total = 0.0;
+    let mut windows = 0;
+
+    for y in (0..=h - win_h).step_by(STEP as usize) {
+        for x in (0..=w - win_w).step_by(STEP as usize) {
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..win_h {
+                for dx in 0..win_w {
+                    let pa = a.get_pixel(x + dx, y + dy)[0] as f64;
+                    let pb = b.get_pixel(x + dx, y + dy)[0] as f64;
+                    sa += pa;
+                    sb += pb;
+                    saa += pa * pa;
+                    sbb += pb * pb;
+                    sab += pa * pb;
+                }
+            }
+            let n = (win_w * win_h) as f64;
+            let (ma, mb) = (sa / n, sb / n);
+            let (va, vb, cov) = (saa / n - ma * ma, sbb / n - mb * mb, sab / n - ma * mb);
+            total += ((2.0 * ma * mb + C1) * (2.0 * cov + C2)) / ((ma * ma + mb * mb + C1) * (va + vb + C2));
+            windows += 1;
+        }
+    }
+
+    total / windows as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(noise: u8) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(64, 48, |x, y| {
+            let v = (x * 3 + y * 2) as u8;
+            image::Luma([if (x + y) % 2 == 0 { v.saturating_add(noise) } else { v.saturating_sub(noise) }])
+        }))
+    }
+
+    #[test]
+    fn identical_images_are_perfect() {
+        let q = measure(&gradient(0), &gradient(0));
+        assert!((q.ssim - 1.0).abs() < 1e-9);
+        assert_eq!(q.psnr, PSNR_IDENTICAL);
+    }
+
+    #[test]
+    fn noise_lowers_both_scores_and_trips_the_threshold() {
+        let light = measure(&gradient(0), &gradient(2));
+        let heavy = measure(&gradient(0), &gradient(40));
+        assert!(light.ssim > heavy.ssim && light.psnr > heavy.psnr);
+
+        let thresholds = Thresholds { min_ssim: 0.9, min_psnr: 30.0 };
+        assert!(light.problem(&thresholds).is_none());
+        assert!(heavy.problem(&thresholds).is_some());
+    }
+}
@@ -0,0 +1,526 @@
+use crate::compressor::{PdfCompressor, Summary};
+use crate::error::Error;
+use notify::{RecursiveMode, Watcher};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often the stop flag and the pending files are looked at.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Full directory scan on top of the events, for anything they missed
+/// (network shares written from another machine don't always send any).
+const RESCAN: Duration = Duration::from_secs(30);
+
+/// Long-running mode for a scanner inbox: every PDF that appears in `inbox`
+/// is compressed into `output` once it has stopped changing, and the original
+/// is moved to `archive`. PDFs that fail to load are tried again a few times
+/// (a slow writer may not be done yet) and then moved to `quarantine` with an
+/// `.error` file next to them; refused ones (signed, wrong password) go there
+/// straight away.
+#[derive(Debug, Clone)]
+pub struct FolderWatcher {
+    compressor: PdfCompressor,
+    inbox: PathBuf,
+    output: PathBuf,
+    archive: PathBuf,
+    quarantine: PathBuf,
+    settle: Duration,
+    retries: u32,
+    retry_delay: Duration,
+    log: Option<PathBuf>,
+    verbose: bool,
+}
+
+/// What happened to one file, one line of the log.
+#[derive(Serialize, Debug, Clone)]
+pub struct WatchEvent {
+    /// UTC, e.g. `2024-05-01T12:00:00Z`
+    pub time: String,
+    pub input: String,
+    pub outcome: WatchOutcome,
+    /// Attempts so far, counting this one
+    pub attempt: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// Where the original went (archive or quarantine)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moved_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub input_size: u64,
+    pub output_size: u64,
+    pub images_found: usize,
+    pub optimized: usize,
+    pub failed: usize,
+    pub elapsed_ms: u128,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchOutcome {
+    Compressed,
+    /// Failed to load, will be tried again
+    Retry,
+    Quarantined,
+}
+
+/// Totals of one [`FolderWatcher::run`].
+#[derive(Debug, Default, Clone)]
+pub struct WatchStats {
+    pub compressed: usize,
+    pub retried: usize,
+    pub quarantined: usize,
+    pub input_size: u64,
+    pub output_size: u64,
+}
+
+/// A file seen in the inbox that hasn't been dealt with yet.
+struct Pending {
+    /// Size and modification time when last looked at
+    snapshot: Option<(u64, SystemTime)>,
+    /// Last time the snapshot changed or an event came in
+    changed: Instant,
+    attempts: u32,
+    retry_at: Option<Instant>,
+}
+
+impl FolderWatcher {
+    /// Archive and quarantine default to subdirectories of the inbox; only the
+    /// inbox itself is watched, not what is below it. The inbox is made canonical,
+    /// as some platforms report events with the resolved path.
+    pub fn new(compressor: PdfCompressor, inbox: &Path, output: &Path) -> Self {
+        let inbox = inbox.canonicalize().unwrap_or_else(|_| inbox.to_path_buf());
+        FolderWatcher {
+            compressor,
+            output: output.to_path_buf(),
+            archive: inbox.join("archive"),
+            quarantine: inbox.join("quarantine"),
+            inbox,
+            settle: Duration::from_secs(5),
+            retries: 3,
+            retry_delay: Duration::from_secs(10),
+            log: None,
+            verbose: false,
+        }
+    }
+
+    pub fn archive(mut self, dir: &Path) -> Self {
+        self.archive = dir.to_path_buf();
+        self
+    }
+
+    pub fn quarantine(mut self, dir: &Path) -> Self {
+        self.quarantine = dir.to_path_buf();
+        self
+    }
+
+    /// How long a file has to stay unchanged (size and modification time)
+    /// before it counts as fully written.
+    pub fn settle(mut self, settle: Duration) -> Self {
+        self.settle = settle;
+        self
+    }
+
+    /// How often a file that fails to load is tried again before it is quarantined.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait before the first retry; every further one waits that much longer.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Append one JSON line per [`WatchEvent`] to this file.
+    pub fn log(mut self, path: Option<PathBuf>) -> Self {
+        self.log = path;
+        self
+    }
+
+    /// Print every outcome to stdout, as the command line does.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Watches until `stop` is set, then returns once the file being compressed
+    /// at that moment is finished. PDFs already in the inbox are picked up first.
+    pub fn run(&self, stop: &AtomicBool) -> Result<WatchStats, Error> {
+        for dir in [&self.output, &self.archive, &self.quarantine] {
+            fs::create_dir_all(dir)?;
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx).map_err(std::io::Error::other)?;
+        watcher.watch(&self.inbox, RecursiveMode::NonRecursive).map_err(std::io::Error::other)?;
+        if self.verbose {
+            println!("👀 Watching {} (output {}, archive {}, quarantine {})",
+                self.inbox.display(), self.output.display(), self.archive.display(), self.quarantine.display());
+        }
+
+        let mut stats = WatchStats::default();
+        let mut pending: BTreeMap<PathBuf, Pending> = BTreeMap::new();
+        // Handled but still in the inbox because moving them failed, as they were then
+        let mut stuck: HashMap<PathBuf, Option<(u64, SystemTime)>> = HashMap::new();
+        let mut scanned: Option<Instant> = None;
+
+        while !stop.load(Ordering::SeqCst) {
+            let mut touched = Vec::new();
+            match rx.recv_timeout(TICK) {
+                Ok(event) => touched.push(event),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Err(std::io::Error::other("file watcher stopped").into()),
+            }
+            touched.extend(rx.try_iter());
+            for event in touched {
+                match event {
+                    Ok(event) => {
+                        for path in event.paths {
+                            if self.is_candidate(&path) && !is_stuck(&mut stuck, &path) {
+                                pending.entry(path).or_insert_with(Pending::new).changed = Instant::now();
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("⚠️  Watch error: {}", e),
+                }
+            }
+
+            if scanned.is_none_or(|t| t.elapsed() >= RESCAN) {
+                stuck.retain(|path, seen| snapshot(path) == *seen);
+                for path in fs::read_dir(&self.inbox)?.filter_map(|e| e.ok().map(|e| e.path())) {
+                    if self.is_candidate(&path) && !is_stuck(&mut stuck, &path) {
+                        pending.entry(path).or_insert_with(Pending::new);
+                    }
+                }
+                scanned = Some(Instant::now());
+            }
+
+            let ready = self.ready(&mut pending);
+            for path in ready {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let attempts = pending.get(&path).map_or(0, |p| p.attempts) + 1;
+                let event = self.handle(&path, attempts);
+                match event.outcome {
+                    WatchOutcome::Compressed => {
+                        stats.compressed += 1;
+                        stats.input_size += event.input_size;
+                        stats.output_size += event.output_size;
+                    }
+                    WatchOutcome::Retry => stats.retried += 1,
+                    WatchOutcome::Quarantined => stats.quarantined += 1,
+                }
+                if event.outcome == WatchOutcome::Retry {
+                    let entry = pending.entry(path).or_insert_with(Pending::new);
+                    entry.attempts = attempts;
+                    entry.retry_at = Some(Instant::now() + self.retry_delay * attempts);
+                } else {
+                    pending.remove(&path);
+                    if event.moved_to.is_none() {
+                        let seen = snapshot(&path);
+                        stuck.insert(path, seen);
+                    }
+                }
+                self.record(&event);
+            }
+        }
+
+        if self.verbose {
+            println!("🛑 Stopped watching {}", self.inbox.display());
+        }
+        Ok(stats)
+    }
+
+    /// PDFs directly in the inbox; hidden files are usually still being copied.
+    fn is_candidate(&self, path: &Path) -> bool {
+        let hidden = path.file_name().and_then(|n| n.to_str()).is_none_or(|n| n.starts_with('.'));
+        !hidden
+            && path.parent() == Some(self.inbox.as_path())
+            && path.extension().and_then(|e| e.to_str()).is_some_and(|e| e.eq_ignore_ascii_case("pdf"))
+    }
+
+    /// Pending files that have stopped changing and aren't waiting for a retry.
+    /// Files that went away are forgotten.
+    fn ready(&self, pending: &mut BTreeMap<PathBuf, Pending>) -> Vec<PathBuf> {
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        pending.retain(|path, file| {
+            let Ok(meta) = fs::metadata(path) else { return false };
+            if !meta.is_file() {
+                return false;
+            }
+            let snapshot = Some((meta.len(), meta.modified().unwrap_or(UNIX_EPOCH)));
+            if snapshot != file.snapshot {
+                file.snapshot = snapshot;
+                file.changed = now;
+            }
+            let settled = now.duration_since(file.changed) >= self.settle && meta.len() > 0;
+            if settled && file.retry_at.is_none_or(|t| now >= t) {
+                ready.push(path.clone());
+            }
+            true
+        });
+        ready
+    }
+
+    /// Compresses one settled file and moves the original where it belongs.
+    fn handle(&self, input: &Path, attempt: u32) -> WatchEvent {
+        let started = Instant::now();
+        let name = input.file_name().unwrap_or_default();
+        let output = unique_path(&self.output.join(name));
+        let mut event = WatchEvent {
+            time: utc_now(),
+            input: input.display().to_string(),
+            outcome: WatchOutcome::Compressed,
+            attempt,
+            output: None,
+            moved_to: None,
+            error: None,
+            input_size: 0,
+            output_size: 0,
+            images_found: 0,
+            optimized: 0,
+            failed: 0,
+            elapsed_ms: 0,
+        };
+
+        if self.verbose {
+            println!("📥 {} (attempt {})", input.display(), attempt);
+        }
+        match self.compressor.compress_file(input, &output) {
+            Ok(summary) => {
+                event.output = Some(output.display().to_string());
+                event.fill(&summary);
+                event.moved_to = self.move_to(input, &self.archive, &mut event);
+            }
+            Err(e) if !e.is_refusal() && attempt <= self.retries => {
+                event.outcome = WatchOutcome::Retry;
+                event.error = Some(e.to_string());
+            }
+            Err(e) => {
+                event.outcome = WatchOutcome::Quarantined;
+                event.error = Some(e.to_string());
+                event.moved_to = self.move_to(input, &self.quarantine, &mut event);
+                if let Some(moved) = &event.moved_to {
+                    let note = format!("{}.error", moved);
+                    if let Err(write_error) = fs::write(&note, format!("{}\n", e)) {
+                        eprintln!("⚠️  Cannot write {}: {}", note, write_error);
+                    }
+                }
+            }
+        }
+        event.elapsed_ms = started.elapsed().as_millis();
+        event
+    }
+
+    /// Moves `input` into `dir`, returning where it ended up. A failure is
+    /// added to the event's error, the file then stays in the inbox.
+    fn move_to(&self, input: &Path, dir: &Path, event: &mut WatchEvent) -> Option<String> {
+        let target = unique_path(&dir.join(input.file_name().unwrap_or_default()));
+        match move_file(input, &target) {
+            Ok(()) => Some(target.display().to_string()),
+            Err(e) => {
+                let message = format!("cannot move to {}: {}", dir.display(), e);
+                event.error = Some(match event.error.take() {
+                    Some(error) => format!("{}; {}", error, message),
+                    None => message,
+                });
+                None
+            }
+        }
+    }
+
+    fn record(&self, event: &WatchEvent) {
+        if self.verbose {
+            match event.outcome {
+                WatchOutcome::Compressed => println!(
+                    "✅ {}: {}kb -> {}kb, {} of {} images optimized{}",
+                    event.input, event.input_size / 1024, event.output_size / 1024, event.optimized, event.images_found,
+                    event.error.as_ref().map_or(String::new(), |e| format!(" ({})", e))
+                ),
+                WatchOutcome::Retry => println!(
+                    "🔁 {}: {}, trying again ({} of {} retries)",
+                    event.input, event.error.as_deref().unwrap_or_default(), event.attempt, self.retries
+                ),
+                WatchOutcome::Quarantined => eprintln!(
+                    "☣️  {}: {}, quarantined",
+                    event.input, event.error.as_deref().unwrap_or_default()
+                ),
+            }
+        }
+
+        let Some(log) = &self.log else { return };
+        let written = OpenOptions::new().create(true).append(true).open(log).and_then(|mut file| {
+            let line = serde_json::to_string(event).map_err(std::io::Error::other)?;
+            writeln!(file, "{}", line)
+        });
+        if let Err(e) = written {
+            eprintln!("⚠️  Cannot write to {}: {}", log.display(), e);
+        }
+    }
+}
+
+impl Pending {
+    fn new() -> Self {
+        Pending { snapshot: None, changed: Instant::now(), attempts: 0, retry_at: None }
+    }
+}
+
+impl WatchEvent {
+    fn fill(&mut self, summary: &Summary) {
+        self.input_size = summary.input_size;
+        self.output_size = summary.output_size;
+        self.images_found = summary.found;
+        self.optimized = summary.optimized;
+        self.failed = summary.failed;
+    }
+}
+
+/// Size and modification time of `path`, `None` if it is gone.
+fn snapshot(path: &Path) -> Option<(u64, SystemTime)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.len(), meta.modified().unwrap_or(UNIX_EPOCH)))
+}
+
+/// Whether `path` is a file that couldn't be moved out and hasn't changed since.
+/// One that was replaced or removed is forgotten, so a new file dropped under
+/// the same name gets picked up.
+fn is_stuck(stuck: &mut HashMap<PathBuf, Option<(u64, SystemTime)>>, path: &Path) -> bool {
+    match stuck.get(path) {
+        Some(seen) if *seen == snapshot(path) => true,
+        Some(_) => {
+            stuck.remove(path);
+            false
+        }
+        None => false,
+    }
+}
+
+/// `path`, or `name-1.pdf`, `name-2.pdf`... if that is taken.
+fn unique_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("output");
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("pdf");
+    let mut candidate = path.to_path_buf();
+    let mut n = 0;
+    while candidate.exists() {
+        n += 1;
+        candidate = path.with_file_name(format!("{}-{}.{}", stem, n, extension));
+    }
+    candidate
+}
+
+/// Rename, or copy and delete when the archive is on another filesystem.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    fs::remove_file(from)
+}
+
+/// Current time as `YYYY-MM-DDTHH:MM:SSZ`.
+fn utc_now() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rest / 3600, rest % 3600 / 60, rest % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{Document, Object, Stream, dictionary};
+    use std::sync::Arc;
+
+    fn minimal_pdf() -> Vec<u8> {
+        let mut doc = Document::with_version("1.4");
+        let pages_id = doc.new_object_id();
+        let content = doc.add_object(Stream::new(dictionary! {}, b"0 0 m 10 10 l S".to_vec()));
+        let page = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Contents" => content });
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! { "Type" => "Pages", "Kids" => vec![page.into()], "Count" => 1 }));
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog);
+        let mut pdf = Vec::new();
+        doc.save_to(&mut pdf).unwrap();
+        pdf
+    }
+
+    #[test]
+    fn compresses_new_files_and_quarantines_broken_ones() {
+        let root = std::env::temp_dir().join(format!("compress_pdf_watch_{}", std::process::id()));
+        let (inbox, output) = (root.join("inbox"), root.join("out"));
+        fs::create_dir_all(&inbox).unwrap();
+        fs::write(inbox.join("waiting.pdf"), minimal_pdf()).unwrap();
+
+        let watcher = FolderWatcher::new(PdfCompressor::new(), &inbox, &output)
+            .settle(Duration::from_millis(100))
+            .retries(1)
+            .retry_delay(Duration::from_millis(50))
+            .log(Some(root.join("watch.log")));
+        let stop = Arc::new(AtomicBool::new(false));
+        let running = {
+            let stop = stop.clone();
+            std::thread::spawn(move || watcher.run(&stop))
+        };
+
+        // One file that arrives while watching, one that never becomes a PDF
+        std::thread::sleep(Duration::from_millis(300));
+        fs::write(inbox.join("scan.pdf"), minimal_pdf()).unwrap();
+        fs::write(inbox.join("broken.pdf"), b"%PDF-1.4 not really").unwrap();
+        let done = |name: &str, dir: &str| root.join("inbox").join(dir).join(name).exists();
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while !(done("scan.pdf", "archive") && done("waiting.pdf", "archive") && done("broken.pdf", "quarantine")) {
+            assert!(Instant::now() < deadline, "files weren't handled in time");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        stop.store(true, Ordering::SeqCst);
+        let stats = running.join().unwrap().unwrap();
+
+        assert_eq!((stats.compressed, stats.retried, stats.quarantined), (2, 1, 1));
+        assert!(output.join("scan.pdf").exists() && output.join("waiting.pdf").exists());
+        assert!(inbox.join("quarantine").join("broken.pdf.error").exists());
+        let log = fs::read_to_string(root.join("watch.log")).unwrap();
+        let outcomes: Vec<_> = log.lines().map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap()["outcome"].clone()).collect();
+        assert_eq!(outcomes.iter().filter(|o| *o == "retry").count(), 1);
+        assert_eq!(outcomes.len(), 4);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn inbox_is_canonical_and_replaced_stuck_files_are_forgotten() {
+        let root = std::env::temp_dir().join(format!("compress_pdf_stuck_{}", std::process::id()));
+        let inbox = root.join("inbox");
+        fs::create_dir_all(&inbox).unwrap();
+        let watcher = FolderWatcher::new(PdfCompressor::new(), &root.join(".").join("inbox"), &root.join("out"));
+        let scan = inbox.canonicalize().unwrap().join("scan.pdf");
+        assert!(watcher.is_candidate(&scan));
+
+        fs::write(&scan, b"first").unwrap();
+        let mut stuck = HashMap::from([(scan.clone(), snapshot(&scan))]);
+        assert!(is_stuck(&mut stuck, &scan));
+        fs::write(&scan, b"a different scan").unwrap();
+        assert!(!is_stuck(&mut stuck, &scan));
+        assert!(stuck.is_empty());
+        let _ = fs::remove_dir_all(&root);
+    }
+}